@@ -0,0 +1,347 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::enums::command::Command;
+use crate::enums::response::Response;
+use crate::enums::sync::{SyncChange, SyncReply};
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const MAX_DETAIL_LENGTH: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+struct AuditRecord {
+    seq: u64,
+    timestamp: u64,
+    peer: String,
+    command: String,
+    arguments: Vec<String>,
+    status: String,
+    detail: Option<String>,
+    // Left out when unset so the entries written before it existed still hash the same.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i32>,
+    bytes: Option<u64>,
+    sha256: Option<String>,
+    prev_hash: String,
+}
+
+impl AuditRecord {
+    fn hash(&self) -> String {
+        let serialized = serde_json::to_vec(self).expect("Failed to serialize audit record");
+        to_hex(&Sha256::digest(serialized))
+    }
+}
+
+// One line of the log. The hash covers the record including the previous entry's hash,
+// so changing, removing or reordering any line breaks the chain from there on.
+#[derive(Serialize, Deserialize)]
+struct AuditEntry {
+    #[serde(flatten)]
+    record: AuditRecord,
+    hash: String,
+}
+
+/// A command handled on behalf of the peer, filled in while it runs and appended once it finished.
+pub struct AuditEvent {
+    peer: String,
+    command: String,
+    arguments: Vec<String>,
+    status: String,
+    detail: Option<String>,
+    exit_code: Option<i32>,
+    bytes: Option<u64>,
+    sha256: Option<String>,
+    expected_bytes: Option<u64>,
+}
+
+impl AuditEvent {
+    pub fn new(command: &Command, peer: &str) -> Self {
+        let mut expected_bytes = None;
+        let mut sha256 = None;
+        let (name, arguments) = match command {
+            Command::Echo { message } => ("ECHO", vec![message.clone()]),
+            Command::ListFiles => ("LIST", vec![]),
+            Command::Whoami => ("WHOAMI", vec![]),
+            Command::Info => ("INFO", vec![]),
+            Command::Pwd => ("PWD", vec![]),
+            Command::Netstat => ("NETSTAT", vec![]),
+            Command::Network => ("NETWORK", vec![]),
+            Command::Handshake => ("HANDSHAKE", vec![]),
+            Command::GetFile { file_path, .. } => ("GET", vec![file_path.clone()]),
+            Command::PutFile { file_up_path, total_size, .. } => {
+                expected_bytes = Some(*total_size);
+                ("PUT", vec![file_up_path.clone()])
+            }
+            Command::Execute { command } => ("EXEC", command.split_whitespace().map(str::to_string).collect()),
+            Command::Find { root, pattern, .. } => ("FIND", vec![root.clone(), pattern.clone()]),
+            Command::Grep { pattern, path, .. } => ("GREP", vec![pattern.clone(), path.clone()]),
+            Command::Tail { path, lines, follow } => {
+                let mut arguments = vec![path.clone(), format!("-n {}", lines)];
+                if *follow {
+                    arguments.push("-f".to_string());
+                }
+                ("TAIL", arguments)
+            }
+            Command::Jobs => ("JOBS", vec![]),
+            Command::Cancel { job_id } => ("CANCEL", job_id.iter().map(u32::to_string).collect()),
+            Command::SyncManifest { root, .. } => ("SYNC-MANIFEST", vec![root.clone()]),
+            Command::SyncSignatures { root, path, .. } => ("SYNC-SIGNATURES", vec![root.clone(), path.clone()]),
+            Command::SyncDelta { root, path, .. } => ("SYNC-DELTA", vec![root.clone(), path.clone()]),
+            Command::ForwardConnect { host, port, .. } => ("FORWARD", vec![format!("{}:{}", host, port)]),
+            Command::ForwardData { .. } => ("FORWARD-DATA", vec![]),
+            Command::ForwardClose { .. } => ("FORWARD-CLOSE", vec![]),
+            Command::ForwardListen { port, .. } => ("FORWARD-LISTEN", vec![port.to_string()]),
+            Command::ForwardUnlisten { forward_id } => ("FORWARD-UNLISTEN", vec![forward_id.to_string()]),
+            Command::SyncApply { root, path, change, .. } => {
+                let kind = match change {
+                    SyncChange::Directory => "directory",
+                    SyncChange::Delete => "delete",
+                    SyncChange::File { delta, .. } => match &delta.end {
+                        Some(end) => {
+                            sha256 = Some(to_hex(&end.hash));
+                            "file"
+                        }
+                        None => "file-part",
+                    },
+                };
+                ("SYNC-APPLY", vec![root.clone(), path.clone(), kind.to_string()])
+            }
+        };
+
+        AuditEvent {
+            peer: peer.to_string(),
+            command: name.to_string(),
+            arguments,
+            status: "ok".to_string(),
+            detail: None,
+            exit_code: None,
+            bytes: None,
+            sha256,
+            expected_bytes,
+        }
+    }
+
+    pub fn set_sha256(&mut self, hash: &[u8]) {
+        self.sha256 = Some(to_hex(hash));
+    }
+
+    /// Takes the status from the response. Returns false for a chunk in the middle of an upload,
+    /// which is only logged once it completed or failed.
+    pub fn complete(&mut self, response: &Response) -> bool {
+        match response {
+            Response::PolicyDenied { reason, .. } => self.fail("denied", reason),
+            Response::TransferStatus { error: Some(e), transferred, .. } => {
+                self.bytes = Some(*transferred);
+                self.fail("error", e);
+            }
+            Response::TransferStatus { error: None, transferred, .. } => {
+                if self.expected_bytes.is_some_and(|expected| *transferred < expected) {
+                    return false;
+                }
+                self.bytes = Some(*transferred);
+            }
+            Response::FileData { total_size, .. } => self.bytes = Some(*total_size),
+            Response::Sync { reply: SyncReply::Error(e), .. } => self.fail("error", e),
+            Response::ForwardConnected { error: Some(e), .. } | Response::ForwardListening { error: Some(e), .. } => self.fail("error", e),
+            Response::Error { content } => self.fail("error", content),
+            Response::CommandOutput { exit_code: Some(0), .. } => self.exit_code = Some(0),
+            Response::CommandOutput { exit_code: Some(code), .. } => {
+                self.exit_code = Some(*code);
+                self.fail("error", &format!("exited with status {}", code));
+            }
+            Response::CommandOutput { exit_code: None, .. } => self.fail("error", "terminated by a signal"),
+            Response::Message { content } => self.detail = Some(truncate(content.trim())),
+            _ => {}
+        }
+        true
+    }
+
+    fn fail(&mut self, status: &str, detail: &str) {
+        self.status = status.to_string();
+        self.detail = Some(truncate(detail));
+    }
+}
+
+/// Append-only JSON lines log of the commands this node ran for its peers, enabled with `--audit-log <file>`.
+pub struct AuditLog {
+    file: File,
+    seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens or creates the log and continues the chain after its last entry.
+    pub fn open(path: &Path) -> Result<Self, String> {
+        let (seq, last_hash) = match File::open(path) {
+            Ok(_) => verify(path).map_err(|e| format!("Audit log {} failed verification: {}", path.display(), e))?,
+            Err(_) => (0, GENESIS_HASH.to_string()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("Failed to open audit log {}: {}", path.display(), e))?;
+        Ok(AuditLog { file, seq, last_hash })
+    }
+
+    pub fn append(&mut self, event: AuditEvent) -> Result<(), String> {
+        let record = AuditRecord {
+            seq: self.seq + 1,
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()),
+            peer: event.peer,
+            command: event.command,
+            arguments: event.arguments,
+            status: event.status,
+            detail: event.detail,
+            exit_code: event.exit_code,
+            bytes: event.bytes,
+            sha256: event.sha256,
+            prev_hash: self.last_hash.clone(),
+        };
+        let entry = AuditEntry { hash: record.hash(), record };
+
+        let mut line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())
+            .and_then(|_| self.file.flush())
+            .map_err(|e| format!("Failed to write audit log: {}", e))?;
+
+        self.seq = entry.record.seq;
+        self.last_hash = entry.hash;
+        Ok(())
+    }
+}
+
+/// Checks every entry's hash and link to the previous one. Returns the number of entries and the last hash.
+pub fn verify(path: &Path) -> Result<(u64, String), String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+
+    let mut seq = 0;
+    let mut last_hash = GENESIS_HASH.to_string();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.map_err(|e| format!("line {}: {}", line_number, e))?;
+        let entry: AuditEntry = serde_json::from_str(&line).map_err(|e| format!("line {}: malformed entry: {}", line_number, e))?;
+
+        if entry.record.seq != seq + 1 {
+            return Err(format!("line {}: expected entry {} but found {}", line_number, seq + 1, entry.record.seq));
+        }
+        if entry.record.prev_hash != last_hash {
+            return Err(format!("line {}: entry {} does not follow the previous entry", line_number, entry.record.seq));
+        }
+        if entry.record.hash() != entry.hash {
+            return Err(format!("line {}: entry {} was modified", line_number, entry.record.seq));
+        }
+
+        seq = entry.record.seq;
+        last_hash = entry.hash;
+    }
+    Ok((seq, last_hash))
+}
+
+fn truncate(text: &str) -> String {
+    match text.char_indices().nth(MAX_DETAIL_LENGTH) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completed(command: &Command, response: Response) -> AuditEvent {
+        let mut event = AuditEvent::new(command, "127.0.0.1:9000");
+        assert!(event.complete(&response));
+        event
+    }
+
+    #[test]
+    fn errors_and_refusals_are_not_logged_as_ok() {
+        let list = Command::ListFiles;
+        let event = completed(&list, Response::Error { content: "Path is outside the allowed roots".to_string() });
+        assert_eq!(event.status, "error");
+        assert_eq!(event.detail.as_deref(), Some("Path is outside the allowed roots"));
+
+        let event = completed(&list, Response::PolicyDenied { operation: "LIST".to_string(), reason: "not granted".to_string() });
+        assert_eq!(event.status, "denied");
+
+        let event = completed(&Command::Pwd, Response::Message { content: "/srv".to_string() });
+        assert_eq!(event.status, "ok");
+    }
+
+    #[test]
+    fn executed_programs_record_their_exit_code() {
+        let exec = Command::Execute { command: "false".to_string() };
+        let event = completed(&exec, Response::CommandOutput { output: String::new(), exit_code: Some(0) });
+        assert_eq!((event.status.as_str(), event.exit_code), ("ok", Some(0)));
+
+        let event = completed(&exec, Response::CommandOutput { output: String::new(), exit_code: Some(1) });
+        assert_eq!((event.status.as_str(), event.exit_code), ("error", Some(1)));
+
+        let event = completed(&exec, Response::CommandOutput { output: String::new(), exit_code: None });
+        assert_eq!((event.status.as_str(), event.exit_code), ("error", None));
+    }
+
+    fn write_log(name: &str, commands: &[Command]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("uplink-audit-{}-{}.log", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let mut log = AuditLog::open(&path).unwrap();
+        for command in commands {
+            log.append(completed(command, Response::Message { content: "done".to_string() })).unwrap();
+        }
+        path
+    }
+
+    fn edit_lines(path: &Path, edit: impl FnOnce(&mut Vec<String>)) {
+        let mut lines: Vec<String> = std::fs::read_to_string(path).unwrap().lines().map(str::to_string).collect();
+        edit(&mut lines);
+        std::fs::write(path, lines.join("\n") + "\n").unwrap();
+    }
+
+    #[test]
+    fn reopening_the_log_continues_the_chain() {
+        let path = write_log("reopen", &[Command::Pwd, Command::ListFiles]);
+        let (entries, last_hash) = verify(&path).unwrap();
+        assert_eq!(entries, 2);
+
+        let mut log = AuditLog::open(&path).unwrap();
+        log.append(completed(&Command::Pwd, Response::Message { content: "/srv".to_string() })).unwrap();
+        let (entries, hash) = verify(&path).unwrap();
+        assert_eq!(entries, 3);
+        assert_ne!(hash, last_hash);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn modified_removed_and_reordered_entries_are_detected() {
+        let commands = [Command::Pwd, Command::ListFiles, Command::Pwd];
+
+        let modified = write_log("modified", &commands);
+        edit_lines(&modified, |lines| lines[1] = lines[1].replace("\"ok\"", "\"denied\""));
+        assert!(verify(&modified).unwrap_err().contains("entry 2 was modified"));
+        assert!(AuditLog::open(&modified).is_err());
+
+        edit_lines(&modified, |lines| { lines.remove(1); });
+        assert!(verify(&modified).unwrap_err().contains("expected entry 2"));
+
+        let reordered = write_log("reordered", &commands);
+        edit_lines(&reordered, |lines| lines.swap(1, 2));
+        assert!(verify(&reordered).is_err());
+
+        let truncated = write_log("truncated", &commands);
+        edit_lines(&truncated, |lines| { lines.remove(0); });
+        assert!(verify(&truncated).is_err());
+
+        for path in [modified, reordered, truncated] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
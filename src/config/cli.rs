@@ -0,0 +1,238 @@
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use clap::builder::BoolishValueParser;
+use clap::{Args, Parser, Subcommand};
+use tracing_subscriber::filter::LevelFilter;
+use crate::logging::logging;
+use crate::policy::capabilities::{self, CapabilitySet};
+use crate::policy::forward_policy;
+use crate::transport::compression::{self, Compression};
+
+#[derive(Parser)]
+#[command(name = "uplink", version, about = "Encrypted command and file transfer channel between two nodes.", args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Without a command the mode and address are taken from the config file, or the settings compiled into the binary.
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+
+    /// The node options when no command is given, then the environment applies as with `server` or `client`.
+    #[command(flatten)]
+    pub node: NodeOptions,
+
+    /// TOML config file, by default ~/.config/uplink/config.toml or /etc/uplink/config.toml if present.
+    #[arg(long, global = true, env = "UPLINK_CONFIG", value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub log: LogOptions,
+}
+
+#[derive(Args)]
+pub struct LogOptions {
+    /// Diagnostics printed to stderr: off, error, warn, info (default), debug or trace.
+    #[arg(long, global = true, env = "UPLINK_LOG_LEVEL", value_parser = logging::parse_level)]
+    pub log_level: Option<LevelFilter>,
+
+    /// Also append diagnostics to this file as JSON lines.
+    #[arg(long, global = true, env = "UPLINK_LOG_FILE", value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Listen for the peer node on <ADDRESS>.
+    Server {
+        /// <host>:<port>, taken from the config file when omitted.
+        #[arg(env = "UPLINK_ADDRESS", value_parser = parse_address)]
+        address: Option<String>,
+        #[command(flatten)]
+        node: NodeOptions,
+    },
+    /// Connect to the peer node at <ADDRESS>, reconnecting when the connection drops.
+    Client {
+        /// <host>:<port>, taken from the config file when omitted.
+        #[arg(env = "UPLINK_ADDRESS", value_parser = parse_address)]
+        address: Option<String>,
+        #[command(flatten)]
+        node: NodeOptions,
+    },
+    /// Connect to <ADDRESS>, run one shell command on the peer, print its output and exit.
+    Exec {
+        /// <host>:<port>
+        #[arg(value_parser = parse_address)]
+        address: String,
+        #[command(flatten)]
+        node: NodeOptions,
+        /// The command to run, e.g. `uplink exec 10.0.0.5:8000 -- ls -la /tmp`.
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Generate a random passphrase for a pair of nodes.
+    Keygen {
+        /// Write the passphrase to this file (readable by the owner only) instead of stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Number of random bytes, the passphrase is their hex encoding.
+        #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u16).range(16..=1024))]
+        bytes: u16,
+    },
+    /// Inspect the configuration.
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Check the hash chain of an audit log written with --audit-log.
+    VerifyAudit {
+        path: PathBuf,
+    },
+    /// Print the version.
+    Version,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigAction {
+    /// Validate the config file, options and environment, and print the effective configuration.
+    Check {
+        #[command(flatten)]
+        node: NodeOptions,
+    },
+}
+
+/// Options of a node. Anything not given here or in the environment falls back to the config file.
+#[derive(Args, Default)]
+pub struct NodeOptions {
+    /// Shared passphrase of both nodes. Prefer the PASSPHRASE environment variable, arguments are visible to other users.
+    #[arg(long)]
+    pub passphrase: Option<String>,
+
+    /// Read the passphrase from the first line of this file, e.g. one written by `uplink keygen --out`, or set UPLINK_PASSPHRASE_FILE.
+    /// --passphrase and --passphrase-file win over PASSPHRASE and UPLINK_PASSPHRASE_FILE.
+    #[arg(long, value_name = "FILE")]
+    pub passphrase_file: Option<PathBuf>,
+
+    /// Ask for the passphrase on the terminal without echoing it. Done anyway when no passphrase is configured.
+    #[arg(long)]
+    pub passphrase_prompt: bool,
+
+    /// Start even with a missing, built-in default or weak passphrase.
+    #[arg(long, env = "UPLINK_INSECURE")]
+    pub insecure: bool,
+
+    /// Do not wrap commands in an RSA envelope, only the passphrase protects the channel. `=false` overrides the config file.
+    #[arg(long, env = "UPLINK_NO_ENVELOPE", value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub no_envelope: Option<bool>,
+
+    /// Compression to use, most preferred first, comma separated: zstd, lz4, gzip or none (default zstd,lz4,gzip).
+    #[arg(long, env = "UPLINK_COMPRESSION", value_delimiter = ',', value_parser = compression::parse_method)]
+    pub compression: Vec<Compression>,
+
+    /// Send frames smaller than this many bytes uncompressed (default 256).
+    #[arg(long, env = "UPLINK_COMPRESSION_THRESHOLD", value_name = "BYTES")]
+    pub compression_threshold: Option<usize>,
+
+    /// Drop the connection when the peer sends a message larger than this many bytes (default 16 MiB).
+    #[arg(long, env = "UPLINK_MAX_FRAME_SIZE", value_name = "BYTES")]
+    pub max_frame_size: Option<usize>,
+
+    /// Drop the connection when a message from the peer decompresses to more than this many bytes (default 64 MiB).
+    #[arg(long, env = "UPLINK_MAX_DECOMPRESSED_SIZE", value_name = "BYTES")]
+    pub max_decompressed_size: Option<usize>,
+
+    /// Ping the peer every this many seconds, 0 disables heartbeats (default 15).
+    #[arg(long, env = "UPLINK_HEARTBEAT_INTERVAL", value_name = "SECS")]
+    pub heartbeat_interval: Option<u64>,
+
+    /// Drop the connection when the peer has not been heard from for this many seconds (default 45).
+    #[arg(long, env = "UPLINK_HEARTBEAT_TIMEOUT", value_name = "SECS")]
+    pub heartbeat_timeout: Option<u64>,
+
+    /// Close the session after this many seconds without commands or responses, 0 never does (default).
+    #[arg(long, env = "UPLINK_IDLE_TIMEOUT", value_name = "SECS")]
+    pub idle_timeout: Option<u64>,
+
+    /// Client: address to try when the server cannot be reached, in order after <ADDRESS>. Can be repeated.
+    #[arg(long = "fallback", env = "UPLINK_FALLBACK", value_delimiter = ',', value_name = "HOST:PORT", value_parser = parse_address)]
+    pub fallback_addresses: Vec<String>,
+
+    /// Client: seconds to wait before the first reconnect, doubled after every failed round (default 1).
+    #[arg(long, env = "UPLINK_RECONNECT_DELAY", value_name = "SECS")]
+    pub reconnect_delay: Option<u64>,
+
+    /// Client: longest wait between reconnect rounds in seconds (default 60).
+    #[arg(long, env = "UPLINK_RECONNECT_MAX_DELAY", value_name = "SECS")]
+    pub reconnect_max_delay: Option<u64>,
+
+    /// Client: exit after this many failed reconnect rounds, 0 retries forever (default).
+    #[arg(long, env = "UPLINK_MAX_RETRIES", value_name = "N")]
+    pub max_retries: Option<u32>,
+
+    /// Client: exit after this many seconds without a connection, 0 waits forever (default).
+    #[arg(long, env = "UPLINK_RECONNECT_DEADLINE", value_name = "SECS")]
+    pub reconnect_deadline: Option<u64>,
+
+    /// Client: exit when the connection ends instead of reconnecting. `=false` overrides the config file.
+    #[arg(long, env = "UPLINK_EXIT_ON_DISCONNECT", value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub exit_on_disconnect: Option<bool>,
+
+    /// Do not let the peer run commands or query system and network information. `=false` overrides the config file.
+    #[arg(long, env = "UPLINK_NO_EXEC", value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub no_exec: Option<bool>,
+
+    /// Do not let the peer read or write files. `=false` overrides the config file.
+    #[arg(long, env = "UPLINK_NO_TRANSFER", value_name = "BOOL", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    pub no_transfer: Option<bool>,
+
+    /// Capabilities granted to the peer, comma separated: read-files, write-files, exec, sysinfo, network-info, forwarding, all (default) or none.
+    #[arg(long, env = "UPLINK_CAPABILITIES", value_parser = capabilities::parse_capabilities)]
+    pub capabilities: Option<CapabilitySet>,
+
+    /// Capabilities for a specific peer IP address, e.g. 10.0.0.5=read-files,sysinfo. Can be repeated.
+    #[arg(long, value_name = "IP=CAPABILITIES", value_parser = parse_peer_capabilities)]
+    pub peer_capabilities: Vec<(String, CapabilitySet)>,
+
+    /// Maximum number of results FIND and GREP return (default 1000).
+    #[arg(long, env = "UPLINK_MAX_RESULTS")]
+    pub max_results: Option<usize>,
+
+    /// Address this node listens on for the peer's FORWARD R (default 127.0.0.1).
+    #[arg(long, env = "UPLINK_FORWARD_BIND", value_name = "IP")]
+    pub forward_bind: Option<IpAddr>,
+
+    /// Ports the peer may have this node listen on with FORWARD R, e.g. 8000-8099,9000 (default any).
+    #[arg(long, env = "UPLINK_FORWARD_PORTS", value_delimiter = ',', value_name = "PORTS", value_parser = forward_policy::parse_port_range)]
+    pub forward_ports: Vec<RangeInclusive<u16>>,
+
+    /// Connections each of the peer's FORWARD R listeners takes at a time (default 16).
+    #[arg(long, env = "UPLINK_FORWARD_MAX_CONNECTIONS", value_name = "N")]
+    pub forward_max_connections: Option<usize>,
+
+    /// Confine file commands to this directory, read-write. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    pub transfer_root: Vec<PathBuf>,
+
+    /// Also allow file commands to read from this directory, read-only. Can be repeated.
+    #[arg(long, value_name = "DIR")]
+    pub read_root: Vec<PathBuf>,
+
+    /// TOML or JSON allowlist of programs the peer may execute.
+    #[arg(long, env = "UPLINK_EXEC_POLICY", value_name = "FILE")]
+    pub exec_policy: Option<PathBuf>,
+
+    /// Append every command run for the peer to this hash-chained JSON lines log.
+    #[arg(long, env = "UPLINK_AUDIT_LOG", value_name = "FILE")]
+    pub audit_log: Option<PathBuf>,
+}
+
+pub fn parse_address(address: &str) -> Result<String, String> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(address.to_string()),
+        _ => Err(format!("expected <host>:<port>, got {}", address)),
+    }
+}
+
+fn parse_peer_capabilities(entry: &str) -> Result<(String, CapabilitySet), String> {
+    let (peer, list) = entry.split_once('=')
+        .ok_or_else(|| format!("expected <ip>=<capabilities>, got {}", entry))?;
+    Ok((peer.to_string(), capabilities::parse_capabilities(list)?))
+}
@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tracing_subscriber::filter::LevelFilter;
+use crate::config::cli::{self, LogOptions, NodeOptions};
+use crate::crypto::kdf::KdfParams;
+use crate::crypto::passphrase;
+use crate::config::config_file::{ConfigFile, Credentials, LoggingSection, PolicySection, ReconnectSection, TransportSection};
+use crate::filesystem::sandbox::Sandbox;
+use crate::logging::logging;
+use crate::policy::capabilities::{self, Capability, CapabilitySet};
+use crate::policy::exec_policy::ExecPolicy;
+use crate::policy::forward_policy::{self, ForwardPolicy};
+use crate::policy::policy::Policy;
+use crate::transport::communication::{self, FrameLimits, HeartbeatSettings};
+use crate::transport::compression::{self, CompressionSettings};
+use crate::uplink_client::reconnect::{self, ReconnectSettings};
+
+pub const DEFAULT_MAX_RESULTS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Server,
+    Client,
+}
+
+/// The effective configuration. Each setting is taken from the first of: command line, environment,
+/// config file, the values compiled in through build.rs and the built-in defaults.
+pub struct Config {
+    pub mode: Option<Mode>,
+    pub address: Option<String>,
+    /// None when no passphrase is configured anywhere, it is then asked for on the terminal.
+    pub passphrase: Option<String>,
+    pub passphrase_prompt: bool,
+    pub insecure: bool,
+    pub no_envelope: bool,
+    pub compression: CompressionSettings,
+    pub limits: FrameLimits,
+    pub heartbeat: HeartbeatSettings,
+    pub reconnect: ReconnectSettings,
+    pub kdf: KdfParams,
+    pub no_exec: bool,
+    pub no_transfer: bool,
+    pub capabilities: CapabilitySet,
+    pub peer_capabilities: HashMap<String, CapabilitySet>,
+    pub transfer_roots: Vec<PathBuf>,
+    pub read_roots: Vec<PathBuf>,
+    pub exec_policy: Option<PathBuf>,
+    pub max_results: usize,
+    pub forward_bind: IpAddr,
+    pub forward_ports: Vec<RangeInclusive<u16>>,
+    pub forward_max_connections: usize,
+    pub log_level: LevelFilter,
+    pub log_file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+}
+
+impl Config {
+    /// `mode` and `address` come from the subcommand, `node` and `log` from its options and the
+    /// environment, which clap already merged. Lists given there replace the ones in the file. The
+    /// passphrase variables are read here, so both passphrase options beat both of them.
+    pub fn resolve(
+        mode: Option<Mode>,
+        address: Option<String>,
+        node: NodeOptions,
+        log: LogOptions,
+        file: ConfigFile,
+    ) -> Result<Self, String> {
+        let compiled = compiled_defaults()?;
+
+        // The compiled transport setting belongs to the compiled mode, it does not apply when the mode was chosen explicitly.
+        let (mode, compiled_mode) = match mode.or(file.mode) {
+            Some(mode) => (Some(mode), false),
+            None => (compiled.mode, compiled.mode.is_some()),
+        };
+
+        let address = match address.or(file.address) {
+            Some(address) => Some(cli::parse_address(&address).map_err(|e| format!("Invalid address: {}", e))?),
+            None => compiled.address,
+        };
+
+        let log_level = match (log.log_level, file.logging.level) {
+            (Some(level), _) => level,
+            (None, Some(level)) => logging::parse_level(&level)?,
+            (None, None) => logging::DEFAULT_LOG_LEVEL,
+        };
+
+        let sources = [
+            (node.passphrase, node.passphrase_file),
+            (env_var("PASSPHRASE"), env_var("UPLINK_PASSPHRASE_FILE").map(PathBuf::from)),
+            (file.credentials.passphrase, file.credentials.passphrase_file),
+        ];
+        let passphrase = match sources.into_iter().find(|(passphrase, path)| passphrase.is_some() || path.is_some()) {
+            Some((Some(passphrase), _)) => Some(passphrase),
+            Some((None, Some(path))) => Some(passphrase::read_file(&path)?),
+            _ => compiled.passphrase,
+        };
+
+        file.kdf.validate()?;
+
+        let compression = CompressionSettings {
+            methods: match or_file(node.compression, file.transport.compression) {
+                methods if methods.is_empty() => compression::DEFAULT_METHODS.to_vec(),
+                methods => methods,
+            },
+            threshold: node.compression_threshold
+                .or(file.transport.compression_threshold)
+                .unwrap_or(compression::DEFAULT_THRESHOLD),
+        };
+
+        let limits = FrameLimits {
+            max_frame_size: node.max_frame_size
+                .or(file.transport.max_frame_size)
+                .unwrap_or(communication::DEFAULT_MAX_FRAME_SIZE),
+            max_decompressed_size: node.max_decompressed_size
+                .or(file.transport.max_decompressed_size)
+                .unwrap_or(communication::DEFAULT_MAX_DECOMPRESSED_SIZE),
+        };
+        limits.validate()?;
+
+        let heartbeat = HeartbeatSettings {
+            interval: node.heartbeat_interval
+                .or(file.transport.heartbeat_interval)
+                .map_or(communication::DEFAULT_HEARTBEAT_INTERVAL, Duration::from_secs),
+            timeout: node.heartbeat_timeout
+                .or(file.transport.heartbeat_timeout)
+                .map_or(communication::DEFAULT_HEARTBEAT_TIMEOUT, Duration::from_secs),
+            idle_timeout: node.idle_timeout
+                .or(file.transport.idle_timeout)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+        };
+        heartbeat.validate()?;
+
+        let reconnect = ReconnectSettings {
+            fallback_addresses: or_file(node.fallback_addresses, file.reconnect.fallback)
+                .iter()
+                .map(|address| cli::parse_address(address).map_err(|e| format!("Invalid fallback address: {}", e)))
+                .collect::<Result<_, _>>()?,
+            initial_delay: node.reconnect_delay
+                .or(file.reconnect.delay)
+                .map_or(reconnect::DEFAULT_INITIAL_DELAY, Duration::from_secs),
+            max_delay: node.reconnect_max_delay
+                .or(file.reconnect.max_delay)
+                .map_or(reconnect::DEFAULT_MAX_DELAY, Duration::from_secs),
+            max_retries: node.max_retries
+                .or(file.reconnect.max_retries)
+                .filter(|&retries| retries > 0),
+            deadline: node.reconnect_deadline
+                .or(file.reconnect.deadline)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            exit_on_disconnect: node.exit_on_disconnect.unwrap_or(file.reconnect.exit_on_disconnect),
+        };
+        reconnect.validate()?;
+
+        let policy = file.policy;
+        let peer_capabilities = if node.peer_capabilities.is_empty() {
+            policy.peer_capabilities.into_iter()
+                .map(|(peer, granted)| (peer, granted.into_iter().collect()))
+                .collect()
+        } else {
+            node.peer_capabilities.into_iter().collect()
+        };
+
+        let forward_ports = if node.forward_ports.is_empty() {
+            policy.forward_ports.iter()
+                .map(|range| forward_policy::parse_port_range(range).map_err(|e| format!("Invalid forward port range: {}", e)))
+                .collect::<Result<_, _>>()?
+        } else {
+            node.forward_ports
+        };
+        let forward_max_connections = node.forward_max_connections
+            .or(policy.forward_max_connections)
+            .unwrap_or(forward_policy::DEFAULT_MAX_CONNECTIONS);
+        if forward_max_connections == 0 {
+            return Err("The forward connection limit must be at least 1".to_string());
+        }
+
+        Ok(Config {
+            mode,
+            address,
+            passphrase,
+            passphrase_prompt: node.passphrase_prompt,
+            insecure: node.insecure,
+            no_envelope: node.no_envelope.unwrap_or(file.transport.no_envelope || (compiled_mode && compiled.no_envelope)),
+            compression,
+            limits,
+            heartbeat,
+            reconnect,
+            kdf: file.kdf,
+            no_exec: node.no_exec.unwrap_or(policy.no_exec),
+            no_transfer: node.no_transfer.unwrap_or(policy.no_transfer),
+            capabilities: node.capabilities
+                .or_else(|| policy.capabilities.map(|granted| granted.into_iter().collect()))
+                .unwrap_or_else(|| capabilities::ALL.into()),
+            peer_capabilities,
+            transfer_roots: or_file(node.transfer_root, policy.transfer_roots),
+            read_roots: or_file(node.read_root, policy.read_roots),
+            exec_policy: node.exec_policy.or(policy.exec_policy),
+            max_results: node.max_results.or(policy.max_results).unwrap_or(DEFAULT_MAX_RESULTS),
+            forward_bind: node.forward_bind.or(policy.forward_bind).unwrap_or(forward_policy::DEFAULT_BIND_ADDRESS),
+            forward_ports,
+            forward_max_connections,
+            log_level,
+            log_file: log.log_file.or(file.logging.file),
+            audit_log: node.audit_log.or(file.logging.audit_log),
+        })
+    }
+
+    pub fn policy(&self) -> Result<Policy, String> {
+        // --no-exec and --no-transfer are shorthands for dropping the capabilities they used to cover.
+        let mut revoked = CapabilitySet::new();
+        if self.no_exec {
+            revoked.extend([Capability::Exec, Capability::Sysinfo, Capability::NetworkInfo]);
+        }
+        if self.no_transfer {
+            revoked.extend([Capability::ReadFiles, Capability::WriteFiles]);
+        }
+        let restrict = |granted: &CapabilitySet| -> CapabilitySet { granted.difference(&revoked).copied().collect() };
+
+        let peer_capabilities = self.peer_capabilities.iter()
+            .map(|(peer, granted)| (peer.clone(), restrict(granted)))
+            .collect();
+
+        let sandbox = Sandbox::new(&self.transfer_roots, &self.read_roots)?;
+        let exec_policy = match &self.exec_policy {
+            Some(path) => ExecPolicy::load(path)?,
+            None => ExecPolicy::unrestricted(),
+        };
+
+        let forwarding = ForwardPolicy::new(self.forward_bind, self.forward_ports.clone(), self.forward_max_connections);
+
+        Ok(Policy::new(sandbox, exec_policy, forwarding, restrict(&self.capabilities), peer_capabilities))
+    }
+
+    /// The configuration in config file form, as printed by `config check`. The passphrase is not shown.
+    pub fn to_toml(&self) -> Result<String, String> {
+        let file = ConfigFile {
+            mode: self.mode,
+            address: self.address.clone(),
+            credentials: Credentials {
+                passphrase: self.passphrase.as_ref().map(|_| "<redacted>".to_string()),
+                passphrase_file: None,
+            },
+            policy: PolicySection {
+                capabilities: Some(self.capabilities.iter().copied().collect()),
+                peer_capabilities: self.peer_capabilities.iter()
+                    .map(|(peer, granted)| (peer.clone(), granted.iter().copied().collect()))
+                    .collect(),
+                no_exec: self.no_exec,
+                no_transfer: self.no_transfer,
+                transfer_roots: self.transfer_roots.clone(),
+                read_roots: self.read_roots.clone(),
+                exec_policy: self.exec_policy.clone(),
+                max_results: Some(self.max_results),
+                forward_bind: Some(self.forward_bind),
+                forward_ports: forward_policy::format_port_ranges(&self.forward_ports),
+                forward_max_connections: Some(self.forward_max_connections),
+            },
+            transport: TransportSection {
+                no_envelope: self.no_envelope,
+                compression: self.compression.methods.clone(),
+                compression_threshold: Some(self.compression.threshold),
+                max_frame_size: Some(self.limits.max_frame_size),
+                max_decompressed_size: Some(self.limits.max_decompressed_size),
+                heartbeat_interval: Some(self.heartbeat.interval.as_secs()),
+                heartbeat_timeout: Some(self.heartbeat.timeout.as_secs()),
+                idle_timeout: Some(self.heartbeat.idle_timeout.map_or(0, |idle| idle.as_secs())),
+            },
+            reconnect: ReconnectSection {
+                fallback: self.reconnect.fallback_addresses.clone(),
+                delay: Some(self.reconnect.initial_delay.as_secs()),
+                max_delay: Some(self.reconnect.max_delay.as_secs()),
+                max_retries: Some(self.reconnect.max_retries.unwrap_or(0)),
+                deadline: Some(self.reconnect.deadline.map_or(0, |deadline| deadline.as_secs())),
+                exit_on_disconnect: self.reconnect.exit_on_disconnect,
+            },
+            kdf: self.kdf,
+            logging: LoggingSection {
+                level: Some(self.log_level.to_string().to_lowercase()),
+                file: self.log_file.clone(),
+                audit_log: self.audit_log.clone(),
+            },
+        };
+        toml::to_string(&file).map_err(|e| format!("Failed to format the configuration: {}", e))
+    }
+}
+
+struct CompiledDefaults {
+    mode: Option<Mode>,
+    address: Option<String>,
+    passphrase: Option<String>,
+    no_envelope: bool,
+}
+
+// Settings embedded by build.rs, so a preconfigured binary runs without arguments.
+fn compiled_defaults() -> Result<CompiledDefaults, String> {
+    let compiled = |value: Option<&str>| value.map(|value| value.trim_matches('"').to_string());
+
+    let mode = match compiled(option_env!("CARGO_PKG_METADATA_PRECOMPILED_MODE")).as_deref() {
+        Some("server") => Some(Mode::Server),
+        Some("client") => Some(Mode::Client),
+        Some(other) => return Err(format!("Invalid compiled-in mode: {}", other)),
+        None => None,
+    };
+
+    Ok(CompiledDefaults {
+        mode,
+        address: compiled(option_env!("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS")),
+        passphrase: compiled(option_env!("CARGO_PKG_METADATA_PRECOMPILED_PASSPHRASE")),
+        no_envelope: option_env!("CARGO_PKG_METADATA_PRECOMPILED_NO_ENVELOPE")
+            .and_then(|value| value.parse::<bool>().ok())
+            .unwrap_or(false),
+    })
+}
+
+// Unset and empty are the same, as for the variables clap reads.
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn or_file<T>(given: Vec<T>, from_file: Vec<T>) -> Vec<T> {
+    if given.is_empty() { from_file } else { given }
+}
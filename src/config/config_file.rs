@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use crate::config::config::Mode;
+use crate::crypto::kdf::KdfParams;
+use crate::policy::capabilities::Capability;
+use crate::transport::compression::Compression;
+
+/// The TOML config file. Keys are named after the command line options, every one is optional:
+///
+/// ```toml
+/// mode = "server"
+/// address = "0.0.0.0:8000"
+///
+/// [credentials]
+/// passphrase-file = "/etc/uplink/passphrase"
+///
+/// [policy]
+/// capabilities = ["read-files", "sysinfo"]
+/// transfer-roots = ["/srv/uplink"]
+/// forward-ports = ["8000-8099"]
+///
+/// [policy.peer-capabilities]
+/// "10.0.0.5" = ["read-files", "write-files"]
+///
+/// [transport]
+/// no-envelope = false
+/// compression = ["zstd", "gzip"]
+///
+/// [reconnect]
+/// fallback = ["10.0.0.6:8000"]
+/// max-delay = 30
+///
+/// [kdf]
+/// memory-kib = 19456
+///
+/// [logging]
+/// level = "info"
+/// ```
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub mode: Option<Mode>,
+    pub address: Option<String>,
+    #[serde(default)]
+    pub credentials: Credentials,
+    #[serde(default)]
+    pub policy: PolicySection,
+    #[serde(default)]
+    pub transport: TransportSection,
+    #[serde(default)]
+    pub reconnect: ReconnectSection,
+    #[serde(default)]
+    pub kdf: KdfParams,
+    #[serde(default)]
+    pub logging: LoggingSection,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Credentials {
+    pub passphrase: Option<String>,
+    pub passphrase_file: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct PolicySection {
+    pub capabilities: Option<Vec<Capability>>,
+    #[serde(default)]
+    pub no_exec: bool,
+    #[serde(default)]
+    pub no_transfer: bool,
+    #[serde(default)]
+    pub transfer_roots: Vec<PathBuf>,
+    #[serde(default)]
+    pub read_roots: Vec<PathBuf>,
+    pub exec_policy: Option<PathBuf>,
+    pub max_results: Option<usize>,
+    pub forward_bind: Option<IpAddr>,
+    #[serde(default)]
+    pub forward_ports: Vec<String>,
+    pub forward_max_connections: Option<usize>,
+    #[serde(default)]
+    pub peer_capabilities: BTreeMap<String, Vec<Capability>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TransportSection {
+    #[serde(default)]
+    pub no_envelope: bool,
+    #[serde(default)]
+    pub compression: Vec<Compression>,
+    pub compression_threshold: Option<usize>,
+    pub max_frame_size: Option<usize>,
+    pub max_decompressed_size: Option<usize>,
+    pub heartbeat_interval: Option<u64>,
+    pub heartbeat_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ReconnectSection {
+    #[serde(default)]
+    pub fallback: Vec<String>,
+    pub delay: Option<u64>,
+    pub max_delay: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub deadline: Option<u64>,
+    #[serde(default)]
+    pub exit_on_disconnect: bool,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LoggingSection {
+    pub level: Option<String>,
+    pub file: Option<PathBuf>,
+    pub audit_log: Option<PathBuf>,
+}
+
+impl ConfigFile {
+    /// Loads `path`, or the first default location that exists. No file at all is an empty config,
+    /// but a file given with `--config` has to exist.
+    pub fn load(path: Option<&Path>) -> Result<(Self, Option<PathBuf>), String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match default_paths().into_iter().find(|path| path.is_file()) {
+                Some(path) => path,
+                None => return Ok((ConfigFile::default(), None)),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+        let file = toml::from_str(&content)
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+        Ok((file, Some(path)))
+    }
+}
+
+fn default_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let user_config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from));
+    if let Some(user_config) = user_config {
+        paths.push(user_config.join("uplink").join("config.toml"));
+    }
+    if cfg!(unix) {
+        paths.push(PathBuf::from("/etc/uplink/config.toml"));
+    }
+    paths
+}
@@ -1,18 +1,12 @@
 use aes_gcm::aead::{Aead, KeyInit, OsRng};
 use aes_gcm::{Aes256Gcm, Nonce};
-use hkdf::Hkdf;
-use sha2::Sha256;
 use rand::RngCore;
 
 use aes_gcm::aead::{Payload, Error as AeadError};
 use aes_gcm::aead::generic_array::GenericArray;
 
-pub fn derive_key(passphrase: &[u8]) -> [u8; 32] {
-    let hkdf = Hkdf::<Sha256>::new(None, passphrase);
-    let mut key = [0u8; 32];
-    hkdf.expand(&[], &mut key).expect("Key derivation failed");
-    key
-}
+/// The key protecting the outer AES-GCM layer of a connection, derived from the passphrase by `kdf`.
+pub type ChannelKey = [u8; 32];
 
 pub fn generate_session_key() -> Vec<u8> {
     let mut session_key = vec![0u8; 32];
@@ -28,7 +22,7 @@ pub fn generate_nonce() -> [u8; 12] {
 }
 
 pub fn encrypt(data: &[u8], session_key: &[u8]) -> Vec<u8> {
-    let cipher = Aes256Gcm::new_from_slice(&session_key).expect("Key creation failed");
+    let cipher = Aes256Gcm::new_from_slice(session_key).expect("Key creation failed");
 
     let nonce = generate_nonce();
     let mut ciphertext = cipher
@@ -42,6 +36,10 @@ pub fn encrypt(data: &[u8], session_key: &[u8]) -> Vec<u8> {
 }
 
 pub fn decrypt(encrypted_data: &[u8], key: &[u8]) -> Result<Vec<u8>, AeadError> {
+    // Both come from the peer, a short frame or a session key of the wrong size is just invalid.
+    if encrypted_data.len() < 12 || key.len() != 32 {
+        return Err(AeadError);
+    }
     let cipher = Aes256Gcm::new(GenericArray::from_slice(key));
 
     let (nonce, ciphertext) = encrypted_data.split_at(12);
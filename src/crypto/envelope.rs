@@ -5,7 +5,9 @@ use serde::{Serialize, Deserialize};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Envelope {
+    #[serde(with = "serde_bytes")]
     pub encrypted_session_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
     pub encrypted_command: Vec<u8>,
 }
 
@@ -30,24 +32,21 @@ impl Envelope {
             .expect("Failed to encrypt session key")
     }
 
-    pub fn decrypt_session_key(private_key: &RsaPrivateKey, encrypted_session_key: &[u8]) -> Vec<u8> {
+    pub fn decrypt_session_key(private_key: &RsaPrivateKey, encrypted_session_key: &[u8]) -> Result<Vec<u8>, String> {
         private_key.decrypt(Pkcs1v15Encrypt, encrypted_session_key)
-            .expect("Failed to decrypt session key")
+            .map_err(|e| format!("Failed to decrypt session key: {}", e))
     }
 
     pub fn create_encrypted_envelope(public_key: &RsaPublicKey, command: &[u8], session_key: &[u8]) -> Envelope {
-        let encrypted_session_key = Self::encrypt_session_key(public_key, &session_key);
-        let encrypted_command = crate::crypto::aes::encrypt(command, &session_key);
+        let encrypted_session_key = Self::encrypt_session_key(public_key, session_key);
+        let encrypted_command = crate::crypto::aes::encrypt(command, session_key);
         Envelope::new(encrypted_session_key, encrypted_command)
     }
 
-    pub fn decrypt_envelope(private_key: &RsaPrivateKey, envelope: Envelope) -> (Vec<u8>, Vec<u8>) {
-        let session_key = Self::decrypt_session_key(private_key, &envelope.encrypted_session_key);
-        let decrypted_command = crate::crypto::aes::decrypt(&envelope.encrypted_command, &session_key).unwrap();
-        (session_key, decrypted_command)
-    }
-
-    pub fn to_bytes(&self) -> Vec<u8> {
-        serde_json::to_vec(self).expect("Failed to serialize Envelope to JSON")
+    pub fn decrypt_envelope(private_key: &RsaPrivateKey, envelope: Envelope) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let session_key = Self::decrypt_session_key(private_key, &envelope.encrypted_session_key)?;
+        let decrypted_command = crate::crypto::aes::decrypt(&envelope.encrypted_command, &session_key)
+            .map_err(|_| "Failed to decrypt the command in the envelope".to_string())?;
+        Ok((session_key, decrypted_command))
     }
 }
\ No newline at end of file
@@ -0,0 +1,115 @@
+use aes_gcm::aead::OsRng;
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use crate::crypto::aes::ChannelKey;
+
+pub const SALT_LENGTH: usize = 16;
+pub const ALGORITHM: &str = "argon2id";
+
+// Upper bounds a node accepts from its peer, so a peer cannot make it allocate or compute without limit.
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ITERATIONS: u32 = 64;
+const MAX_PARALLELISM: u32 = 16;
+// Each derivation takes `memory_kib`, connections beyond this many wait for their turn.
+const MAX_CONCURRENT_DERIVATIONS: usize = 2;
+
+/// Argon2id cost parameters, `[kdf]` in the config file. The defaults are the OWASP recommendation.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+impl KdfParams {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.memory_kib > MAX_MEMORY_KIB || self.iterations > MAX_ITERATIONS || self.parallelism > MAX_PARALLELISM {
+            return Err(format!(
+                "KDF parameters {} exceed the limits of {} KiB, {} iterations and {} lanes",
+                self, MAX_MEMORY_KIB, MAX_ITERATIONS, MAX_PARALLELISM
+            ));
+        }
+        self.argon2_params().map(|_| ())
+    }
+
+    /// The client only derives with parameters at least as costly as its own, so whoever answers
+    /// the connection cannot talk it into a key that is cheap to guess.
+    pub fn check_at_least(&self, minimum: &KdfParams) -> Result<(), String> {
+        if self.memory_kib < minimum.memory_kib || self.iterations < minimum.iterations || self.parallelism < minimum.parallelism {
+            return Err(format!("KDF parameters {} are weaker than the required {}", self, minimum));
+        }
+        self.validate()
+    }
+
+    fn argon2_params(&self) -> Result<Params, String> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| format!("Invalid KDF parameters {}: {}", self, e))
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(m={} KiB, t={}, p={})", self.memory_kib, self.iterations, self.parallelism)
+    }
+}
+
+/// Sent in the clear by the accepting node as part of its hello.
+#[derive(Serialize, Deserialize)]
+pub struct KdfAnnouncement {
+    pub algorithm: String,
+    pub salt: Vec<u8>,
+    pub params: KdfParams,
+}
+
+/// The passphrase together with the cost of turning it into a channel key. The key is derived once
+/// per connection, with that connection's salt.
+pub struct KeyDerivation {
+    passphrase: String,
+    params: KdfParams,
+    permits: Semaphore,
+}
+
+impl KeyDerivation {
+    pub fn new(passphrase: String, params: KdfParams) -> Self {
+        KeyDerivation { passphrase, params, permits: Semaphore::new(MAX_CONCURRENT_DERIVATIONS) }
+    }
+
+    pub fn params(&self) -> KdfParams {
+        self.params
+    }
+
+    /// A fresh announcement for an accepted connection.
+    pub fn announce(&self) -> KdfAnnouncement {
+        let mut salt = vec![0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        KdfAnnouncement { algorithm: ALGORITHM.to_string(), salt, params: self.params }
+    }
+
+    /// Memory-hard, so it runs on the blocking pool instead of stalling other connections, and only
+    /// a few at a time however many peers connect at once.
+    pub async fn derive(&self, salt: &[u8], params: KdfParams) -> Result<ChannelKey, String> {
+        let _permit = self.permits.acquire().await.map_err(|e| format!("Key derivation failed: {}", e))?;
+        let passphrase = self.passphrase.clone();
+        let salt = salt.to_vec();
+        tokio::task::spawn_blocking(move || derive_key(passphrase.as_bytes(), &salt, &params))
+            .await
+            .map_err(|e| format!("Key derivation failed: {}", e))?
+    }
+}
+
+pub fn derive_key(passphrase: &[u8], salt: &[u8], params: &KdfParams) -> Result<ChannelKey, String> {
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.argon2_params()?);
+    let mut key = [0u8; 32];
+    argon2.hash_password_into(passphrase, salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
@@ -1,2 +1,4 @@
 pub mod aes;
-pub mod envelope;
\ No newline at end of file
+pub mod envelope;
+pub mod kdf;
+pub mod passphrase;
\ No newline at end of file
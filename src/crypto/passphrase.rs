@@ -0,0 +1,62 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use aes_gcm::aead::OsRng;
+use rand::RngCore;
+
+/// Used with `--insecure` when no passphrase is configured, as before passphrases were enforced.
+pub const INSECURE_FALLBACK: &str = "default_passphrase";
+/// Passphrases that shipped as defaults and must be assumed to be known.
+const KNOWN_DEFAULTS: [&str; 2] = [INSECURE_FALLBACK, "my_precompiled_passphrase"];
+const MIN_LENGTH: usize = 12;
+const MIN_DISTINCT_CHARACTERS: usize = 6;
+
+/// A random passphrase for `uplink keygen`: `bytes` bytes from the OS generator, hex encoded.
+pub fn generate(bytes: usize) -> String {
+    let mut random = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut random);
+    random.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Writes the passphrase to a new file only the owner can read.
+pub fn write_to_file(path: &Path, passphrase: &str) -> Result<(), String> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    writeln!(file, "{}", passphrase).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+/// Reads the first line of a file, as written by `uplink keygen --out`.
+pub fn read_file(path: &Path) -> Result<String, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read passphrase file {}: {}", path.display(), e))?;
+    Ok(content.lines().next().unwrap_or_default().to_string())
+}
+
+/// Asks on the terminal without echoing what is typed.
+pub fn prompt() -> Result<String, String> {
+    rpassword::prompt_password("Passphrase: ").map_err(|e| format!("Failed to read the passphrase: {}", e))
+}
+
+/// Why the passphrase is not good enough to protect the channel, if it is not.
+pub fn weakness(passphrase: &str) -> Option<String> {
+    if passphrase.is_empty() {
+        return Some("is empty".to_string());
+    }
+    if KNOWN_DEFAULTS.contains(&passphrase) {
+        return Some("is a built-in default".to_string());
+    }
+    if passphrase.chars().count() < MIN_LENGTH {
+        return Some(format!("is shorter than {} characters", MIN_LENGTH));
+    }
+    if passphrase.chars().collect::<HashSet<_>>().len() < MIN_DISTINCT_CHARACTERS {
+        return Some(format!("has fewer than {} different characters", MIN_DISTINCT_CHARACTERS));
+    }
+    None
+}
@@ -1,4 +1,5 @@
 use serde::{Serialize, Deserialize};
+use crate::enums::sync::{BlockSignature, SyncChange};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
@@ -10,7 +11,56 @@ pub enum Command {
     Netstat,
     Network,
     Handshake,
-    GetFile { file_path: String, file_local_path: String },
-    PutFile { file_path: String, file_up_path: String, data: Vec<u8> },
+    GetFile { file_path: String, transfer_id: u32 },
+    PutFile {
+        file_path: String,
+        file_up_path: String,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+        transfer_id: u32,
+        offset: u64,
+        total_size: u64,
+        mode: Option<u32>,
+        options: WriteOptions,
+    },
     Execute { command: String },
-}
\ No newline at end of file
+    Find {
+        root: String,
+        pattern: String,
+        max_depth: Option<usize>,
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+        newer_than_secs: Option<u64>,
+        limit: Option<usize>,
+    },
+    Grep { pattern: String, path: String, recursive: bool, limit: Option<usize> },
+    Tail { path: String, lines: usize, follow: bool },
+    Jobs,
+    Cancel { job_id: Option<u32> },
+    SyncManifest { request_id: u32, root: String },
+    SyncSignatures { request_id: u32, root: String, path: String, block_size: u32 },
+    SyncDelta { request_id: u32, root: String, path: String, block_size: u32, signatures: Vec<BlockSignature> },
+    SyncApply { request_id: u32, root: String, path: String, change: SyncChange },
+    /// Opens a connection to `host:port` for a forwarded connection, see `Response::ForwardConnected`.
+    ForwardConnect { forward_id: u32, stream_id: u32, host: String, port: u16 },
+    ForwardData { forward_id: u32, stream_id: u32, #[serde(with = "serde_bytes")] data: Vec<u8> },
+    /// The sender will not send more data on the stream.
+    ForwardClose { forward_id: u32, stream_id: u32 },
+    /// Listens on `port` and tunnels every connection back, see `Response::ForwardListening`.
+    ForwardListen { forward_id: u32, port: u16 },
+    ForwardUnlisten { forward_id: u32 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExistingFilePolicy {
+    Overwrite,
+    #[default]
+    Skip,
+    Rename,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    pub existing: ExistingFilePolicy,
+    pub follow_symlinks: bool,
+}
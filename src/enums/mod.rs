@@ -1,2 +1,3 @@
 pub mod command;
-pub mod response;
\ No newline at end of file
+pub mod response;
+pub mod sync;
\ No newline at end of file
@@ -1,11 +1,35 @@
 use serde::{Serialize, Deserialize};
+use crate::enums::sync::SyncReply;
+use crate::policy::capabilities::Capability;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Response {
     Message { content: String },
+    /// The command failed, `content` says why.
+    Error { content: String },
     FileList { files: Vec<String> },
     UserList { users: Vec<String> },
-    FileData { file_path: String, data: Vec<u8> },
-    CommandOutput { output: String },
-    Handshake { public_key: Vec<u8> }
-}
\ No newline at end of file
+    FileData { file_path: String, #[serde(with = "serde_bytes")] data: Vec<u8>, transfer_id: u32, offset: u64, total_size: u64, mode: Option<u32> },
+    TransferStatus { transfer_id: u32, transferred: u64, error: Option<String> },
+    /// `exit_code` is None when the program was killed by a signal.
+    CommandOutput { output: String, exit_code: Option<i32> },
+    PolicyDenied { operation: String, reason: String },
+    Capabilities { capabilities: Vec<Capability> },
+    Handshake { #[serde(with = "serde_bytes")] public_key: Vec<u8> },
+    SearchResults { matches: Vec<SearchMatch>, done: bool, truncated: bool },
+    TailData { path: String, content: String, job_id: Option<u32> },
+    Sync { request_id: u32, reply: SyncReply },
+    ForwardConnected { forward_id: u32, stream_id: u32, error: Option<String> },
+    ForwardData { forward_id: u32, stream_id: u32, #[serde(with = "serde_bytes")] data: Vec<u8> },
+    ForwardClose { forward_id: u32, stream_id: u32 },
+    ForwardListening { forward_id: u32, address: String, error: Option<String> },
+    /// A connection to a forward listening here, tunnelled back as stream `stream_id`.
+    ForwardAccepted { forward_id: u32, stream_id: u32, client: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchMatch {
+    pub path: String,
+    pub line_number: Option<u64>,
+    pub line: Option<String>,
+}
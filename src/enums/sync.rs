@@ -0,0 +1,55 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub mtime: u64,
+    pub hash: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum DeltaOp {
+    Copy { index: u32 },
+    Data { #[serde(with = "serde_bytes")] bytes: Vec<u8> },
+}
+
+/// One part of a file's delta. Large files are sent in several, each continuing the rebuilt file at `offset`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileDelta {
+    pub block_size: u32,
+    pub ops: Vec<DeltaOp>,
+    pub offset: u64,
+    /// Only set on the last part, the file is staged until then.
+    pub end: Option<DeltaEnd>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeltaEnd {
+    pub mtime: u64,
+    pub hash: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SyncChange {
+    Directory,
+    /// A part of a file's delta, the parts are staged under `transfer_id` until the last one.
+    File { transfer_id: u32, delta: FileDelta },
+    Delete,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum SyncReply {
+    Manifest(Vec<ManifestEntry>),
+    Signatures(Vec<BlockSignature>),
+    Delta(FileDelta),
+    Applied,
+    Error(String),
+}
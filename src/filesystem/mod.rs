@@ -0,0 +1,6 @@
+pub mod safe_write;
+pub mod sandbox;
+pub mod search;
+pub mod sync;
+pub mod tail;
+pub mod transfer;
\ No newline at end of file
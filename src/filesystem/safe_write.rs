@@ -0,0 +1,202 @@
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+use crate::enums::command::{ExistingFilePolicy, WriteOptions};
+
+/// Incoming files are assembled in a hidden file next to the target and only
+/// renamed over it once complete, so readers never see a half written file. The
+/// name carries a random per-process suffix, so it cannot be planted in advance.
+fn temp_path(target: &Path, transfer_id: u32) -> PathBuf {
+    static SUFFIX: OnceLock<u64> = OnceLock::new();
+    let suffix = SUFFIX.get_or_init(rand::random);
+    let file_name = target.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    target.with_file_name(format!(".{}.uplink-{}-{:016x}.part", file_name, transfer_id, suffix))
+}
+
+/// Applies the symlink and existing file policies to `target` and returns the path to write to.
+pub async fn check_target(target: &Path, options: &WriteOptions) -> Result<PathBuf, String> {
+    let mut target = target.to_path_buf();
+
+    if let Ok(metadata) = fs::symlink_metadata(&target).await {
+        if metadata.file_type().is_symlink() {
+            if !options.follow_symlinks {
+                return Err(format!("{} is a symlink (use --follow-symlinks to write through it)", target.display()));
+            }
+            target = resolve_symlink(&target).await?;
+        }
+    }
+
+    match fs::metadata(&target).await {
+        Ok(metadata) if metadata.is_dir() => Err(format!("{} is a directory", target.display())),
+        Ok(_) => match options.existing {
+            ExistingFilePolicy::Overwrite => Ok(target),
+            ExistingFilePolicy::Rename => Ok(unique_path(&target).await),
+            ExistingFilePolicy::Skip => Err(format!("{} already exists (use --overwrite or --rename)", target.display())),
+        },
+        Err(_) => Ok(target),
+    }
+}
+
+async fn resolve_symlink(link: &Path) -> Result<PathBuf, String> {
+    let destination = fs::read_link(link).await.map_err(|e| format!("Failed to read symlink {}: {}", link.display(), e))?;
+    Ok(match link.parent() {
+        Some(parent) if destination.is_relative() => parent.join(destination),
+        _ => destination,
+    })
+}
+
+async fn unique_path(target: &Path) -> PathBuf {
+    let stem = target.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = target.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+
+    let mut counter = 1;
+    loop {
+        let candidate = target.with_file_name(format!("{} ({}){}", stem, counter, extension));
+        if fs::symlink_metadata(&candidate).await.is_err() {
+            return candidate;
+        }
+        counter += 1;
+    }
+}
+
+pub struct IncomingChunk<'a> {
+    pub transfer_id: u32,
+    pub offset: u64,
+    pub total_size: u64,
+    pub mode: Option<u32>,
+    pub data: &'a [u8],
+}
+
+/// Handles one chunk of an incoming file: the target is checked before the first chunk
+/// and the file is moved into place after the last. Returns the final path once complete.
+pub async fn receive_chunk(target: &Path, chunk: &IncomingChunk<'_>, options: &WriteOptions) -> Result<Option<PathBuf>, String> {
+    if chunk.offset == 0 {
+        check_target(target, options).await?;
+    }
+
+    if let Err(e) = write_chunk(target, chunk.transfer_id, chunk.offset, chunk.data).await {
+        abort(target, chunk.transfer_id).await;
+        return Err(e);
+    }
+
+    if chunk.offset + chunk.data.len() as u64 >= chunk.total_size {
+        commit(target, chunk.transfer_id, options, chunk.mode).await.map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Writes a chunk into the temp file for `target`. The first chunk creates it exclusively,
+/// replacing what a restarted transfer left behind without following a symlink there.
+pub async fn write_chunk(target: &Path, transfer_id: u32, offset: u64, data: &[u8]) -> Result<(), String> {
+    let temp = temp_path(target, transfer_id);
+    let result = async {
+        let mut file = if offset == 0 {
+            fs::remove_file(&temp).await.ok();
+            OpenOptions::new().write(true).create_new(true).open(&temp).await?
+        } else {
+            OpenOptions::new().write(true).open(&temp).await?
+        };
+        file.seek(SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        file.flush().await
+    }.await;
+    result.map_err(|e| format!("Failed to write file: {}", e))
+}
+
+/// Moves the completed temp file into place according to `options` and returns the final path.
+/// Without a `mode` from the sender the mode of the file being replaced is kept.
+pub async fn commit(target: &Path, transfer_id: u32, options: &WriteOptions, mode: Option<u32>) -> Result<PathBuf, String> {
+    let temp = temp_path(target, transfer_id);
+    let destination = match check_target(target, options).await {
+        Ok(destination) => destination,
+        Err(e) => {
+            abort(target, transfer_id).await;
+            return Err(e);
+        }
+    };
+
+    let existing_mode = fs::metadata(&destination).await.ok().and_then(|metadata| file_mode(&metadata));
+    if let Some(mode) = mode.or(existing_mode) {
+        set_mode(&temp, mode).await;
+    }
+
+    match fs::rename(&temp, &destination).await {
+        Ok(()) => Ok(destination),
+        Err(e) => {
+            abort(target, transfer_id).await;
+            Err(format!("Failed to move file into place: {}", e))
+        }
+    }
+}
+
+pub async fn abort(target: &Path, transfer_id: u32) {
+    fs::remove_file(temp_path(target, transfer_id)).await.ok();
+}
+
+#[cfg(unix)]
+pub fn file_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode() & 0o777)
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+async fn set_mode(path: &Path, mode: u32) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Err(e) = fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777)).await {
+        tracing::warn!("Failed to set mode of {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(not(unix))]
+async fn set_mode(_path: &Path, _mode: u32) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("uplink-write-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn chunks_are_assembled_and_moved_into_place() {
+        let dir = temp_dir("chunks");
+        let target = dir.join("file.bin");
+        let options = WriteOptions::default();
+
+        let first = IncomingChunk { transfer_id: 1, offset: 0, total_size: 6, mode: None, data: b"abc" };
+        assert_eq!(receive_chunk(&target, &first, &options).await.unwrap(), None);
+        let last = IncomingChunk { transfer_id: 1, offset: 3, total_size: 6, mode: None, data: b"def" };
+        assert_eq!(receive_chunk(&target, &last, &options).await.unwrap(), Some(target.clone()));
+
+        assert_eq!(std::fs::read(&target).unwrap(), b"abcdef");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_symlink_at_the_temp_path_is_replaced_not_followed() {
+        let dir = temp_dir("symlink");
+        let target = dir.join("file.bin");
+        let outside = dir.join("outside.txt");
+        std::fs::write(&outside, b"untouched").unwrap();
+        std::os::unix::fs::symlink(&outside, temp_path(&target, 7)).unwrap();
+
+        write_chunk(&target, 7, 0, b"data").await.unwrap();
+        assert_eq!(std::fs::read(&outside).unwrap(), b"untouched");
+        assert_eq!(std::fs::read(temp_path(&target, 7)).unwrap(), b"data");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
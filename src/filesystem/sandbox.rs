@@ -0,0 +1,187 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Directory trees the peer's file commands are confined to (`--transfer-root` read-write,
+/// `--read-root` read-only). Without any root every path is allowed.
+pub struct Sandbox {
+    read_roots: Vec<PathBuf>,
+    write_roots: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Transfer roots allow reading and writing, read roots only reading.
+    pub fn new(transfer_roots: &[PathBuf], read_roots: &[PathBuf]) -> Result<Self, String> {
+        let canonical_roots = |roots: &[PathBuf]| -> Result<Vec<PathBuf>, String> {
+            roots.iter()
+                .map(|root| match fs::canonicalize(root) {
+                    Ok(root) if root.is_dir() => Ok(root),
+                    Ok(_) => Err(format!("{} is not a directory", root.display())),
+                    Err(e) => Err(format!("Invalid root {}: {}", root.display(), e)),
+                })
+                .collect()
+        };
+
+        let write_roots = canonical_roots(transfer_roots)?;
+        let read_roots = write_roots.iter().cloned().chain(canonical_roots(read_roots)?).collect();
+        Ok(Sandbox { read_roots, write_roots })
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        !self.read_roots.is_empty()
+    }
+
+    pub fn describe(&self) -> Vec<String> {
+        self.read_roots.iter()
+            .map(|root| {
+                let access = if self.write_roots.contains(root) { "read-write" } else { "read-only" };
+                format!("{} ({})", root.display(), access)
+            })
+            .collect()
+    }
+
+    /// Resolves an existing path to read from. Symlinks are followed before the check, so a
+    /// link inside a root pointing outside of it is refused.
+    pub fn resolve_read(&self, path: &Path) -> Result<PathBuf, String> {
+        if !self.is_restricted() {
+            return Ok(path.to_path_buf());
+        }
+
+        let resolved = fs::canonicalize(self.absolute(path)).map_err(|e| format!("{}: {}", path.display(), e))?;
+        self.check(path, &resolved, &self.read_roots)?;
+        Ok(resolved)
+    }
+
+    /// Resolves a path to write to, which does not need to exist yet. The parent directories are
+    /// canonicalized, the final component is kept so a symlink there is still handled by the
+    /// write policy, but only if it points inside a write root.
+    pub fn resolve_write(&self, path: &Path) -> Result<PathBuf, String> {
+        if !self.is_restricted() {
+            return Ok(path.to_path_buf());
+        }
+
+        let absolute = self.absolute(path);
+        let (parent, file_name) = match (absolute.parent(), absolute.file_name()) {
+            (Some(parent), Some(file_name)) => (parent, file_name),
+            _ => return Err(format!("{} is not a valid file path", path.display())),
+        };
+        let target = canonicalize_existing(parent)?.join(file_name);
+        self.check(path, &target, &self.write_roots)?;
+
+        if fs::symlink_metadata(&target).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+            let destination = fs::canonicalize(&target).map_err(|e| format!("{}: {}", path.display(), e))?;
+            self.check(path, &destination, &self.write_roots)?;
+        }
+        Ok(target)
+    }
+
+    // Relative paths start at the first root rather than the node's working directory.
+    fn absolute(&self, path: &Path) -> PathBuf {
+        match self.read_roots.first() {
+            Some(base) if path.is_relative() => base.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    fn check(&self, requested: &Path, resolved: &Path, roots: &[PathBuf]) -> Result<(), String> {
+        let within = |roots: &[PathBuf]| roots.iter().any(|root| resolved.starts_with(root));
+        if within(roots) {
+            Ok(())
+        } else if within(&self.read_roots) {
+            Err(format!("Access denied: {} is in a read-only directory", requested.display()))
+        } else {
+            Err(format!("Access denied: {} is outside the allowed directories", requested.display()))
+        }
+    }
+}
+
+// Canonicalizes the longest existing prefix of `path`. The part that does not exist yet may
+// only contain plain names, a `..` there could not be checked against the roots.
+fn canonicalize_existing(path: &Path) -> Result<PathBuf, String> {
+    for ancestor in path.ancestors() {
+        if let Ok(mut resolved) = fs::canonicalize(ancestor) {
+            let remainder = path.strip_prefix(ancestor).unwrap_or(Path::new(""));
+            for component in remainder.components() {
+                match component {
+                    Component::Normal(name) => resolved.push(name),
+                    Component::CurDir => {}
+                    _ => return Err(format!("{} is not a valid file path", path.display())),
+                }
+            }
+            return Ok(resolved);
+        }
+    }
+    Err(format!("{} is not a valid file path", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Dirs {
+        base: PathBuf,
+        writable: PathBuf,
+        readable: PathBuf,
+        outside: PathBuf,
+    }
+
+    impl Dirs {
+        fn new(name: &str) -> Self {
+            let base = fs::canonicalize(std::env::temp_dir()).unwrap().join(format!("uplink-sandbox-{}-{}", name, std::process::id()));
+            let dirs = Dirs { writable: base.join("writable"), readable: base.join("readable"), outside: base.join("outside"), base };
+            for dir in [&dirs.writable, &dirs.readable, &dirs.outside] {
+                fs::create_dir_all(dir).unwrap();
+                fs::write(dir.join("file.txt"), b"content").unwrap();
+            }
+            dirs
+        }
+
+        fn sandbox(&self) -> Sandbox {
+            Sandbox::new(std::slice::from_ref(&self.writable), std::slice::from_ref(&self.readable)).unwrap()
+        }
+    }
+
+    impl Drop for Dirs {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.base).ok();
+        }
+    }
+
+    #[test]
+    fn paths_are_confined_to_their_roots() {
+        let dirs = Dirs::new("roots");
+        let sandbox = dirs.sandbox();
+
+        assert_eq!(sandbox.resolve_read(&dirs.readable.join("file.txt")).unwrap(), dirs.readable.join("file.txt"));
+        assert!(sandbox.resolve_read(&dirs.outside.join("file.txt")).unwrap_err().contains("outside the allowed"));
+        assert!(sandbox.resolve_read(&dirs.writable.join("../outside/file.txt")).is_err());
+
+        assert_eq!(sandbox.resolve_write(&dirs.writable.join("new/name.txt")).unwrap(), dirs.writable.join("new/name.txt"));
+        assert!(sandbox.resolve_write(&dirs.readable.join("file.txt")).unwrap_err().contains("read-only"));
+        assert!(sandbox.resolve_write(&dirs.writable.join("new/../../outside/file.txt")).is_err());
+
+        // Relative paths start at the first root.
+        assert_eq!(sandbox.resolve_read(Path::new("file.txt")).unwrap(), dirs.writable.join("file.txt"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_leaving_the_roots_are_refused() {
+        let dirs = Dirs::new("symlinks");
+        let sandbox = dirs.sandbox();
+        std::os::unix::fs::symlink(&dirs.outside, dirs.writable.join("escape")).unwrap();
+        std::os::unix::fs::symlink(dirs.outside.join("file.txt"), dirs.writable.join("link.txt")).unwrap();
+
+        assert!(sandbox.resolve_read(&dirs.writable.join("escape/file.txt")).is_err());
+        assert!(sandbox.resolve_write(&dirs.writable.join("escape/file.txt")).is_err());
+        assert!(sandbox.resolve_read(&dirs.writable.join("link.txt")).is_err());
+        assert!(sandbox.resolve_write(&dirs.writable.join("link.txt")).is_err());
+    }
+
+    #[test]
+    fn without_roots_every_path_is_allowed() {
+        let sandbox = Sandbox::new(&[], &[]).unwrap();
+        assert!(!sandbox.is_restricted());
+        assert_eq!(sandbox.resolve_write(Path::new("/etc/passwd")).unwrap(), Path::new("/etc/passwd"));
+        assert!(Sandbox::new(&[PathBuf::from("/nonexistent/uplink-root")], &[]).is_err());
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use globset::{Glob, GlobMatcher};
+use regex::Regex;
+use tokio::sync::mpsc::Sender;
+use crate::enums::response::SearchMatch;
+
+const MAX_LINE_LENGTH: usize = 512;
+const MAX_SCANNED_LINE_LENGTH: u64 = 64 * 1024;
+const BINARY_SNIFF_SIZE: usize = 8192;
+
+#[derive(Clone, Copy)]
+pub struct FindFilter {
+    pub max_depth: Option<usize>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub newer_than: Option<Duration>,
+}
+
+struct Walker<'a> {
+    sender: &'a Sender<SearchMatch>,
+    max_results: usize,
+    found: usize,
+    truncated: bool,
+}
+
+impl<'a> Walker<'a> {
+    fn new(sender: &'a Sender<SearchMatch>, max_results: usize) -> Self {
+        Self { sender, max_results, found: 0, truncated: false }
+    }
+
+    // Returns false once the result limit is hit or the receiving side has gone away.
+    fn emit(&mut self, search_match: SearchMatch) -> bool {
+        if self.found >= self.max_results {
+            self.truncated = true;
+            return false;
+        }
+        self.found += 1;
+        self.sender.blocking_send(search_match).is_ok()
+    }
+}
+
+pub fn compile_glob(pattern: &str) -> Result<GlobMatcher, String> {
+    Glob::new(pattern)
+        .map(|glob| glob.compile_matcher())
+        .map_err(|e| format!("Invalid glob pattern: {}", e))
+}
+
+pub fn compile_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|e| format!("Invalid pattern: {}", e))
+}
+
+/// Walks `root` and sends every entry matching `matcher` and `filter`.
+/// Returns true if the walk stopped early because `max_results` was reached.
+pub fn find(root: &Path, matcher: &GlobMatcher, filter: &FindFilter, sender: &Sender<SearchMatch>, max_results: usize) -> Result<bool, String> {
+    if !root.is_dir() {
+        return Err(format!("{} is not a directory", root.display()));
+    }
+
+    let newer_than = filter.newer_than.and_then(|age| SystemTime::now().checked_sub(age));
+    let mut walker = Walker::new(sender, max_results);
+    find_in(root, root, 1, matcher, filter, newer_than, &mut walker);
+    Ok(walker.truncated)
+}
+
+fn find_in(
+    root: &Path,
+    dir: &Path,
+    depth: usize,
+    matcher: &GlobMatcher,
+    filter: &FindFilter,
+    newer_than: Option<SystemTime>,
+    walker: &mut Walker,
+) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return true,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        let name_matches = matcher.is_match(entry.file_name()) || matcher.is_match(relative);
+
+        if name_matches && matches_filter(&metadata, filter, newer_than) {
+            let search_match = SearchMatch { path: path.display().to_string(), line_number: None, line: None };
+            if !walker.emit(search_match) {
+                return false;
+            }
+        }
+
+        let within_depth = filter.max_depth.is_none_or(|max_depth| depth < max_depth);
+        if metadata.is_dir() && within_depth && !find_in(root, &path, depth + 1, matcher, filter, newer_than, walker) {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn matches_filter(metadata: &fs::Metadata, filter: &FindFilter, newer_than: Option<SystemTime>) -> bool {
+    if filter.min_size.is_some() || filter.max_size.is_some() {
+        if !metadata.is_file() {
+            return false;
+        }
+        let size = metadata.len();
+        if filter.min_size.is_some_and(|min| size < min) || filter.max_size.is_some_and(|max| size > max) {
+            return false;
+        }
+    }
+
+    if let Some(newer_than) = newer_than {
+        match metadata.modified() {
+            Ok(modified) if modified >= newer_than => {}
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+/// Searches `path` (a file, or a directory when `recursive`) for lines matching `regex`.
+/// Returns true if the search stopped early because `max_results` was reached.
+pub fn grep(path: &Path, regex: &Regex, recursive: bool, sender: &Sender<SearchMatch>, max_results: usize) -> Result<bool, String> {
+    let mut walker = Walker::new(sender, max_results);
+
+    if path.is_file() {
+        grep_file(path, regex, &mut walker);
+    } else if path.is_dir() {
+        if !recursive {
+            return Err(format!("{} is a directory (use -r to search recursively)", path.display()));
+        }
+        grep_dir(path, regex, &mut walker);
+    } else {
+        return Err(format!("{} does not exist", path.display()));
+    }
+
+    Ok(walker.truncated)
+}
+
+fn grep_dir(dir: &Path, regex: &Regex, walker: &mut Walker) -> bool {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return true,
+    };
+
+    for entry in entries.flatten() {
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+
+        let keep_going = if file_type.is_dir() {
+            grep_dir(&entry.path(), regex, walker)
+        } else if file_type.is_file() {
+            grep_file(&entry.path(), regex, walker)
+        } else {
+            true
+        };
+
+        if !keep_going {
+            return false;
+        }
+    }
+
+    true
+}
+
+fn grep_file(path: &Path, regex: &Regex, walker: &mut Walker) -> bool {
+    if is_binary(path) {
+        return true;
+    }
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return true,
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut buffer = Vec::new();
+    let mut line_number = 0u64;
+
+    loop {
+        buffer.clear();
+        match read_line(&mut reader, &mut buffer) {
+            Ok(0) | Err(_) => return true,
+            Ok(_) => line_number += 1,
+        }
+
+        let line = String::from_utf8_lossy(&buffer);
+        let line = line.trim_end_matches(['\n', '\r']);
+        if !regex.is_match(line) {
+            continue;
+        }
+
+        let search_match = SearchMatch {
+            path: path.display().to_string(),
+            line_number: Some(line_number),
+            line: Some(line.chars().take(MAX_LINE_LENGTH).collect()),
+        };
+        if !walker.emit(search_match) {
+            return false;
+        }
+    }
+}
+
+// Reads one line into `buffer`. Only the first `MAX_SCANNED_LINE_LENGTH` bytes of a longer line are
+// kept and searched, the rest of it is skipped.
+fn read_line(reader: &mut impl BufRead, buffer: &mut Vec<u8>) -> io::Result<usize> {
+    let read = reader.take(MAX_SCANNED_LINE_LENGTH).read_until(b'\n', buffer)?;
+    if read as u64 == MAX_SCANNED_LINE_LENGTH && buffer.last() != Some(&b'\n') {
+        reader.skip_until(b'\n')?;
+    }
+    Ok(read)
+}
+
+fn is_binary(path: &Path) -> bool {
+    let mut buffer = [0u8; BINARY_SNIFF_SIZE];
+    match File::open(path).and_then(|mut file| file.read(&mut buffer)) {
+        Ok(n) => buffer[..n].contains(&0),
+        Err(_) => false,
+    }
+}
@@ -0,0 +1,602 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use crate::enums::sync::{BlockSignature, DeltaEnd, DeltaOp, FileDelta, ManifestEntry, SyncChange};
+use crate::filesystem::transfer::CHUNK_SIZE;
+
+/// Files smaller than this are always sent whole, a block delta would not pay for itself.
+pub const DELTA_THRESHOLD: u64 = 64 * 1024;
+
+/// Literal data in one part of a delta. Parts are sent in frames of their own, like the chunks of GET and PUT.
+pub const DELTA_PART_SIZE: usize = CHUNK_SIZE as usize;
+// Copied blocks cost a few bytes each, this keeps the part for a large unchanged stretch small too.
+const MAX_PART_OPS: usize = 64 * 1024;
+const READ_SIZE: usize = 64 * 1024;
+
+const MIN_BLOCK_SIZE: u32 = 2048;
+const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+const STRONG_HASH_SIZE: usize = 16;
+
+pub struct PlannedFile {
+    pub entry: ManifestEntry,
+    pub has_basis: bool,
+}
+
+pub struct SyncPlan {
+    pub directories: Vec<String>,
+    pub files: Vec<PlannedFile>,
+    pub deletions: Vec<String>,
+    pub unchanged: usize,
+}
+
+/// Joins a peer supplied relative path onto `root`, refusing anything that could leave it or
+/// name the root itself.
+pub fn resolve(root: &Path, relative: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(relative);
+    let mut components = relative.components().peekable();
+    if components.peek().is_none() || components.any(|component| !matches!(component, Component::Normal(_))) {
+        return Err(format!("Refusing unsafe sync path: {}", relative.display()));
+    }
+    Ok(root.join(relative))
+}
+
+pub fn build_manifest(root: &Path) -> Result<Vec<ManifestEntry>, String> {
+    let mut entries = vec![];
+    match fs::metadata(root) {
+        Ok(metadata) if metadata.is_dir() => {
+            collect_entries(root, "", &mut entries).map_err(|e| format!("Failed to read {}: {}", root.display(), e))?;
+        }
+        Ok(_) => return Err(format!("{} is not a directory", root.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("Failed to read {}: {}", root.display(), e)),
+    }
+    Ok(entries)
+}
+
+fn collect_entries(dir: &Path, prefix: &str, entries: &mut Vec<ManifestEntry>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = fs::symlink_metadata(entry.path())?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let relative = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+
+        if metadata.is_dir() {
+            entries.push(ManifestEntry { path: relative.clone(), is_dir: true, size: 0, mtime: 0, hash: vec![] });
+            collect_entries(&entry.path(), &relative, entries)?;
+        } else if metadata.is_file() {
+            entries.push(ManifestEntry {
+                path: relative,
+                is_dir: false,
+                size: metadata.len(),
+                mtime: modified_secs(&metadata),
+                hash: hash_file(&entry.path())?,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Decides what has to change on the destination to make it match the source.
+/// Files with equal size and either equal mtime or equal content are left alone.
+pub fn plan(source: &[ManifestEntry], destination: &[ManifestEntry], delete: bool) -> SyncPlan {
+    let existing: HashMap<&str, &ManifestEntry> = destination.iter().map(|entry| (entry.path.as_str(), entry)).collect();
+    let mut plan = SyncPlan { directories: vec![], files: vec![], deletions: vec![], unchanged: 0 };
+
+    for entry in source {
+        let current = existing.get(entry.path.as_str());
+        if entry.is_dir {
+            match current {
+                Some(current) if current.is_dir => {}
+                _ => plan.directories.push(entry.path.clone()),
+            }
+            continue;
+        }
+
+        match current {
+            Some(current) if !current.is_dir && current.size == entry.size && (current.mtime == entry.mtime || current.hash == entry.hash) => {
+                plan.unchanged += 1;
+            }
+            Some(current) => plan.files.push(PlannedFile { entry: entry.clone(), has_basis: !current.is_dir }),
+            None => plan.files.push(PlannedFile { entry: entry.clone(), has_basis: false }),
+        }
+    }
+
+    if delete {
+        let wanted: HashSet<&str> = source.iter().map(|entry| entry.path.as_str()).collect();
+        for entry in destination {
+            let inside_deleted_dir = plan.deletions.iter().any(|deleted| entry.path.starts_with(&format!("{}/", deleted)));
+            if !wanted.contains(entry.path.as_str()) && !inside_deleted_dir {
+                plan.deletions.push(entry.path.clone());
+            }
+        }
+    }
+
+    plan
+}
+
+pub fn block_size_for(size: u64) -> u32 {
+    ((size as f64).sqrt() as u32).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+pub fn signatures(path: &Path, block_size: u32) -> Result<Vec<BlockSignature>, String> {
+    check_block_size(block_size)?;
+    let read_failed = |e: io::Error| format!("Failed to read {}: {}", path.display(), e);
+    let mut file = File::open(path).map_err(read_failed)?;
+    let mut signatures = vec![];
+    let mut block = Vec::with_capacity(block_size as usize);
+    loop {
+        block.clear();
+        (&mut file).take(block_size as u64).read_to_end(&mut block).map_err(read_failed)?;
+        if block.is_empty() {
+            return Ok(signatures);
+        }
+        signatures.push(BlockSignature { weak: RollingChecksum::new(&block).digest(), strong: strong_hash(&block) });
+    }
+}
+
+/// Describes the file at `path` as blocks copied from a basis with the given signatures plus
+/// literal data, one part at a time, so neither side holds the whole file. Without signatures
+/// the whole file is literal data.
+pub struct DeltaReader {
+    path: PathBuf,
+    file: File,
+    signatures: Vec<BlockSignature>,
+    index: HashMap<u32, Vec<u32>>,
+    block_size: u32,
+    buffer: Vec<u8>,
+    position: usize,
+    rolling: Option<RollingChecksum>,
+    at_end: bool,
+    offset: u64,
+    hasher: Sha256,
+    mtime: u64,
+}
+
+impl DeltaReader {
+    pub fn open(path: &Path, signatures: Vec<BlockSignature>, block_size: u32) -> Result<Self, String> {
+        check_block_size(block_size)?;
+        let read_failed = |e: io::Error| format!("Failed to read {}: {}", path.display(), e);
+        let file = File::open(path).map_err(read_failed)?;
+        let metadata = file.metadata().map_err(read_failed)?;
+
+        let mut index: HashMap<u32, Vec<u32>> = HashMap::new();
+        for (i, signature) in signatures.iter().enumerate() {
+            index.entry(signature.weak).or_default().push(i as u32);
+        }
+
+        Ok(DeltaReader {
+            path: path.to_path_buf(),
+            file,
+            signatures,
+            index,
+            block_size,
+            buffer: vec![],
+            position: 0,
+            rolling: None,
+            at_end: false,
+            offset: 0,
+            hasher: Sha256::new(),
+            mtime: modified_secs(&metadata),
+        })
+    }
+
+    /// Bytes of the file the parts so far described.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The next part, with at most `DELTA_PART_SIZE` of literal data. The last one has the
+    /// file's hash and modification time.
+    pub fn next_part(&mut self) -> Result<FileDelta, String> {
+        let block = self.block_size as usize;
+        let offset = self.offset;
+        let mut ops = vec![];
+        let mut literal = vec![];
+
+        let end = loop {
+            if literal.len() >= DELTA_PART_SIZE || ops.len() >= MAX_PART_OPS {
+                break None;
+            }
+            self.fill(block + 1)?;
+            let available = self.buffer.len() - self.position;
+
+            if self.index.is_empty() && available > 0 {
+                let length = available.min(DELTA_PART_SIZE - literal.len());
+                literal.extend_from_slice(&self.buffer[self.position..self.position + length]);
+                self.advance(length);
+                continue;
+            }
+
+            let window_end = self.position + block;
+            if !self.index.is_empty() && window_end <= self.buffer.len() {
+                let window = &self.buffer[self.position..window_end];
+                let rolling = self.rolling.get_or_insert_with(|| RollingChecksum::new(window));
+                if let Some(matched) = find_block(&self.index, &self.signatures, rolling.digest(), window) {
+                    flush_literal(&mut ops, &mut literal);
+                    ops.push(DeltaOp::Copy { index: matched });
+                    self.rolling = None;
+                    self.advance(block);
+                    continue;
+                }
+
+                let outgoing = self.buffer[self.position];
+                literal.push(outgoing);
+                match self.buffer.get(window_end) {
+                    Some(&incoming) => rolling.roll(outgoing, incoming),
+                    None => self.rolling = None,
+                }
+                self.advance(1);
+                continue;
+            }
+
+            // Less than a block is left. The basis' final block may be shorter than `block_size`,
+            // so this tail can still match it.
+            let tail = &self.buffer[self.position..];
+            let last = self.signatures.len().saturating_sub(1) as u32;
+            if !tail.is_empty() && find_block(&self.index, &self.signatures, RollingChecksum::new(tail).digest(), tail) == Some(last) {
+                flush_literal(&mut ops, &mut literal);
+                ops.push(DeltaOp::Copy { index: last });
+            } else {
+                literal.extend_from_slice(tail);
+            }
+            self.advance(tail.len());
+            self.hasher.update(&self.buffer[..self.position]);
+            break Some(DeltaEnd { mtime: self.mtime, hash: std::mem::take(&mut self.hasher).finalize().to_vec() });
+        };
+        flush_literal(&mut ops, &mut literal);
+
+        Ok(FileDelta { block_size: self.block_size, ops, offset, end })
+    }
+
+    fn advance(&mut self, length: usize) {
+        self.position += length;
+        self.offset += length as u64;
+    }
+
+    // Buffers at least `wanted` bytes from the current position unless the file ends first.
+    // What was already described is hashed and dropped.
+    fn fill(&mut self, wanted: usize) -> Result<(), String> {
+        if self.at_end || self.buffer.len() - self.position >= wanted {
+            return Ok(());
+        }
+        self.hasher.update(&self.buffer[..self.position]);
+        self.buffer.drain(..self.position);
+        self.position = 0;
+
+        while !self.at_end && self.buffer.len() < wanted {
+            let read = (&mut self.file).take(READ_SIZE as u64).read_to_end(&mut self.buffer)
+                .map_err(|e| format!("Failed to read {}: {}", self.path.display(), e))?;
+            self.at_end = read == 0;
+        }
+        Ok(())
+    }
+}
+
+fn check_block_size(block_size: u32) -> Result<(), String> {
+    if (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&block_size) {
+        Ok(())
+    } else {
+        Err(format!("Invalid sync block size: {}", block_size))
+    }
+}
+
+fn find_block(index: &HashMap<u32, Vec<u32>>, signatures: &[BlockSignature], weak: u32, window: &[u8]) -> Option<u32> {
+    let candidates = index.get(&weak)?;
+    let strong = strong_hash(window);
+    candidates.iter().copied().find(|&i| signatures[i as usize].strong == strong)
+}
+
+fn flush_literal(ops: &mut Vec<DeltaOp>, literal: &mut Vec<u8>) {
+    if !literal.is_empty() {
+        ops.push(DeltaOp::Data { bytes: std::mem::take(literal) });
+    }
+}
+
+pub fn apply_change(root: &Path, relative: &str, change: &SyncChange) -> Result<(), String> {
+    let target = resolve(root, relative)?;
+    let result = match change {
+        SyncChange::Directory => {
+            if target.is_file() {
+                fs::remove_file(&target).ok();
+            }
+            fs::create_dir_all(&target)
+        }
+        SyncChange::Delete => match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target),
+            Ok(_) => fs::remove_file(&target),
+            Err(e) => Err(e),
+        },
+        SyncChange::File { transfer_id, delta } => return apply_delta(&target, *transfer_id, delta),
+    };
+    result.map_err(|e| format!("Failed to update {}: {}", target.display(), e))
+}
+
+// Parts are written to a staging file next to the target, which is moved over it once the last
+// part checks out, so the basis stays intact until the new content is complete.
+fn apply_delta(target: &Path, transfer_id: u32, delta: &FileDelta) -> Result<(), String> {
+    check_block_size(delta.block_size)?;
+    let staging = staging_path(target, transfer_id);
+    let result = stage(target, &staging, delta).and_then(|file| match &delta.end {
+        Some(end) => complete(target, &staging, file, end),
+        None => Ok(()),
+    });
+    if result.is_err() {
+        fs::remove_file(&staging).ok();
+    }
+    result
+}
+
+/// Staging files carry a random per-process suffix like the temp files of PUT, so they cannot be planted in advance.
+fn staging_path(target: &Path, transfer_id: u32) -> PathBuf {
+    static SUFFIX: OnceLock<u64> = OnceLock::new();
+    let suffix = SUFFIX.get_or_init(rand::random);
+    let file_name = target.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    target.with_file_name(format!(".{}.uplink-sync-{}-{:016x}", file_name, transfer_id, suffix))
+}
+
+// Writes a part at its offset. The first part creates the staging file exclusively, replacing what
+// an aborted sync left behind without following a symlink there. A part sent again after a
+// reconnect overwrites what it wrote before.
+fn stage(target: &Path, staging: &Path, delta: &FileDelta) -> Result<File, String> {
+    let write_failed = |e: io::Error| format!("Failed to write {}: {}", target.display(), e);
+    let mut file = if delta.offset == 0 {
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(write_failed)?;
+        }
+        fs::remove_file(staging).ok();
+        OpenOptions::new().write(true).create_new(true).open(staging)
+    } else {
+        OpenOptions::new().write(true).open(staging)
+    }.map_err(write_failed)?;
+
+    if file.metadata().map_err(write_failed)?.len() < delta.offset {
+        return Err(format!("Delta for {} is missing a part", target.display()));
+    }
+    file.set_len(delta.offset).and_then(|_| file.seek(SeekFrom::Start(delta.offset))).map_err(write_failed)?;
+
+    let mut basis = None;
+    let block = delta.block_size as u64;
+    for op in &delta.ops {
+        match op {
+            DeltaOp::Copy { index } => {
+                let basis = match &mut basis {
+                    Some(basis) => basis,
+                    None => basis.insert(File::open(target).map_err(|e| format!("Failed to read basis {}: {}", target.display(), e))?),
+                };
+                basis.seek(SeekFrom::Start(*index as u64 * block)).map_err(write_failed)?;
+                if io::copy(&mut basis.take(block), &mut file).map_err(write_failed)? == 0 {
+                    return Err(format!("Delta for {} references a missing block", target.display()));
+                }
+            }
+            DeltaOp::Data { bytes } => file.write_all(bytes).map_err(write_failed)?,
+        }
+    }
+    Ok(file)
+}
+
+fn complete(target: &Path, staging: &Path, file: File, end: &DeltaEnd) -> Result<(), String> {
+    let write_failed = |e: io::Error| format!("Failed to write {}: {}", target.display(), e);
+    file.set_modified(UNIX_EPOCH + Duration::from_secs(end.mtime)).map_err(write_failed)?;
+    drop(file);
+    if hash_file(staging).map_err(write_failed)? != end.hash {
+        return Err(format!("Checksum mismatch after patching {}", target.display()));
+    }
+    if target.is_dir() {
+        fs::remove_dir_all(target).map_err(write_failed)?;
+    }
+    fs::rename(staging, target).map_err(write_failed)
+}
+
+pub fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+fn strong_hash(block: &[u8]) -> Vec<u8> {
+    Sha256::digest(block)[..STRONG_HASH_SIZE].to_vec()
+}
+
+fn modified_secs(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |age| age.as_secs())
+}
+
+/// rsync style weak checksum that can slide over the data one byte at a time.
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    length: u32,
+}
+
+impl RollingChecksum {
+    fn new(block: &[u8]) -> Self {
+        let length = block.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((length - i as u32).wrapping_mul(byte as u32));
+        }
+        RollingChecksum { a: a & 0xffff, b: b & 0xffff, length }
+    }
+
+    fn roll(&mut self, outgoing: u8, incoming: u8) {
+        self.a = self.a.wrapping_sub(outgoing as u32).wrapping_add(incoming as u32) & 0xffff;
+        self.b = self.b.wrapping_sub(self.length.wrapping_mul(outgoing as u32)).wrapping_add(self.a) & 0xffff;
+    }
+
+    fn digest(&self) -> u32 {
+        self.a | (self.b << 16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enums::command::Command;
+    use crate::transport::codec;
+    use crate::transport::communication::DEFAULT_MAX_FRAME_SIZE;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("uplink-sync-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolve_refuses_paths_leaving_or_naming_the_root() {
+        let root = Path::new("/srv/sync");
+        assert_eq!(resolve(root, "a/b.txt").unwrap(), root.join("a/b.txt"));
+        for relative in ["", ".", "./", "..", "a/../..", "/etc/passwd"] {
+            assert!(resolve(root, relative).is_err(), "{:?} was accepted", relative);
+        }
+    }
+
+    #[test]
+    fn deleting_an_empty_path_keeps_the_root() {
+        let root = temp_dir("delete");
+        fs::write(root.join("kept.txt"), b"kept").unwrap();
+
+        assert!(apply_change(&root, "", &SyncChange::Delete).is_err());
+        assert!(root.join("kept.txt").is_file());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    // Bytes that do not repeat within a block, so every block of the basis has its own signature.
+    fn content(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    // Applies the delta of `source` to `relative` part by part, the way SYNC sends it.
+    fn sync_file(root: &Path, source: &Path, relative: &str, signatures: Vec<BlockSignature>, block_size: u32) -> Result<Vec<FileDelta>, String> {
+        let mut reader = DeltaReader::open(source, signatures, block_size)?;
+        let mut parts = vec![];
+        loop {
+            let delta = reader.next_part()?;
+            let last = delta.end.is_some();
+            apply_change(root, relative, &SyncChange::File { transfer_id: 1, delta: delta.clone() })?;
+            parts.push(delta);
+            if last {
+                return Ok(parts);
+            }
+        }
+    }
+
+    fn literal_bytes(parts: &[FileDelta]) -> usize {
+        parts.iter().flat_map(|delta| &delta.ops).map(|op| match op {
+            DeltaOp::Data { bytes } => bytes.len(),
+            DeltaOp::Copy { .. } => 0,
+        }).sum()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replacing_a_file_does_not_write_through_a_planted_symlink() {
+        let root = temp_dir("symlink");
+        let outside = root.join("outside.txt");
+        fs::write(&outside, b"untouched").unwrap();
+        fs::write(root.join("source.txt"), b"new").unwrap();
+        std::os::unix::fs::symlink(&outside, staging_path(&root.join("target.txt"), 1)).unwrap();
+
+        sync_file(&root, &root.join("source.txt"), "target.txt", vec![], MIN_BLOCK_SIZE).unwrap();
+        assert_eq!(fs::read(&outside).unwrap(), b"untouched");
+        assert_eq!(fs::read(root.join("target.txt")).unwrap(), b"new");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_delta_rebuilds_the_edited_file_from_the_basis() {
+        let root = temp_dir("delta");
+        let basis = content(100_000, 1);
+        let mut edited = basis.clone();
+        edited.splice(30_000..30_000, b"inserted in the middle".iter().copied());
+        edited.truncate(90_000);
+        edited.extend_from_slice(&content(500, 2));
+        fs::write(root.join("target.bin"), &basis).unwrap();
+        fs::write(root.join("source.bin"), &edited).unwrap();
+
+        let block_size = block_size_for(basis.len() as u64);
+        let basis_signatures = signatures(&root.join("target.bin"), block_size).unwrap();
+        let parts = sync_file(&root, &root.join("source.bin"), "target.bin", basis_signatures, block_size).unwrap();
+        assert!(literal_bytes(&parts) < 4 * block_size as usize, "{} literal bytes", literal_bytes(&parts));
+        assert_eq!(fs::read(root.join("target.bin")).unwrap(), edited);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_file_larger_than_a_frame_is_sent_in_parts_that_fit_one() {
+        let root = temp_dir("large");
+        let source = root.join("source.bin");
+        let data = content(DEFAULT_MAX_FRAME_SIZE + DELTA_PART_SIZE * 3 / 2, 3);
+        fs::write(&source, &data).unwrap();
+
+        let parts = sync_file(&root, &source, "copy.bin", vec![], MIN_BLOCK_SIZE).unwrap();
+        assert_eq!(parts.len(), data.len().div_ceil(DELTA_PART_SIZE));
+        assert_eq!(literal_bytes(&parts), data.len());
+        for delta in parts {
+            let change = SyncChange::File { transfer_id: 1, delta };
+            let frame = codec::encode(&Command::SyncApply { request_id: 1, root: "/srv".to_string(), path: "copy.bin".to_string(), change });
+            assert!(frame.len() < DELTA_PART_SIZE + 1024, "{} byte frame", frame.len());
+        }
+        assert_eq!(fs::read(root.join("copy.bin")).unwrap(), data);
+
+        // Against that copy only the edited blocks are sent.
+        let mut edited = data;
+        edited[DEFAULT_MAX_FRAME_SIZE / 2..][..100].fill(0);
+        edited.extend_from_slice(b"appended");
+        fs::write(&source, &edited).unwrap();
+        let block_size = block_size_for(edited.len() as u64);
+        let basis_signatures = signatures(&root.join("copy.bin"), block_size).unwrap();
+        let parts = sync_file(&root, &source, "copy.bin", basis_signatures, block_size).unwrap();
+        assert!(literal_bytes(&parts) < 4 * block_size as usize, "{} literal bytes", literal_bytes(&parts));
+        assert_eq!(fs::read(root.join("copy.bin")).unwrap(), edited);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deltas_not_matching_the_basis_are_refused() {
+        let root = temp_dir("mismatch");
+        let data = content(10_000, 4);
+        fs::write(root.join("source.bin"), &data).unwrap();
+        fs::write(root.join("target.bin"), &data).unwrap();
+        let basis = signatures(&root.join("target.bin"), MIN_BLOCK_SIZE).unwrap();
+        let delta = DeltaReader::open(&root.join("source.bin"), basis, MIN_BLOCK_SIZE).unwrap().next_part().unwrap();
+        assert!(delta.end.is_some());
+
+        // The basis changed after its signatures were taken.
+        fs::write(root.join("target.bin"), content(10_000, 5)).unwrap();
+        let error = apply_change(&root, "target.bin", &SyncChange::File { transfer_id: 1, delta: delta.clone() }).unwrap_err();
+        assert!(error.contains("Checksum mismatch"), "{}", error);
+        assert_eq!(fs::read(root.join("target.bin")).unwrap(), content(10_000, 5));
+        assert!(!staging_path(&root.join("target.bin"), 1).exists());
+
+        let invalid = FileDelta { block_size: 16, ..delta.clone() };
+        assert!(apply_change(&root, "target.bin", &SyncChange::File { transfer_id: 1, delta: invalid }).is_err());
+        let without_first_part = FileDelta { offset: 4096, ..delta };
+        assert!(apply_change(&root, "target.bin", &SyncChange::File { transfer_id: 2, delta: without_first_part }).is_err());
+        assert!(signatures(&root.join("target.bin"), MAX_BLOCK_SIZE + 1).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
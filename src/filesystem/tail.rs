@@ -0,0 +1,214 @@
+use std::fs::Metadata;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
+use crate::filesystem::sandbox::Sandbox;
+
+const SCAN_CHUNK_SIZE: u64 = 8192;
+const MAX_READ_PER_POLL: u64 = 1024 * 1024;
+
+pub enum TailEvent {
+    Data(Vec<u8>),
+    Truncated,
+    Rotated,
+}
+
+/// Keeps a file open and reports data appended to it, following the path
+/// across truncation and log rotation (rename + recreate). `path` is the one the
+/// peer asked for, it goes through the sandbox again before a rotated file is opened.
+pub struct TailFollower {
+    path: PathBuf,
+    file: File,
+    position: u64,
+    identity: Option<(u64, u64)>,
+}
+
+impl TailFollower {
+    /// Opens `resolved`, the sandboxed form of `path`, and returns the follower positioned at
+    /// the end of the file, together with the last `lines` lines of its current content. Like a
+    /// poll it reads at most `MAX_READ_PER_POLL` bytes, longer lines are cut at the front.
+    pub async fn open(path: &Path, resolved: &Path, lines: usize) -> io::Result<(Self, Vec<u8>)> {
+        let mut file = File::open(resolved).await?;
+        let metadata = file.metadata().await?;
+        if !metadata.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a regular file", path.display())));
+        }
+
+        let end = metadata.len();
+        let start = find_last_lines_offset(&mut file, end.saturating_sub(MAX_READ_PER_POLL), end, lines).await?;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut content = Vec::with_capacity((end - start) as usize);
+        (&mut file).take(end - start).read_to_end(&mut content).await?;
+
+        let follower = TailFollower {
+            path: path.to_path_buf(),
+            file,
+            position: end,
+            identity: file_identity(&metadata),
+        };
+        Ok((follower, content))
+    }
+
+    pub async fn poll(&mut self, sandbox: &Sandbox) -> io::Result<Vec<TailEvent>> {
+        let mut events = vec![];
+
+        let length = self.file.metadata().await?.len();
+        if length < self.position {
+            self.position = 0;
+            events.push(TailEvent::Truncated);
+        }
+
+        let data = self.read_appended(length).await?;
+        if !data.is_empty() {
+            events.push(TailEvent::Data(data));
+        }
+
+        // Whatever was written to the old file before the rotation is read first, the
+        // switch waits for a poll that finds the old handle drained.
+        if self.position < length {
+            return Ok(events);
+        }
+        if let Some((file, length)) = self.rotated(sandbox).await? {
+            self.file = file;
+            self.position = 0;
+            events.push(TailEvent::Rotated);
+
+            let data = self.read_appended(length).await?;
+            if !data.is_empty() {
+                events.push(TailEvent::Data(data));
+            }
+        }
+
+        Ok(events)
+    }
+
+    // A different file behind the same path means the log was rotated. A path that no longer
+    // resolves inside the sandbox, or not at all yet, keeps the old file.
+    async fn rotated(&mut self, sandbox: &Sandbox) -> io::Result<Option<(File, u64)>> {
+        let Ok(resolved) = sandbox.resolve_read(&self.path) else { return Ok(None) };
+        let Ok(metadata) = fs::metadata(&resolved).await else { return Ok(None) };
+        let identity = file_identity(&metadata);
+        if identity.is_none() || identity == self.identity || !metadata.is_file() {
+            return Ok(None);
+        }
+
+        let file = File::open(&resolved).await?;
+        let metadata = file.metadata().await?;
+        self.identity = file_identity(&metadata);
+        Ok(Some((file, metadata.len())))
+    }
+
+    async fn read_appended(&mut self, length: u64) -> io::Result<Vec<u8>> {
+        if length <= self.position {
+            return Ok(vec![]);
+        }
+
+        let available = (length - self.position).min(MAX_READ_PER_POLL);
+        self.file.seek(SeekFrom::Start(self.position)).await?;
+        let mut data = Vec::with_capacity(available as usize);
+        (&mut self.file).take(available).read_to_end(&mut data).await?;
+        self.position += data.len() as u64;
+        Ok(data)
+    }
+}
+
+// Scans back from `end` no further than `floor`, which is returned if fewer lines start after it.
+async fn find_last_lines_offset(file: &mut File, floor: u64, end: u64, lines: usize) -> io::Result<u64> {
+    if lines == 0 {
+        return Ok(end);
+    }
+
+    let mut newlines = 0;
+    let mut offset = end;
+    let mut buffer = vec![0u8; SCAN_CHUNK_SIZE as usize];
+
+    while offset > floor {
+        let chunk_size = SCAN_CHUNK_SIZE.min(offset - floor);
+        offset -= chunk_size;
+        file.seek(SeekFrom::Start(offset)).await?;
+        let chunk = &mut buffer[..chunk_size as usize];
+        file.read_exact(chunk).await?;
+
+        for (i, byte) in chunk.iter().enumerate().rev() {
+            // A newline terminating the very last line does not start a new one.
+            if *byte != b'\n' || offset + i as u64 == end - 1 {
+                continue;
+            }
+            newlines += 1;
+            if newlines == lines {
+                return Ok(offset + i as u64 + 1);
+            }
+        }
+    }
+
+    Ok(floor)
+}
+
+#[cfg(unix)]
+fn file_identity(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rotation_waits_until_the_old_file_is_drained() {
+        let dir = std::env::temp_dir().join(format!("uplink-tail-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        std::fs::write(&path, b"").unwrap();
+        let sandbox = Sandbox::new(std::slice::from_ref(&dir), &[]).unwrap();
+        let resolved = sandbox.resolve_read(&path).unwrap();
+        let (mut follower, _) = TailFollower::open(&path, &resolved, 10).await.unwrap();
+
+        let appended = vec![b'x'; (MAX_READ_PER_POLL * 2 + MAX_READ_PER_POLL / 2) as usize];
+        std::fs::write(&path, &appended).unwrap();
+        std::fs::rename(&path, dir.join("app.log.1")).unwrap();
+        std::fs::write(&path, b"new").unwrap();
+
+        let mut old = vec![];
+        let mut rotated = None;
+        for _ in 0..5 {
+            for event in follower.poll(&sandbox).await.unwrap() {
+                match event {
+                    TailEvent::Data(data) if rotated.is_none() => old.extend(data),
+                    TailEvent::Data(data) => rotated = Some(data),
+                    TailEvent::Rotated => rotated = Some(vec![]),
+                    TailEvent::Truncated => panic!("not truncated"),
+                }
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(old, appended);
+        assert_eq!(rotated.as_deref(), Some(&b"new"[..]));
+    }
+
+    #[tokio::test]
+    async fn the_initial_lines_are_cut_to_one_read() {
+        let dir = std::env::temp_dir().join(format!("uplink-tail-initial-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("app.log");
+        let mut content = b"first\n".to_vec();
+        content.extend(vec![b'x'; (MAX_READ_PER_POLL * 3) as usize]);
+        content.extend(b"\nlast\n");
+        std::fs::write(&path, &content).unwrap();
+
+        let (_, last_two) = TailFollower::open(&path, &path, 2).await.unwrap();
+        let (_, last_one) = TailFollower::open(&path, &path, 1).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(last_two.len() as u64, MAX_READ_PER_POLL);
+        assert!(last_two.ends_with(b"x\nlast\n"));
+        assert_eq!(last_one, b"last\n");
+    }
+}
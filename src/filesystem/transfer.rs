@@ -0,0 +1,11 @@
+use tokio::fs::File;
+use tokio::io::{self, AsyncReadExt};
+
+/// GET and PUT move files in chunks of this size so both sides can report progress.
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+
+pub async fn read_chunk(file: &mut File) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(CHUNK_SIZE as usize);
+    file.take(CHUNK_SIZE).read_to_end(&mut data).await?;
+    Ok(data)
+}
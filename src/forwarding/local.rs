@@ -0,0 +1,47 @@
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use crate::enums::command::Command as NodeCommand;
+use crate::forwarding::tunnel::{self, Outbound};
+use crate::handlers::command_sender::CommandSender;
+use crate::shared_state::forwards::Origin;
+
+/// `FORWARD L`: listens on `bind` and tunnels every connection through the link to the peer, which
+/// connects onward to `host:port`. Returns the forward's id.
+pub async fn listen(bind: SocketAddr, host: String, port: u16, command_sender: CommandSender) -> Result<u32, String> {
+    let listener = TcpListener::bind(bind).await.map_err(|e| format!("Failed to listen on {}: {}", bind, e))?;
+    let bind = listener.local_addr().unwrap_or(bind);
+
+    let mut shared_state = command_sender.shared_state().lock().await;
+    let forward_id = shared_state.forwards.reserve_id();
+    let description = format!("L {} -> {}:{}", bind, host, port);
+    let task = tokio::spawn(accept_connections(listener, forward_id, host, port, command_sender.clone()));
+    shared_state.forwards.insert_local(forward_id, description, task.abort_handle());
+    Ok(forward_id)
+}
+
+async fn accept_connections(listener: TcpListener, forward_id: u32, host: String, port: u16, command_sender: CommandSender) {
+    let shared_state = command_sender.shared_state().clone();
+    loop {
+        let (socket, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Forward {} failed to accept a connection: {}", forward_id, e);
+                sleep(tunnel::ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let Some(stream_id) = shared_state.lock().await.forwards.next_stream(forward_id) else { return };
+        debug!("Forward {}: connection {} from {}", forward_id, stream_id, client);
+
+        let outbound = Outbound::Commands(command_sender.clone());
+        let queued = tunnel::open(forward_id, stream_id, &outbound).await;
+        let connect = NodeCommand::ForwardConnect { forward_id, stream_id, host: host.clone(), port };
+        if command_sender.send(connect).await {
+            tunnel::attach(socket, queued, forward_id, stream_id, outbound).await;
+        } else {
+            shared_state.lock().await.forwards.abort_stream(Origin::Here, forward_id, stream_id);
+        }
+    }
+}
@@ -0,0 +1,3 @@
+pub mod local;
+pub mod remote;
+pub mod tunnel;
\ No newline at end of file
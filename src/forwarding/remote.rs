@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tokio::time::sleep;
+use tracing::{debug, warn};
+use crate::enums::command::Command as NodeCommand;
+use crate::enums::response::Response;
+use crate::forwarding::tunnel::{self, Outbound};
+use crate::handlers::command_sender::CommandSender;
+use crate::handlers::response_sender::ResponseSender;
+use crate::policy::forward_policy::ForwardPolicy;
+use crate::shared_state::forwards::Origin;
+use crate::shared_state::shared_state::SharedStateHandle;
+
+/// `FORWARD R`, on the node asked to listen: binds `port` on the address the policy sets and
+/// tunnels every connection back to the peer. Returns the address it listens on.
+pub async fn listen(forward_id: u32, port: u16, policy: &ForwardPolicy, response_sender: ResponseSender) -> Result<SocketAddr, String> {
+    policy.check_port(port)?;
+    let bind = SocketAddr::new(policy.bind_address, port);
+    let listener = TcpListener::bind(bind).await.map_err(|e| format!("Failed to listen on {}: {}", bind, e))?;
+
+    let mut shared_state = response_sender.shared_state().lock().await;
+    let task = tokio::spawn(accept_connections(listener, forward_id, response_sender.clone()));
+    shared_state.forwards.insert_peer(forward_id, bind, task.abort_handle(), policy.max_connections);
+    Ok(bind)
+}
+
+async fn accept_connections(listener: TcpListener, forward_id: u32, response_sender: ResponseSender) {
+    let shared_state = response_sender.shared_state().clone();
+    loop {
+        let (socket, client) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("The peer's forward {} failed to accept a connection: {}", forward_id, e);
+                sleep(tunnel::ACCEPT_RETRY_DELAY).await;
+                continue;
+            }
+        };
+        let stream_id = match shared_state.lock().await.forwards.accept_peer(forward_id) {
+            Ok(stream_id) => stream_id,
+            Err(e) => {
+                warn!("The peer's forward {} refused a connection from {}: {}", forward_id, client, e);
+                continue;
+            }
+        };
+        debug!("The peer's forward {}: connection {} from {}", forward_id, stream_id, client);
+
+        let outbound = Outbound::Responses(response_sender.clone());
+        let queued = tunnel::open(forward_id, stream_id, &outbound).await;
+        let accepted = Response::ForwardAccepted { forward_id, stream_id, client: client.to_string() };
+        if response_sender.send(accepted).await {
+            tunnel::attach(socket, queued, forward_id, stream_id, outbound).await;
+        } else {
+            shared_state.lock().await.forwards.abort_stream(Origin::Peer, forward_id, stream_id);
+        }
+    }
+}
+
+/// `FORWARD R`, on the operator: the peer accepted a connection, which is connected to the
+/// forward's target here. One for a forward that is unknown or stopped here is closed with
+/// `session_sender`, the peer would keep its client waiting otherwise.
+pub async fn accepted(shared_state: &SharedStateHandle, session_sender: &CommandSender, forward_id: u32, stream_id: u32, client: String) {
+    let Some((host, port, command_sender)) = shared_state.lock().await.forwards.accept_remote(forward_id) else {
+        debug!("Connection {} for stopped forward {} closed", stream_id, forward_id);
+        session_sender.send(NodeCommand::ForwardClose { forward_id, stream_id }).await;
+        return;
+    };
+    // Opened before connecting, the peer may already be sending what its client wrote.
+    let outbound = Outbound::Commands(command_sender.clone());
+    let queued = tunnel::open(forward_id, stream_id, &outbound).await;
+    let shared_state = shared_state.clone();
+    tokio::spawn(async move {
+        match tunnel::connect(&host, port).await {
+            Ok(socket) => {
+                debug!("Forward {}: connection {} from {} on the peer", forward_id, stream_id, client);
+                tunnel::attach(socket, queued, forward_id, stream_id, outbound).await;
+            }
+            Err(e) => {
+                warn!("Forward {}: {}", forward_id, e);
+                shared_state.lock().await.forwards.abort_stream(Origin::Here, forward_id, stream_id);
+                command_sender.send(NodeCommand::ForwardClose { forward_id, stream_id }).await;
+            }
+        }
+    });
+}
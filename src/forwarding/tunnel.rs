@@ -0,0 +1,181 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, Receiver};
+use tracing::{debug, warn};
+use crate::enums::command::Command as NodeCommand;
+use crate::enums::response::Response;
+use crate::handlers::command_sender::CommandSender;
+use crate::handlers::response_sender::ResponseSender;
+use crate::shared_state::forwards::{Delivery, Origin};
+use crate::shared_state::shared_state::SharedStateHandle;
+
+const READ_BUFFER_SIZE: usize = 32 * 1024;
+// Pieces of data from the peer waiting for a stream's socket, up to 8 MiB. A socket that falls
+// further behind is closed instead of buffering without limit.
+const MAX_QUEUED_PIECES: usize = 256;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a forward's listener waits after a failed accept, e.g. when out of file descriptors.
+pub const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// How a forwarded connection reaches the peer: the node that set up the forward sends commands,
+/// the other one responds.
+#[derive(Clone)]
+pub enum Outbound {
+    Commands(CommandSender),
+    Responses(ResponseSender),
+}
+
+impl Outbound {
+    pub fn origin(&self) -> Origin {
+        match self {
+            Outbound::Commands(_) => Origin::Here,
+            Outbound::Responses(_) => Origin::Peer,
+        }
+    }
+
+    fn shared_state(&self) -> &SharedStateHandle {
+        match self {
+            Outbound::Commands(sender) => sender.shared_state(),
+            Outbound::Responses(sender) => sender.shared_state(),
+        }
+    }
+
+    async fn data(&self, forward_id: u32, stream_id: u32, data: Vec<u8>) -> bool {
+        match self {
+            Outbound::Commands(sender) => sender.send(NodeCommand::ForwardData { forward_id, stream_id, data }).await,
+            Outbound::Responses(sender) => sender.send(Response::ForwardData { forward_id, stream_id, data }).await,
+        }
+    }
+
+    pub async fn close(&self, forward_id: u32, stream_id: u32) {
+        match self {
+            Outbound::Commands(sender) => sender.send(NodeCommand::ForwardClose { forward_id, stream_id }).await,
+            Outbound::Responses(sender) => sender.send(Response::ForwardClose { forward_id, stream_id }).await,
+        };
+    }
+}
+
+/// Parses `[address:]port` to listen on, without an address only local clients can connect.
+pub fn parse_bind(bind: &str) -> Result<SocketAddr, String> {
+    if let Ok(port) = bind.parse::<u16>() {
+        return Ok(SocketAddr::from(([127, 0, 0, 1], port)));
+    }
+    bind.parse().map_err(|_| format!("Invalid address to listen on: {} (expected [address:]port)", bind))
+}
+
+/// Parses `host:port`, an IPv6 host in brackets.
+pub fn parse_target(target: &str) -> Result<(String, u16), String> {
+    let invalid = || format!("Invalid address: {} (expected host:port)", target);
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match port.parse::<u16>() {
+        Ok(port) if port != 0 && !host.is_empty() => Ok((host.to_string(), port)),
+        _ => Err(invalid()),
+    }
+}
+
+pub async fn connect(host: &str, port: u16) -> Result<TcpStream, String> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port))).await {
+        Ok(Ok(socket)) => Ok(socket),
+        Ok(Err(e)) => Err(format!("Failed to connect to {}:{}: {}", host, port, e)),
+        Err(_) => Err(format!("Timed out connecting to {}:{}", host, port)),
+    }
+}
+
+/// Registers stream `stream_id` of forward `forward_id`, which reaches the peer through `outbound`.
+/// Data from the peer is queued until `attach` gives the stream its socket.
+pub async fn open(forward_id: u32, stream_id: u32, outbound: &Outbound) -> Receiver<Vec<u8>> {
+    let (queue, queued) = mpsc::channel(MAX_QUEUED_PIECES);
+    outbound.shared_state().lock().await.forwards.open_stream(forward_id, stream_id, queue, outbound.clone());
+    queued
+}
+
+/// Writes the queued data to `socket` and sends what arrives on it to the peer until it closes.
+/// Called once the peer was told about the stream.
+pub async fn attach(socket: TcpStream, queued: Receiver<Vec<u8>>, forward_id: u32, stream_id: u32, outbound: Outbound) {
+    let (reader, writer) = socket.into_split();
+    tokio::spawn(write_stream(writer, queued));
+    let origin = outbound.origin();
+    let mut shared_state = outbound.shared_state().lock().await;
+    // Started while holding the table, so a close arriving meanwhile finds the task to stop.
+    let task = tokio::spawn(read_stream(reader, forward_id, stream_id, outbound.clone()));
+    if !shared_state.forwards.start_reading(origin, forward_id, stream_id, task.abort_handle()) {
+        task.abort();
+    }
+}
+
+/// Data from the peer for one of its streams, or for one of ours. Queued in pieces of at most the
+/// read buffer's size, so the queue's limit holds whatever size the peer sends.
+pub async fn deliver(shared_state: &SharedStateHandle, origin: Origin, forward_id: u32, stream_id: u32, data: Vec<u8>) {
+    let mut table = shared_state.lock().await;
+    for piece in data.chunks(READ_BUFFER_SIZE) {
+        match table.forwards.write(origin, forward_id, stream_id, piece.to_vec()) {
+            Delivery::Queued => {}
+            Delivery::Gone => {
+                debug!("Dropped data for closed stream {} of forward {}", stream_id, forward_id);
+                return;
+            }
+            Delivery::Overflowed(outbound) => {
+                drop(table);
+                warn!("Closed stream {} of forward {}, its connection does not take the data as fast as it arrives", stream_id, forward_id);
+                outbound.close(forward_id, stream_id).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn read_stream(mut reader: OwnedReadHalf, forward_id: u32, stream_id: u32, outbound: Outbound) {
+    let mut buffer = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer).await {
+            Ok(0) => break,
+            Ok(length) => {
+                if !outbound.data(forward_id, stream_id, buffer[..length].to_vec()).await {
+                    outbound.shared_state().lock().await.forwards.abort_stream(outbound.origin(), forward_id, stream_id);
+                    return;
+                }
+            }
+            Err(e) => {
+                debug!("Stream {} of forward {} failed: {}", stream_id, forward_id, e);
+                break;
+            }
+        }
+    }
+    outbound.close(forward_id, stream_id).await;
+}
+
+async fn write_stream(mut writer: OwnedWriteHalf, mut queued: Receiver<Vec<u8>>) {
+    while let Some(data) = queued.recv().await {
+        if let Err(e) = writer.write_all(&data).await {
+            debug!("Failed to write to a forwarded connection: {}", e);
+            return;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+    use crate::shared_state::shared_state::SharedState;
+
+    #[tokio::test]
+    async fn a_stream_whose_socket_falls_behind_is_closed() {
+        let shared_state: SharedStateHandle = Arc::new(Mutex::new(SharedState::new()));
+        let outbound = Outbound::Responses(ResponseSender::for_session(Arc::clone(&shared_state)));
+        let _queued = open(1, 1, &outbound).await;
+
+        // One large message from the peer takes as many places in the queue as it has pieces.
+        deliver(&shared_state, Origin::Peer, 1, 1, vec![0; READ_BUFFER_SIZE * (MAX_QUEUED_PIECES - 1)]).await;
+        assert!(matches!(shared_state.lock().await.forwards.write(Origin::Peer, 1, 1, vec![0]), Delivery::Queued));
+
+        deliver(&shared_state, Origin::Peer, 1, 1, vec![0]).await;
+        assert!(matches!(shared_state.lock().await.forwards.write(Origin::Peer, 1, 1, vec![0]), Delivery::Gone));
+    }
+}
@@ -1,4 +1,5 @@
 use tokio::sync::Mutex;
+use tracing::error;
 use crate::handlers::tx_command_handler::TxCommandHandler;
 use tokio::io::{self, AsyncBufReadExt};
 use std::sync::Arc;
@@ -17,19 +18,13 @@ pub async fn handle_cli(command_handler: Arc<Mutex<TxCommandHandler>>) {
                     continue;
                 }
 
-                let mut handler = command_handler.lock().await;
-                if handler.is_connection_active().await {
-                    handler.handle_command(command).await;
-                } else {
-                    println!("Connection inactive. Waiting to reconnect...");
-                    break;
-                }
+                command_handler.lock().await.handle_command(command).await;
             }
             Ok(None) => {
                 break;
             }
             Err(e) => {
-                eprintln!("Error reading line: {}", e);
+                error!("Error reading line: {}", e);
                 break;
             }
         }
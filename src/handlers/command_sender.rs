@@ -0,0 +1,63 @@
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::warn;
+use rsa::RsaPublicKey;
+use crate::crypto::envelope::Envelope;
+use crate::enums::command::Command as NodeCommand;
+use crate::handlers::response_sender::ResponseSender;
+use crate::shared_state::shared_state::SharedState;
+use crate::transport::codec::{self, Frame};
+
+/// Sends the operator's commands to the peer, sealed in an envelope unless envelopes are disabled.
+/// Cheap to clone, so forwarded connections can send from their own tasks.
+#[derive(Clone)]
+pub struct CommandSender {
+    frame_sender: ResponseSender,
+    no_envelope: bool,
+    shared_state: Arc<Mutex<SharedState>>,
+}
+
+impl CommandSender {
+    pub fn new(no_envelope: bool, shared_state: Arc<Mutex<SharedState>>) -> Self {
+        Self {
+            frame_sender: ResponseSender::for_session(Arc::clone(&shared_state)),
+            no_envelope,
+            shared_state,
+        }
+    }
+
+    /// True when the command was sent or kept for the peer to receive once it resumes the session.
+    pub async fn send(&self, node_command: NodeCommand) -> bool {
+        if let Some((public_key, session_key)) = self.get_keys().await {
+            let envelope = Envelope::create_encrypted_envelope(
+                &public_key,
+                &codec::encode(&node_command),
+                &session_key
+            );
+
+            self.send_frame(Frame::Envelope(envelope)).await
+        } else if !self.no_envelope {
+            warn!("Session key or public key not available. Command not sent.");
+            false
+        } else {
+            self.send_frame(Frame::Command(node_command)).await
+        }
+    }
+
+    pub async fn send_frame(&self, frame: Frame) -> bool {
+        let sent = self.frame_sender.send_frame(frame).await;
+        if !sent {
+            warn!("No active WebSocket connection. Command not sent.");
+        }
+        sent
+    }
+
+    async fn get_keys(&self) -> Option<(RsaPublicKey, Vec<u8>)> {
+        let shared_state = self.shared_state.lock().await;
+        Some((shared_state.server_public_key.clone()?, shared_state.session_key.clone()?))
+    }
+
+    pub fn shared_state(&self) -> &Arc<Mutex<SharedState>> {
+        &self.shared_state
+    }
+}
@@ -0,0 +1,54 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use crate::handlers::response_sender::ResponseSender;
+use crate::transport::codec::Frame;
+use crate::transport::communication::HeartbeatSettings;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pings the peer and watches the link. Returns once the peer has been silent for longer than the
+/// heartbeat timeout or the session has been idle for longer than the idle timeout, with the code and
+/// reason the connection should be closed with. A silent peer may still resume the session, an idle
+/// session is ended before returning so it is closed as `Normal` and cannot be resumed.
+pub async fn monitor(response_sender: ResponseSender, settings: HeartbeatSettings) -> (CloseCode, String) {
+    let heartbeats = !settings.interval.is_zero();
+    let mut last_ping = Instant::now();
+    let mut ticker = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        if !response_sender.is_current().await {
+            return (CloseCode::Away, "Replaced by a newer connection".to_string());
+        }
+
+        let ping = {
+            let mut shared_state = response_sender.shared_state().lock().await;
+            let Some(link) = shared_state.link.as_mut() else { continue };
+
+            // A peer that does not answer pings is only silent, not necessarily gone.
+            if heartbeats && link.heartbeats() && link.silent_for() > settings.timeout {
+                return (CloseCode::Away, format!("No sign of the peer for {} seconds", link.silent_for().as_secs()));
+            }
+            if let Some(idle_timeout) = settings.idle_timeout {
+                if link.idle_for() >= idle_timeout {
+                    drop(shared_state);
+                    response_sender.end_session().await;
+                    return (CloseCode::Normal, format!("Session idle for {} seconds", idle_timeout.as_secs()));
+                }
+            }
+
+            if heartbeats && link.heartbeats() && last_ping.elapsed() >= settings.interval {
+                last_ping = Instant::now();
+                Some((link.start_ping(), shared_state.session.received()))
+            } else {
+                None
+            }
+        };
+
+        if let Some((nonce, received)) = ping {
+            response_sender.send_frame(Frame::Ping { nonce, received }).await;
+        }
+    }
+}
@@ -1,4 +1,7 @@
 pub mod cli_handler;
+pub mod command_sender;
+pub mod heartbeat;
 pub mod response_handler;
+pub mod response_sender;
 pub mod rx_command_handler;
 pub mod tx_command_handler;
\ No newline at end of file
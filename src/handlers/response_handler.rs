@@ -1,33 +1,83 @@
 use crate::enums::response::Response;
-use tokio::fs;
+use crate::enums::sync::SyncReply;
+use tracing::{debug, error, info, warn};
+use std::io::Write;
+use std::path::Path;
+use crate::filesystem::safe_write::{self, IncomingChunk};
 use rsa::{pkcs1::DecodeRsaPublicKey, RsaPublicKey};
+use crate::forwarding::{remote, tunnel};
+use crate::handlers::command_sender::CommandSender;
+use crate::shared_state::forwards::Origin;
 use crate::shared_state::shared_state::SharedStateHandle;
 use crate::crypto::aes::generate_session_key;
+use crate::policy::capabilities::{self, CapabilitySet};
+
+/// `command_sender` answers the peer where a response needs it, e.g. a connection for a forward that is gone.
+pub async fn process_response(response: Response, shared_state: &SharedStateHandle, command_sender: &CommandSender) {
+    if let Response::CommandOutput { .. } | Response::PolicyDenied { .. } | Response::Message { .. } | Response::Error { .. } = response {
+        let pending = shared_state.lock().await.pending_exec_result.take();
+        if let Some(pending) = pending {
+            let _ = pending.send(response);
+            return;
+        }
+    }
 
-pub async fn process_response(response: Response, shared_state: &SharedStateHandle) {
     match response {
         Response::Message { content } => println!("\n{}\n", content),
+        Response::Error { content } => println!("\n[!] {}\n", content),
         Response::FileList { files } => {
             for file in files {
                 println!("{}", file);
             }
-            println!("");
+            println!();
         }
-        Response::FileData { file_path, data } => {
-            println!("{:?}", file_path);
-            if let Err(e) = fs::write(&file_path, data).await {
-                eprintln!("Failed to write file {}: {}", file_path, e);
-            }
+        Response::FileData { file_path, data, transfer_id, offset, total_size, mode } => {
+            let expected = shared_state.lock().await.transfers
+                .expected_download(transfer_id, offset, total_size)
+                .map(|transfer| (transfer.destination.clone(), transfer.write_options));
+            let (destination, write_options) = match expected {
+                Ok(expected) => expected,
+                Err(e) => {
+                    warn!("Rejected file data for {} (transfer {}): {}", file_path, transfer_id, e);
+                    return;
+                }
+            };
+
+            let chunk = IncomingChunk { transfer_id, offset, total_size, mode, data: &data };
+            let result = safe_write::receive_chunk(Path::new(&destination), &chunk, &write_options).await;
+            let mut shared_state = shared_state.lock().await;
+            let line = match result {
+                Ok(written) => {
+                    if let Some(written) = written.filter(|written| written.as_path() != Path::new(&destination)) {
+                        println!("\n[*] {} exists, saved as {}", destination, written.display());
+                    }
+                    shared_state.transfers.set_total(transfer_id, total_size);
+                    shared_state.transfers.update(transfer_id, offset + data.len() as u64)
+                }
+                Err(e) => {
+                    warn!("Failed to write file {}: {}", destination, e);
+                    shared_state.transfers.fail(transfer_id, &e)
+                }
+            };
+            print_progress(line);
+        }
+        Response::TransferStatus { transfer_id, transferred, error } => {
+            let mut shared_state = shared_state.lock().await;
+            let line = match error {
+                Some(e) => shared_state.transfers.fail(transfer_id, &e),
+                None => shared_state.transfers.update(transfer_id, transferred),
+            };
+            print_progress(line);
         }
         Response::UserList {users} => {
             for user in users {
                 println!("{}", user);
             }
-            println!("");
+            println!();
         }
         Response::Handshake { public_key } => {
-            let public_key_pem = String::from_utf8(public_key).expect("Failed to convert public key bytes to string");
-            println!("[+] Public key received\n{:?}\n", public_key_pem);
+            let public_key_pem = String::from_utf8_lossy(&public_key);
+            debug!("Public key received\n{}", public_key_pem);
 
             let mut shared_state = shared_state.lock().await;
             let session_key = generate_session_key();
@@ -35,13 +85,95 @@ pub async fn process_response(response: Response, shared_state: &SharedStateHand
             if let Ok(decoded_key) = RsaPublicKey::from_pkcs1_pem(&public_key_pem) {
                 shared_state.server_public_key = Some(decoded_key);
                 shared_state.session_key = Some(session_key.clone());
-                println!("[+] Peer public key has been set in shared state.");
-                println!("[+] Session key has been set in shared state.");
+                debug!("Peer public key and session key have been set in shared state.");
+                info!("Handshake completed.");
             } else {
-                eprintln!("[!] Failed to decode the received public key.");
+                error!("Failed to decode the received public key.");
+            }
+        }
+
+        Response::CommandOutput { output, exit_code } => {
+            println!("Command output:\n{}", output);
+            match exit_code {
+                Some(0) => println!(),
+                Some(code) => println!("[!] Exited with status {}\n", code),
+                None => println!("[!] Terminated by a signal\n"),
+            }
+        }
+        Response::Capabilities { capabilities } => {
+            let capabilities: CapabilitySet = capabilities.into_iter().collect();
+            info!("Connected node allows: {}", capabilities::format_capabilities(&capabilities));
+            shared_state.lock().await.peer_capabilities = Some(capabilities);
+        }
+        Response::PolicyDenied { operation, reason } => println!("\n[!] Denied by peer policy: {}: {}\n", operation, reason),
+        Response::TailData { content, .. } => {
+            print!("{}", content);
+            let _ = std::io::stdout().flush();
+        }
+        Response::Sync { request_id, reply } => {
+            // A delta arrives in parts, any other reply or the last part completes the request.
+            let completes = !matches!(&reply, SyncReply::Delta(delta) if delta.end.is_none());
+            let pending = {
+                let mut shared_state = shared_state.lock().await;
+                if completes {
+                    shared_state.pending_sync_replies.remove(&request_id)
+                } else {
+                    shared_state.pending_sync_replies.get(&request_id).cloned()
+                }
+            };
+            match pending {
+                // Waiting for the part before to be written holds off the next one.
+                Some(pending) => {
+                    let _ = pending.send(reply).await;
+                }
+                None => warn!("Ignoring unexpected sync reply {}.", request_id),
+            }
+        }
+        Response::ForwardConnected { forward_id, stream_id, error } => match error {
+            Some(e) => {
+                warn!("Connection {} of forward {} refused by the peer: {}", stream_id, forward_id, e);
+                shared_state.lock().await.forwards.abort_stream(Origin::Here, forward_id, stream_id);
+            }
+            None => debug!("Connection {} of forward {} established", stream_id, forward_id),
+        },
+        Response::ForwardData { forward_id, stream_id, data } => tunnel::deliver(shared_state, Origin::Here, forward_id, stream_id, data).await,
+        Response::ForwardClose { forward_id, stream_id } => shared_state.lock().await.forwards.close_stream(Origin::Here, forward_id, stream_id),
+        Response::ForwardListening { forward_id, address, error } => match error {
+            Some(e) => {
+                warn!("Forward {} refused by the peer: {}", forward_id, e);
+                shared_state.lock().await.forwards.stop(forward_id);
+            }
+            None => {
+                println!("\n[*] Forward {}: the connected node listens on {}\n", forward_id, address);
+                shared_state.lock().await.forwards.listening(forward_id, &address);
+            }
+        },
+        Response::ForwardAccepted { forward_id, stream_id, client } => remote::accepted(shared_state, command_sender, forward_id, stream_id, client).await,
+        Response::SearchResults { matches, done, truncated } => {
+            for search_match in matches {
+                match (search_match.line_number, search_match.line) {
+                    (Some(line_number), Some(line)) => println!("{}:{}: {}", search_match.path, line_number, line),
+                    _ => println!("{}", search_match.path),
+                }
+            }
+            if truncated {
+                println!("[!] Result limit reached, output truncated.");
+            }
+            if done {
+                println!();
             }
         }
+    }
+}
 
-        Response::CommandOutput { output } => println!("Command output:\n{}\n", output),
+// Progress lines overwrite each other in place, final lines (starting with [+] or [!]) are kept.
+fn print_progress(line: Option<String>) {
+    if let Some(line) = line {
+        if line.starts_with("[*]") {
+            print!("\r{}\x1b[K", line);
+        } else {
+            println!("\r{}\x1b[K", line);
+        }
+        let _ = std::io::stdout().flush();
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+use futures_util::sink::SinkExt;
+use tokio::sync::Mutex;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+use crate::transport::codec::{self, Frame};
+use crate::transport::communication::{self, Channel, WsSender};
+use crate::enums::response::Response;
+use crate::shared_state::session::Connection;
+use crate::shared_state::shared_state::SharedState;
+
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Encrypts and sends responses, and the operator's commands, to the peer. Cheap to clone, so long
+/// running jobs can keep streaming responses after the command that started them returned.
+#[derive(Clone)]
+pub struct ResponseSender {
+    connection: Option<Connection>,
+    shared_state: Arc<Mutex<SharedState>>,
+}
+
+impl ResponseSender {
+    pub fn new(channel: Channel, ws_sender: Option<WsSender>, shared_state: Arc<Mutex<SharedState>>) -> Self {
+        let connection = ws_sender.map(|ws_sender| Connection { channel, ws_sender });
+        Self { connection, shared_state }
+    }
+
+    /// Not tied to any connection, sends only over the one carrying the session at the time.
+    pub fn for_session(shared_state: Arc<Mutex<SharedState>>) -> Self {
+        Self { connection: None, shared_state }
+    }
+
+    pub async fn send(&self, response: Response) -> bool {
+        self.send_frame(Frame::Response(response)).await
+    }
+
+    /// Heartbeats go over this sender's connection. Commands and responses belong to the session
+    /// and go over whichever connection carries it now, or wait for the peer to resume it. True
+    /// when the frame was sent or kept for replay.
+    pub async fn send_frame(&self, frame: Frame) -> bool {
+        let heartbeat = matches!(frame, Frame::Ping { .. } | Frame::Pong { .. });
+        let connection = if heartbeat {
+            self.connection.clone()
+        } else {
+            self.shared_state.lock().await.session.connection().cloned()
+        };
+
+        let Some(connection) = connection else {
+            if heartbeat {
+                return false;
+            }
+            let mut shared_state = self.shared_state.lock().await;
+            shared_state.session.number(frame);
+            return shared_state.session.resumable();
+        };
+
+        // Only responses get the extra session key layer, commands travel in their own envelope.
+        let wrap = matches!(frame, Frame::Response(_));
+
+        // Numbered while holding the connection, so frames go out in the order of their numbers.
+        let mut sender = connection.ws_sender.lock().await;
+        let (serialized_response, session_key, resumable) = {
+            let mut shared_state = self.shared_state.lock().await;
+            let serialized_response = if heartbeat {
+                codec::encode(&frame)
+            } else {
+                if let Some(link) = shared_state.link.as_mut() {
+                    link.record_activity();
+                }
+                shared_state.session.number(frame)
+            };
+            (serialized_response, shared_state.session_key.clone(), shared_state.session.resumable())
+        };
+
+        let mut encrypted_response = communication::prepare_tx(serialized_response, &connection.channel);
+        if let Some(session_key) = session_key.filter(|_| wrap) {
+            encrypted_response = crate::crypto::aes::encrypt(&encrypted_response, &session_key);
+        }
+
+        if let Err(e) = communication::send_binary_data(&mut sender, encrypted_response).await {
+            warn!("Failed to send encrypted response: {}", e);
+            !heartbeat && resumable
+        } else {
+            debug!("Encrypted response sent.");
+            true
+        }
+    }
+
+    /// False once the session moved on to a newer connection than this sender's.
+    pub async fn is_current(&self) -> bool {
+        match &self.connection {
+            Some(connection) => self.shared_state.lock().await.session.is_current(&connection.ws_sender),
+            None => false,
+        }
+    }
+
+    /// The peer closed this sender's connection with a normal close, ending the session.
+    pub async fn end_session(&self) {
+        if let Some(connection) = &self.connection {
+            self.shared_state.lock().await.close_session(&connection.ws_sender);
+        }
+    }
+
+    /// Closes the connection, telling the peer why. Gives up after a while, the peer may be gone.
+    pub async fn close(&self, code: CloseCode, reason: &str) {
+        if let Some(connection) = &self.connection {
+            let frame = communication::close_frame(code, reason);
+            let close = async { connection.ws_sender.lock().await.send(Message::Close(Some(frame))).await };
+            match tokio::time::timeout(CLOSE_TIMEOUT, close).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Failed to send close frame: {}", e),
+                Err(_) => debug!("Timed out sending close frame"),
+            }
+        }
+    }
+
+    pub fn shared_state(&self) -> &Arc<Mutex<SharedState>> {
+        &self.shared_state
+    }
+}
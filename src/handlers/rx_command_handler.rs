@@ -1,93 +1,171 @@
 use std::env;
+use tracing::{debug, error, info, warn};
+use std::path::Path;
 use std::sync::Arc;
-use futures_util::stream::{SplitSink, SplitStream, StreamExt};
+use std::time::Duration;
+use futures_util::stream::StreamExt;
 use rsa::pkcs1::EncodeRsaPublicKey;
+use sha2::{Digest, Sha256};
 
 use tokio::fs;
-use tokio::net::TcpStream;
 use tokio::process::Command;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
 // use users::all_users;
-use crate::transport::communication;
+use crate::transport::codec::{self, Frame};
+use crate::transport::communication::{self, Channel, WsReceiver, WsSender};
 
-use crate::enums::command::Command as NodeCommand;
-use crate::enums::response::Response;
+use crate::enums::command::{Command as NodeCommand, WriteOptions};
+use crate::enums::response::{Response, SearchMatch};
+use crate::enums::sync::{BlockSignature, SyncReply};
+use crate::filesystem::search::{self, FindFilter};
+use crate::filesystem::sync::{self, DeltaReader};
+use crate::filesystem::tail::{TailEvent, TailFollower};
+use crate::filesystem::safe_write::{self, IncomingChunk};
+use crate::filesystem::transfer;
+use crate::forwarding::remote;
+use crate::forwarding::tunnel::{self, Outbound};
+use crate::handlers::command_sender::CommandSender;
+use crate::handlers::response_sender::ResponseSender;
 use crate::handlers::response_handler::process_response;
+use crate::audit::audit_log::AuditEvent;
 use crate::crypto::envelope::Envelope;
-use crate::shared_state::shared_state::SharedState;
+use crate::policy::capabilities::{Capability, CapabilitySet};
+use crate::policy::exec_policy::Decision;
+use crate::policy::policy::Policy;
+use crate::shared_state::forwards::Origin;
+use crate::shared_state::shared_state::{SharedState, SharedStateHandle};
+
+const SEARCH_BATCH_SIZE: usize = 64;
+const TAIL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct RxCommandHandler {
-    passphrase: String,
-    ws_sender: Option<Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>>,
-    ws_receiver: Option<Arc<Mutex<SplitStream<WebSocketStream<TcpStream>>>>>,
-    no_exec: bool,
-    no_transfer: bool,
+    channel: Channel,
+    response_sender: ResponseSender,
+    command_sender: CommandSender,
+    ws_receiver: Option<WsReceiver>,
     no_envelope: bool,
+    max_results: usize,
+    policy: Arc<Policy>,
+    peer: String,
+    capabilities: CapabilitySet,
     shared_state: Arc<Mutex<SharedState>>,
 }
 
 impl RxCommandHandler {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
-        passphrase: String,
-        ws_sender: Option<Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>>,
-        ws_receiver: Option<Arc<Mutex<SplitStream<WebSocketStream<TcpStream>>>>>,
-        no_exec: bool,
-        no_transfer: bool,
+        channel: Channel,
+        ws_sender: Option<WsSender>,
+        ws_receiver: Option<WsReceiver>,
         no_envelope: bool,
+        max_results: usize,
+        policy: Arc<Policy>,
+        peer: String,
+        capabilities: CapabilitySet,
         shared_state: Arc<Mutex<SharedState>>,
     ) -> Self {
         Self {
-            passphrase,
-            ws_sender,
+            response_sender: ResponseSender::new(channel, ws_sender, Arc::clone(&shared_state)),
+            command_sender: CommandSender::new(no_envelope, Arc::clone(&shared_state)),
+            channel,
             ws_receiver,
-            no_exec,
-            no_transfer,
             no_envelope,
+            max_results,
+            policy,
+            peer,
+            capabilities,
             shared_state,
         }
     }
 
     pub async fn handle_command(&mut self, command: NodeCommand) -> Response {
+        let mut audit = AuditEvent::new(&command, &self.peer);
+        let response = self.dispatch(command, &mut audit).await;
+        if audit.complete(&response) {
+            write_audit(&self.shared_state, audit).await;
+        }
+        response
+    }
+
+    async fn dispatch(&mut self, command: NodeCommand, audit: &mut AuditEvent) -> Response {
+        if let Some(refused) = self.admit(&command).await {
+            return refused;
+        }
+
         match command {
             NodeCommand::Echo { message } => self.echo_message(&message).await,
-            NodeCommand::Info => self.execute_with_permission(|| self.info()).await,
-            NodeCommand::Whoami => self.execute_with_permission(|| self.whoami()).await,
-            NodeCommand::Pwd => self.execute_with_permission(|| self.pwd()).await,
-            // NodeCommand::Users => self.execute_with_permission(|| self.users()).await,
-            NodeCommand::Netstat => self.execute_with_permission(|| self.netstat()).await,
-            NodeCommand::Network => self.execute_with_permission(|| self.network()).await,
-            NodeCommand::ListFiles => self.execute_with_transfer_permission(|| self.list_files()).await,
-            NodeCommand::GetFile { file_path, file_local_path } => self.execute_with_transfer_permission(|| self.download_file(&file_path, &file_local_path)).await,
-            NodeCommand::PutFile { file_path, file_up_path, data } => self.execute_with_transfer_permission(|| self.upload_file(&file_path, &file_up_path, &data)).await,
-            NodeCommand::Execute { command } => self.execute_with_permission(|| self.execute_command(&command)).await,
+            NodeCommand::Info => self.info().await,
+            NodeCommand::Whoami => self.whoami().await,
+            NodeCommand::Pwd => self.pwd().await,
+            // NodeCommand::Users => self.users().await,
+            NodeCommand::Netstat => self.netstat().await,
+            NodeCommand::Network => self.network().await,
+            NodeCommand::ListFiles => self.list_files().await,
+            NodeCommand::GetFile { file_path, transfer_id } => self.download_file(&file_path, transfer_id, audit).await,
+            NodeCommand::PutFile { file_path, file_up_path, data, transfer_id, offset, total_size, mode, options } => {
+                let chunk = IncomingChunk { transfer_id, offset, total_size, mode, data: &data };
+                self.upload_file(&file_path, &file_up_path, &chunk, &options, audit).await
+            }
+            NodeCommand::Execute { command } => self.execute_command(&command).await,
+            NodeCommand::Find { root, pattern, max_depth, min_size, max_size, newer_than_secs, limit } => {
+                let filter = FindFilter { max_depth, min_size, max_size, newer_than: newer_than_secs.map(Duration::from_secs) };
+                self.find_files(&root, &pattern, &filter, limit).await
+            }
+            NodeCommand::Grep { pattern, path, recursive, limit } => self.grep_files(&pattern, &path, recursive, limit).await,
+            NodeCommand::Tail { path, lines, follow } => self.tail_file(&path, lines, follow).await,
+            NodeCommand::Jobs => self.list_jobs().await,
+            NodeCommand::Cancel { job_id } => self.cancel_jobs(job_id).await,
+            NodeCommand::SyncManifest { request_id, root } => self.sync_reply(request_id, || {
+                // A destination that does not exist yet has an empty manifest, so it only needs to be writable.
+                let root = self.policy.sandbox.resolve_read(Path::new(&root)).or_else(|_| self.policy.sandbox.resolve_write(Path::new(&root)))?;
+                sync::build_manifest(&root).map(SyncReply::Manifest)
+            }),
+            NodeCommand::SyncSignatures { request_id, root, path, block_size } => self.sync_reply(request_id, || {
+                let file = self.policy.sandbox.resolve_read(&sync::resolve(Path::new(&root), &path)?)?;
+                sync::signatures(&file, block_size).map(SyncReply::Signatures)
+            }),
+            NodeCommand::SyncDelta { request_id, root, path, block_size, signatures } => {
+                self.send_delta(request_id, &root, &path, block_size, signatures).await
+            }
+            NodeCommand::SyncApply { request_id, root, path, change } => self.sync_reply(request_id, || {
+                let root = self.policy.sandbox.resolve_write(Path::new(&root))?;
+                self.policy.sandbox.resolve_write(&sync::resolve(&root, &path)?)?;
+                sync::apply_change(&root, &path, &change).map(|_| SyncReply::Applied)
+            }),
+            NodeCommand::ForwardListen { forward_id, port } => self.forward_listen(forward_id, port).await,
+            NodeCommand::ForwardConnect { .. }
+            | NodeCommand::ForwardData { .. }
+            | NodeCommand::ForwardClose { .. }
+            | NodeCommand::ForwardUnlisten { .. } => unreachable!("handled in respond"),
             NodeCommand::Handshake => self.handle_handshake().await,
         }
     }
 
-    async fn execute_with_permission<F, Fut>(&self, func: F) -> Response
-    where
-        F: Fn() -> Fut,
-        Fut: std::future::Future<Output = Response>,
-    {
-        if self.no_exec {
-            self.execution_disabled_message()
-        } else {
-            func().await
+    // The refusal for a command this node does not run now, None if it may run.
+    async fn admit(&self, command: &NodeCommand) -> Option<Response> {
+        if let Some(capability) = Capability::required_for(command) {
+            if !self.capabilities.contains(&capability) {
+                return Some(self.capability_denied(command, capability));
+            }
+        }
+        // Chunks of an upload already under way are still written, it counts as draining.
+        let continues_upload = matches!(command, NodeCommand::PutFile { offset, .. } if *offset > 0);
+        if !continues_upload && self.shared_state.lock().await.draining {
+            return Some(refusal(command, "The node is shutting down.".to_string(), || Response::Error {
+                content: "The node is shutting down.".to_string(),
+            }));
         }
+        None
     }
 
-    async fn execute_with_transfer_permission<F, Fut>(&self, func: F) -> Response
-    where
-        F: Fn() -> Fut,
-        Fut: std::future::Future<Output = Response>,
-    {
-        if self.no_transfer {
-            self.transfer_disabled_message()
-        } else {
-            func().await
-        }
+    fn capability_denied(&self, command: &NodeCommand, capability: Capability) -> Response {
+        warn!("Refused a command from {}, it is not granted {}.", self.peer, capability);
+        let reason = format!("{} is not granted to this node", capability);
+        refusal(command, reason.clone(), || Response::PolicyDenied { operation: capability.to_string(), reason })
     }
 
     async fn echo_message(&self, message: &str) -> Response {
@@ -96,7 +174,7 @@ impl RxCommandHandler {
     }
 
     async fn info(&self) -> Response {
-        Response::Message { content: "NOT IMPLEMENTED".to_string() }
+        Response::Error { content: "NOT IMPLEMENTED".to_string() }
     }
 
     async fn pwd(&self) -> Response {
@@ -120,16 +198,21 @@ impl RxCommandHandler {
     // }
 
     async fn netstat(&self) -> Response {
-        Response::Message { content: "NOT IMPLEMENTED".to_string() }
+        Response::Error { content: "NOT IMPLEMENTED".to_string() }
     }
 
     async fn network(&self) -> Response {
-        Response::Message { content: "NOT IMPLEMENTED".to_string() }
+        Response::Error { content: "NOT IMPLEMENTED".to_string() }
     }
 
     async fn list_files(&self) -> Response {
+        let directory = match self.policy.sandbox.resolve_read(Path::new(".")) {
+            Ok(directory) => directory,
+            Err(e) => return Response::Error { content: e },
+        };
+
         let mut file_list = vec![];
-        match fs::read_dir(".").await {
+        match fs::read_dir(directory).await {
             Ok(mut entries) => {
                 while let Ok(Some(entry)) = entries.next_entry().await {
                     if let Some(file_name) = entry.file_name().to_str() {
@@ -137,27 +220,71 @@ impl RxCommandHandler {
                     }
                 }
             }
-            Err(e) => eprintln!("Failed to read directory: {}", e),
+            Err(e) => warn!("Failed to read directory: {}", e),
         }
         Response::FileList { files: file_list }
     }
 
-    async fn download_file(&self, file_path: &str, file_local_path: &str) -> Response {
-        match fs::read(file_path).await {
-            Ok(file_data) => Response::FileData { file_path: file_local_path.to_string(), data: file_data },
+    async fn download_file(&self, file_path: &str, transfer_id: u32, audit: &mut AuditEvent) -> Response {
+        let transfer_failed = |e: String| Response::TransferStatus { transfer_id, transferred: 0, error: Some(e) };
+
+        let path = match self.policy.sandbox.resolve_read(Path::new(file_path)) {
+            Ok(path) => path,
+            Err(e) => return transfer_failed(e),
+        };
+        let mut file = match fs::File::open(path).await {
+            Ok(file) => file,
             Err(e) => {
-                eprintln!("Failed to read file {}: {}", file_path, e);
-                Response::Message { content: format!("Failed to read file: {}", e) }
+                warn!("Failed to read file {}: {}", file_path, e);
+                return transfer_failed(format!("Failed to read file: {}", e));
+            }
+        };
+        let metadata = file.metadata().await.ok();
+        let total_size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        let mode = metadata.as_ref().and_then(safe_write::file_mode);
+
+        // Every chunk but the last is sent right away, the last one is the command's response.
+        let mut offset = 0;
+        let mut hasher = Sha256::new();
+        loop {
+            let data = match transfer::read_chunk(&mut file).await {
+                Ok(data) => data,
+                Err(e) => return transfer_failed(format!("Failed to read file: {}", e)),
+            };
+            hasher.update(&data);
+            let length = data.len() as u64;
+            let chunk = Response::FileData { file_path: file_path.to_string(), data, transfer_id, offset, total_size, mode };
+            offset += length;
+
+            if length == 0 || offset >= total_size {
+                audit.set_sha256(&hasher.finalize());
+                return chunk;
+            }
+            if !self.send_response(chunk).await {
+                return transfer_failed("Connection lost".to_string());
             }
         }
     }
 
-    async fn upload_file(&self, file_path: &str, file_up_path: &str, data: &[u8]) -> Response {
-        match fs::write(file_up_path, data).await {
-            Ok(_) => Response::Message { content: format!("File {} uploaded successfully.", file_path) },
+    async fn upload_file(&self, file_path: &str, file_up_path: &str, chunk: &IncomingChunk<'_>, options: &WriteOptions, audit: &mut AuditEvent) -> Response {
+        let transfer_id = chunk.transfer_id;
+        let result = match self.policy.sandbox.resolve_write(Path::new(file_up_path)) {
+            Ok(target) => safe_write::receive_chunk(&target, chunk, options).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(written) => {
+                if let Some(written) = written {
+                    info!("File {} uploaded successfully to {}.", file_path, written.display());
+                    if let Ok(hash) = tokio::task::block_in_place(|| sync::hash_file(&written)) {
+                        audit.set_sha256(&hash);
+                    }
+                }
+                Response::TransferStatus { transfer_id, transferred: chunk.offset + chunk.data.len() as u64, error: None }
+            }
             Err(e) => {
-                eprintln!("Failed to write file {}: {}", file_up_path, e);
-                Response::Message { content: format!("Failed to write file: {}", e) }
+                warn!("Failed to write file {}: {}", file_up_path, e);
+                Response::TransferStatus { transfer_id, transferred: chunk.offset, error: Some(e) }
             }
         }
     }
@@ -166,23 +293,190 @@ impl RxCommandHandler {
         let mut parts = command.split_whitespace();
         let executable = match parts.next() {
             Some(exe) => exe,
-            None => return Response::Message { content: "Empty command".to_string() },
+            None => return Response::Error { content: "Empty command".to_string() },
         };
         let args: Vec<&str> = parts.collect();
-    
-        let cmd_result = Command::new(executable)
-            .args(&args)
-            .output()
-            .await;
-    
-        match cmd_result {
-            Ok(output) => {
-                let result = String::from_utf8_lossy(&output.stdout);
-                Response::CommandOutput { output: result.to_string() }
+
+        if let Err(reason) = self.authorize_execution(command, executable, &args).await {
+            warn!("Refused to execute {}: {}", command, reason);
+            return Response::PolicyDenied { operation: format!("EXEC {}", command), reason };
+        }
+
+        run_program(executable, &args).await
+    }
+
+    async fn authorize_execution(&self, command: &str, executable: &str, args: &[&str]) -> Result<(), String> {
+        match self.policy.exec.check(executable, args) {
+            Decision::Allow => Ok(()),
+            Decision::Deny(reason) => Err(reason),
+            Decision::Confirm => confirm(&self.shared_state, &format!("Peer wants to execute: {}", command)).await,
+        }
+    }
+
+    // An EXEC the operator of this node has to confirm first, which can take a while.
+    async fn awaits_confirmation(&self, command: &NodeCommand, line: &str) -> bool {
+        let mut parts = line.split_whitespace();
+        let Some(executable) = parts.next() else { return false };
+        let args: Vec<&str> = parts.collect();
+        matches!(self.policy.exec.check(executable, &args), Decision::Confirm) && self.admit(command).await.is_none()
+    }
+
+    async fn find_files(&self, root: &str, pattern: &str, filter: &FindFilter, limit: Option<usize>) -> Response {
+        let matcher = match search::compile_glob(pattern) {
+            Ok(matcher) => matcher,
+            Err(e) => return Response::Error { content: e },
+        };
+
+        let root = match self.policy.sandbox.resolve_read(Path::new(root)) {
+            Ok(root) => root,
+            Err(e) => return Response::Error { content: e },
+        };
+        let filter = *filter;
+        let max_results = self.result_limit(limit);
+        let (tx, rx) = mpsc::channel(SEARCH_BATCH_SIZE);
+        let task = tokio::task::spawn_blocking(move || search::find(&root, &matcher, &filter, &tx, max_results));
+
+        self.stream_search_results(rx, task).await
+    }
+
+    async fn grep_files(&self, pattern: &str, path: &str, recursive: bool, limit: Option<usize>) -> Response {
+        let regex = match search::compile_regex(pattern) {
+            Ok(regex) => regex,
+            Err(e) => return Response::Error { content: e },
+        };
+
+        let path = match self.policy.sandbox.resolve_read(Path::new(path)) {
+            Ok(path) => path,
+            Err(e) => return Response::Error { content: e },
+        };
+        let max_results = self.result_limit(limit);
+        let (tx, rx) = mpsc::channel(SEARCH_BATCH_SIZE);
+        let task = tokio::task::spawn_blocking(move || search::grep(&path, &regex, recursive, &tx, max_results));
+
+        self.stream_search_results(rx, task).await
+    }
+
+    fn result_limit(&self, requested: Option<usize>) -> usize {
+        requested.map_or(self.max_results, |requested| requested.min(self.max_results))
+    }
+
+    async fn stream_search_results(
+        &self,
+        mut rx: mpsc::Receiver<SearchMatch>,
+        task: JoinHandle<Result<bool, String>>,
+    ) -> Response {
+        let mut matches = Vec::with_capacity(SEARCH_BATCH_SIZE);
+        while let Some(search_match) = rx.recv().await {
+            matches.push(search_match);
+            if matches.len() >= SEARCH_BATCH_SIZE {
+                let batch = std::mem::take(&mut matches);
+                self.send_response(Response::SearchResults { matches: batch, done: false, truncated: false }).await;
+            }
+        }
+
+        match task.await {
+            Ok(Ok(truncated)) => Response::SearchResults { matches, done: true, truncated },
+            Ok(Err(e)) => Response::Error { content: format!("Search failed: {}", e) },
+            Err(e) => {
+                error!("Search task failed: {}", e);
+                Response::Error { content: format!("Search failed: {}", e) }
             }
+        }
+    }
+
+    async fn tail_file(&self, path: &str, lines: usize, follow: bool) -> Response {
+        let resolved = match self.policy.sandbox.resolve_read(Path::new(path)) {
+            Ok(resolved) => resolved,
+            Err(e) => return Response::Error { content: e },
+        };
+        let (follower, content) = match TailFollower::open(Path::new(path), &resolved, lines).await {
+            Ok(opened) => opened,
             Err(e) => {
-                eprintln!("Failed to execute command: {}", e);
-                Response::Message { content: format!("Failed to execute command: {}\n", e) }
+                warn!("Failed to open file {}: {}", path, e);
+                return Response::Error { content: format!("Failed to open file: {}", e) };
+            }
+        };
+        let content = String::from_utf8_lossy(&content).to_string();
+
+        if !follow {
+            return Response::TailData { path: path.to_string(), content, job_id: None };
+        }
+
+        let job_id = {
+            let mut shared_state = self.shared_state.lock().await;
+            let job_id = shared_state.jobs.reserve_id();
+            let task = tokio::spawn(follow_file(job_id, path.to_string(), follower, Arc::clone(&self.policy), self.response_sender.clone()));
+            shared_state.jobs.insert(job_id, format!("TAIL -f {}", path), task.abort_handle());
+            job_id
+        };
+
+        self.send_response(Response::TailData { path: path.to_string(), content, job_id: Some(job_id) }).await;
+        Response::Message { content: format!("[*] Following {} (job {}). Use CANCEL {} to stop.", path, job_id, job_id) }
+    }
+
+    async fn list_jobs(&self) -> Response {
+        let shared_state = self.shared_state.lock().await;
+        let jobs = shared_state.jobs.list();
+        if jobs.is_empty() {
+            Response::Message { content: "No running jobs.".to_string() }
+        } else {
+            Response::Message { content: jobs.join("\n") }
+        }
+    }
+
+    async fn cancel_jobs(&self, job_id: Option<u32>) -> Response {
+        let mut shared_state = self.shared_state.lock().await;
+        match job_id {
+            Some(job_id) if shared_state.jobs.cancel(job_id) => Response::Message { content: format!("[+] Job {} cancelled.", job_id) },
+            Some(job_id) => Response::Error { content: format!("No such job: {}", job_id) },
+            None => Response::Message { content: format!("[+] Cancelled {} job(s).", shared_state.jobs.cancel_all()) },
+        }
+    }
+
+    fn sync_reply<F>(&self, request_id: u32, operation: F) -> Response
+    where
+        F: FnOnce() -> Result<SyncReply, String>,
+    {
+        let reply = tokio::task::block_in_place(operation).unwrap_or_else(SyncReply::Error);
+        Response::Sync { request_id, reply }
+    }
+
+    // Every part of the delta but the last is sent right away, the last one is the command's response.
+    async fn send_delta(&self, request_id: u32, root: &str, path: &str, block_size: u32, signatures: Vec<BlockSignature>) -> Response {
+        let opened = tokio::task::block_in_place(|| {
+            let file = self.policy.sandbox.resolve_read(&sync::resolve(Path::new(root), path)?)?;
+            DeltaReader::open(&file, signatures, block_size)
+        });
+        let mut reader = match opened {
+            Ok(reader) => reader,
+            Err(e) => return Response::Sync { request_id, reply: SyncReply::Error(e) },
+        };
+
+        loop {
+            let delta = match tokio::task::block_in_place(|| reader.next_part()) {
+                Ok(delta) => delta,
+                Err(e) => return Response::Sync { request_id, reply: SyncReply::Error(e) },
+            };
+            let last = delta.end.is_some();
+            let part = Response::Sync { request_id, reply: SyncReply::Delta(delta) };
+            if last {
+                return part;
+            }
+            if !self.send_response(part).await {
+                return Response::Sync { request_id, reply: SyncReply::Error("Connection lost".to_string()) };
+            }
+        }
+    }
+
+    async fn forward_listen(&self, forward_id: u32, port: u16) -> Response {
+        match remote::listen(forward_id, port, &self.policy.forwarding, self.response_sender.clone()).await {
+            Ok(address) => {
+                info!("Listening on {} for {}, connections are forwarded to it", address, self.peer);
+                Response::ForwardListening { forward_id, address: address.to_string(), error: None }
+            }
+            Err(e) => {
+                warn!("Refused to listen for {}: {}", self.peer, e);
+                Response::ForwardListening { forward_id, address: String::new(), error: Some(e) }
             }
         }
     }
@@ -195,102 +489,190 @@ impl RxCommandHandler {
         Response::Handshake { public_key: public_key_pem.as_bytes().to_vec() }
     }
 
-    async fn send_response(&self, response: Response) {
-        if let Some(ws_sender) = &self.ws_sender {
-            let serialized_response = serde_json::to_vec(&response).expect("Failed to serialize response");
-            let encrypted_response = self.encrypt_response(serialized_response).await;
-            let mut sender = ws_sender.lock().await;
-            if let Err(e) = communication::send_binary_data(&mut sender, encrypted_response).await {
-                eprintln!("Failed to send encrypted response: {}", e);
-            } else {
-                println!("[*] Encrypted response sent.");
+    async fn respond(&mut self, command: NodeCommand) {
+        // Data of forwarded connections and stopping a forward are neither answered nor audited.
+        match command {
+            NodeCommand::ForwardData { forward_id, stream_id, data } => {
+                return tunnel::deliver(&self.shared_state, Origin::Peer, forward_id, stream_id, data).await;
+            }
+            NodeCommand::ForwardClose { forward_id, stream_id } => {
+                return self.shared_state.lock().await.forwards.close_stream(Origin::Peer, forward_id, stream_id);
+            }
+            NodeCommand::ForwardUnlisten { forward_id } => {
+                if self.shared_state.lock().await.forwards.stop_peer(forward_id) {
+                    info!("Stopped listening for forward {} of {}", forward_id, self.peer);
+                }
+                return;
+            }
+            _ => {}
+        }
+        // Connecting may take a while, the stream is opened first so data the peer sends right
+        // behind the command is queued meanwhile.
+        if let NodeCommand::ForwardConnect { forward_id, stream_id, host, port } = &command {
+            if self.admit(&command).await.is_none() {
+                let outbound = Outbound::Responses(self.response_sender.clone());
+                let queued = tunnel::open(*forward_id, *stream_id, &outbound).await;
+                let audit = AuditEvent::new(&command, &self.peer);
+                let target = (host.clone(), *port);
+                tokio::spawn(forward_connect(*forward_id, *stream_id, target, self.peer.clone(), queued, audit, self.response_sender.clone()));
+                return;
+            }
+        }
+        // The link keeps running while the operator of this node decides, the answer is sent once known.
+        if let NodeCommand::Execute { command: line } = &command {
+            if self.awaits_confirmation(&command, line).await {
+                let audit = AuditEvent::new(&command, &self.peer);
+                tokio::spawn(execute_confirmed(line.clone(), audit, self.response_sender.clone()));
+                return;
             }
         }
-    }
 
-    async fn encrypt_response(&self, serialized_response: Vec<u8>) -> Vec<u8> {
-        let mut communication_data = communication::prepare_tx(serialized_response, &self.passphrase);
-        let session_key = {
-            let shared_state = self.shared_state.lock().await;
-            shared_state.session_key.clone()
+        let upload = match &command {
+            NodeCommand::PutFile { transfer_id, offset, total_size, data, .. } => Some((*transfer_id, offset + data.len() as u64 >= *total_size)),
+            _ => None,
         };
+        let response = self.handle_command(command).await;
+        let upload = upload.map(|(transfer_id, last)| (transfer_id, last || !matches!(response, Response::TransferStatus { error: None, .. })));
+        self.send_response(response).await;
 
-        if let Some(session_key) = session_key {
-            communication_data = crate::crypto::aes::encrypt(&communication_data, &session_key);
+        // An upload is only done once the peer was told, a shutdown waits for that.
+        if let Some((transfer_id, finished)) = upload {
+            self.shared_state.lock().await.transfers.receive(transfer_id, finished);
         }
+    }
 
-        communication_data
+    async fn send_response(&self, response: Response) -> bool {
+        self.response_sender.send(response).await
     }
 
-    async fn decrypt_envelope(&mut self, envelope: Envelope) -> NodeCommand {
+    async fn decrypt_envelope(&mut self, envelope: Envelope) -> Result<NodeCommand, String> {
         let decrypted_command = {
             let mut shared_state = self.shared_state.lock().await;
-            let private_key = shared_state.local_private_key.as_ref().expect("Private key not initialized");
-            let (session_key, decrypted_command) = Envelope::decrypt_envelope(private_key, envelope);
+            let private_key = shared_state.local_private_key.as_ref()
+                .ok_or_else(|| "Envelope received before the handshake".to_string())?;
+            let (session_key, decrypted_command) = Envelope::decrypt_envelope(private_key, envelope)?;
             shared_state.session_key = Some(session_key);
             decrypted_command
         };
-        serde_json::from_slice(&decrypted_command).expect("Failed to deserialize command")
+        codec::decode(&decrypted_command)
     }
 
     pub async fn handle_rx(&mut self) {
+        // Lets the operator know up front which commands this node will refuse.
+        let capabilities = self.capabilities.iter().copied().collect();
+        self.send_response(Response::Capabilities { capabilities }).await;
+
         while let Some(message) = self.get_next_message().await {
-            println!("\n[+] Received message\n");
+            debug!("Received message");
             match message {
-                Ok(Message::Binary(data)) => {
-                    let decrypted_communications = self.decrypt_incoming_message(&data).await;
-                    self.process_decrypted_data(decrypted_communications).await;
-                }
+                Ok(Message::Binary(data)) => match self.decrypt_incoming_message(&data).await {
+                    Ok(decrypted_communications) => self.process_decrypted_data(decrypted_communications).await,
+                    Err(e) => {
+                        // The peer is broken or hostile, nothing it sends after this can be trusted.
+                        warn!("Protocol error, closing the connection: {}", e);
+                        self.response_sender.close(CloseCode::Protocol, &e).await;
+                        break;
+                    }
+                },
                 Ok(Message::Text(text)) => {
-                    eprintln!("Unexpected text message: {}", text);
+                    warn!("Unexpected text message: {}", text);
+                }
+                Ok(Message::Close(frame)) => {
+                    match &frame {
+                        Some(frame) if !frame.reason.is_empty() => info!("Peer closed the connection: {}", frame.reason),
+                        _ => info!("Peer closed the connection"),
+                    }
+                    // Any other close leaves the session for the peer to resume.
+                    if frame.is_some_and(|frame| frame.code == CloseCode::Normal) {
+                        self.response_sender.end_session().await;
+                    }
+                    break;
                 }
                 Ok(_) => {
-                    eprintln!("Received unexpected non-binary message");
+                    debug!("Received unexpected non-binary message");
                 }
                 Err(e) => {
-                    eprintln!("Error receiving WebSocket message: {}", e);
+                    warn!("Error receiving WebSocket message: {}", e);
                     break;
                 }
             }
         }
     }
 
-    async fn decrypt_incoming_message(&self, data: &[u8]) -> Vec<u8> {
+    async fn decrypt_incoming_message(&self, data: &[u8]) -> Result<Vec<u8>, String> {
         let shared_state = self.shared_state.lock().await;
         let decrypted_data = data.to_vec();
         if self.no_envelope {
-            communication::prepare_rx(decrypted_data, &self.passphrase)
+            communication::prepare_rx(decrypted_data, &self.channel)
         } else {
             if let Some(session_key) = &shared_state.session_key {
                 if let Ok(decrypted) = crate::crypto::aes::decrypt(&decrypted_data, session_key) {
-                    return communication::prepare_rx(decrypted, &self.passphrase);
+                    return communication::prepare_rx(decrypted, &self.channel);
                 }
             }
-            communication::prepare_rx(decrypted_data, &self.passphrase)
+            communication::prepare_rx(decrypted_data, &self.channel)
         }
     }
 
     async fn process_decrypted_data(&mut self, decrypted_data: Vec<u8>) {
-        if let Ok(envelope) = serde_json::from_slice::<Envelope>(&decrypted_data) {
-            let command = self.decrypt_envelope(envelope).await;
-            let response = self.handle_command(command).await;
-            self.send_response(response).await;
-        } else if let Ok(command) = serde_json::from_slice::<NodeCommand>(&decrypted_data) {
-            if self.no_envelope {
-                let response = self.handle_command(command).await;
-                self.send_response(response).await;
+        let frame = match codec::decode::<Frame>(&decrypted_data) {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Received unexpected message format: {}", e);
+                return;
+            }
+        };
+
+        let frame = {
+            let mut shared_state = self.shared_state.lock().await;
+            if let Some(link) = shared_state.link.as_mut() {
+                link.record_received(matches!(frame, Frame::Ping { .. } | Frame::Pong { .. }));
+            }
+            match frame {
+                Frame::Numbered { seq, frame } => {
+                    if !shared_state.session.receive(seq) {
+                        debug!("Dropped frame {}, it arrived before the connection was resumed", seq);
+                        return;
+                    }
+                    *frame
+                }
+                frame => frame,
+            }
+        };
+
+        match frame {
+            Frame::Envelope(envelope) => match self.decrypt_envelope(envelope).await {
+                Ok(command) => {
+                    self.respond(command).await;
+                }
+                Err(e) => warn!("Received an envelope with an unreadable command: {}", e),
+            },
+            Frame::Command(command) => if self.no_envelope {
+                self.respond(command).await;
             } else {
                 if let NodeCommand::Handshake = command {
-                    let response = self.handle_command(command).await;
-                    self.send_response(response).await;
+                    self.respond(command).await;
                 } else {
-                    eprintln!("Received unexpected command during handshake.");
+                    warn!("Received unexpected command during handshake.");
                 }
+            },
+            Frame::Response(response) => process_response(response, &self.shared_state, &self.command_sender).await,
+            Frame::Ping { nonce, received } => {
+                let received_here = {
+                    let mut shared_state = self.shared_state.lock().await;
+                    shared_state.session.acknowledge(received);
+                    shared_state.session.received()
+                };
+                self.response_sender.send_frame(Frame::Pong { nonce, received: received_here }).await;
             }
-        } else if let Ok(response) = serde_json::from_slice::<Response>(&decrypted_data) {
-            process_response(response, &self.shared_state).await;
-        } else {
-            eprintln!("Received unexpected message format.");
+            Frame::Pong { nonce, received } => {
+                let mut shared_state = self.shared_state.lock().await;
+                shared_state.session.acknowledge(received);
+                if let Some(link) = shared_state.link.as_mut() {
+                    link.record_pong(nonce);
+                }
+            }
+            Frame::Numbered { seq, .. } => warn!("Received frame {} numbered twice", seq),
         }
     }
 
@@ -302,14 +684,139 @@ impl RxCommandHandler {
             None
         }
     }
+}
 
-    fn execution_disabled_message(&self) -> Response {
-        println!("Execution of commands is disabled (--no-exec flag).");
-        Response::Message { content: format!("Peer has disabled executing commands.\n") }
+// Sync requests, transfers and forwards are awaited by the operator, so refusals are sent as their
+// failed reply rather than a plain message.
+fn refusal(command: &NodeCommand, reason: String, otherwise: impl FnOnce() -> Response) -> Response {
+    match command {
+        NodeCommand::GetFile { transfer_id, .. } => Response::TransferStatus { transfer_id: *transfer_id, transferred: 0, error: Some(reason) },
+        NodeCommand::PutFile { transfer_id, offset, .. } => Response::TransferStatus { transfer_id: *transfer_id, transferred: *offset, error: Some(reason) },
+        NodeCommand::SyncManifest { request_id, .. }
+        | NodeCommand::SyncSignatures { request_id, .. }
+        | NodeCommand::SyncDelta { request_id, .. }
+        | NodeCommand::SyncApply { request_id, .. } => Response::Sync { request_id: *request_id, reply: SyncReply::Error(reason) },
+        NodeCommand::ForwardConnect { forward_id, stream_id, .. } => Response::ForwardConnected { forward_id: *forward_id, stream_id: *stream_id, error: Some(reason) },
+        NodeCommand::ForwardListen { forward_id, .. } => Response::ForwardListening { forward_id: *forward_id, address: String::new(), error: Some(reason) },
+        _ => otherwise(),
     }
+}
+
+async fn execute_confirmed(command: String, mut audit: AuditEvent, response_sender: ResponseSender) {
+    let shared_state = response_sender.shared_state().clone();
+    let mut parts = command.split_whitespace();
+    let executable = parts.next().unwrap_or_default();
+    let args: Vec<&str> = parts.collect();
 
-    fn transfer_disabled_message(&self) -> Response {
-        println!("Transfer is disallowed (--no-transfer flag).");
-        Response::Message { content: format!("Transfer is disallowed (--no-transfer flag).\n") }
+    let response = match confirm(&shared_state, &format!("Peer wants to execute: {}", command)).await {
+        Ok(()) => run_program(executable, &args).await,
+        Err(reason) => {
+            warn!("Refused to execute {}: {}", command, reason);
+            Response::PolicyDenied { operation: format!("EXEC {}", command), reason }
+        }
+    };
+    if audit.complete(&response) {
+        write_audit(&shared_state, audit).await;
+    }
+    response_sender.send(response).await;
+}
+
+async fn forward_connect(
+    forward_id: u32,
+    stream_id: u32,
+    (host, port): (String, u16),
+    peer: String,
+    queued: mpsc::Receiver<Vec<u8>>,
+    mut audit: AuditEvent,
+    response_sender: ResponseSender,
+) {
+    let shared_state = response_sender.shared_state().clone();
+    let error = match tunnel::connect(&host, port).await {
+        Ok(socket) => {
+            info!("Forwarding a connection from {} to {}:{}", peer, host, port);
+            tunnel::attach(socket, queued, forward_id, stream_id, Outbound::Responses(response_sender.clone())).await;
+            None
+        }
+        Err(e) => {
+            warn!("{}", e);
+            shared_state.lock().await.forwards.abort_stream(Origin::Peer, forward_id, stream_id);
+            Some(e)
+        }
+    };
+    let response = Response::ForwardConnected { forward_id, stream_id, error };
+    if audit.complete(&response) {
+        write_audit(&shared_state, audit).await;
+    }
+    response_sender.send(response).await;
+}
+
+// Asks whoever is at this node's terminal, the answer is read by the CLI handler.
+async fn confirm(shared_state: &SharedStateHandle, question: &str) -> Result<(), String> {
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut shared_state = shared_state.lock().await;
+        if shared_state.pending_confirmation.is_some() {
+            return Err("another request is waiting for confirmation".to_string());
+        }
+        shared_state.pending_confirmation = Some(tx);
+    }
+
+    println!("\n[?] {}\n[?] Allow? Type yes or no ({}s)", question, CONFIRMATION_TIMEOUT.as_secs());
+    match tokio::time::timeout(CONFIRMATION_TIMEOUT, rx).await {
+        Ok(Ok(true)) => Ok(()),
+        Ok(Ok(false)) => Err("rejected by the operator of the node".to_string()),
+        _ => {
+            shared_state.lock().await.pending_confirmation = None;
+            Err("not confirmed in time".to_string())
+        }
     }
 }
+
+async fn run_program(executable: &str, args: &[&str]) -> Response {
+    match Command::new(executable).args(args).output().await {
+        Ok(output) => {
+            let result = String::from_utf8_lossy(&output.stdout);
+            Response::CommandOutput { output: result.to_string(), exit_code: output.status.code() }
+        }
+        Err(e) => {
+            warn!("Failed to execute command: {}", e);
+            Response::Error { content: format!("Failed to execute command: {}", e) }
+        }
+    }
+}
+
+async fn write_audit(shared_state: &SharedStateHandle, audit: AuditEvent) {
+    let mut shared_state = shared_state.lock().await;
+    if let Some(audit_log) = shared_state.audit_log.as_mut() {
+        if let Err(e) = audit_log.append(audit) {
+            error!("{}", e);
+        }
+    }
+}
+
+async fn follow_file(job_id: u32, path: String, mut follower: TailFollower, policy: Arc<Policy>, response_sender: ResponseSender) {
+    loop {
+        tokio::time::sleep(TAIL_POLL_INTERVAL).await;
+
+        let response = match follower.poll(&policy.sandbox).await {
+            Ok(events) => events.into_iter().map(|event| match event {
+                TailEvent::Data(data) => String::from_utf8_lossy(&data).to_string(),
+                TailEvent::Truncated => format!("\n[!] {}: file truncated\n", path),
+                TailEvent::Rotated => format!("\n[!] {}: file rotated, following new file\n", path),
+            }).collect::<String>(),
+            Err(e) => {
+                response_sender.send(Response::Error { content: format!("TAIL job {} stopped: {}", job_id, e) }).await;
+                break;
+            }
+        };
+
+        if !response.is_empty() {
+            let tail_data = Response::TailData { path: path.clone(), content: response, job_id: Some(job_id) };
+            if !response_sender.send(tail_data).await {
+                break;
+            }
+        }
+    }
+
+    response_sender.shared_state().lock().await.jobs.remove(job_id);
+}
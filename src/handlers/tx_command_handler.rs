@@ -1,53 +1,113 @@
+use std::path::{Path, PathBuf};
+use tracing::debug;
 use std::sync::Arc;
-use futures_util::stream::SplitSink;
+use std::time::Duration;
 use tokio::fs;
-use tokio::net::TcpStream;
-use tokio::sync::Mutex;
-use tokio_tungstenite::tungstenite::Message;
-use tokio_tungstenite::WebSocketStream;
-use crate::transport::communication;
-use crate::enums::command::Command as NodeCommand;
-use crate::crypto::envelope::Envelope;
+use tokio::sync::{mpsc, oneshot, Mutex};
+use crate::transport::codec::Frame;
+use crate::handlers::command_sender::CommandSender;
+use crate::enums::command::{Command as NodeCommand, ExistingFilePolicy, WriteOptions};
+use crate::enums::response::Response;
+use crate::enums::sync::{DeltaOp, FileDelta, SyncChange, SyncReply};
+use crate::filesystem::sync::{self, DeltaReader, DELTA_THRESHOLD};
+use crate::filesystem::safe_write;
+use crate::filesystem::transfer;
+use crate::forwarding::{local, tunnel};
+use crate::shared_state::transfers::Direction;
+use crate::policy::capabilities::{Capability, CapabilitySet};
 use indoc::indoc;
 use crate::shared_state::shared_state::SharedState;
-use rsa::RsaPublicKey;
+
+const DEFAULT_TAIL_LINES: usize = 10;
+const SYNC_REPLY_TIMEOUT: Duration = Duration::from_secs(120);
+// Parts of a pulled delta waiting to be written, the receive loop holds off while it is full.
+const SYNC_REPLY_QUEUE: usize = 4;
+const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct SyncStats {
+    files: usize,
+    literal_bytes: u64,
+    matched_bytes: u64,
+    directories: usize,
+    deleted: usize,
+    unchanged: usize,
+    failed: usize,
+}
+
+impl SyncStats {
+    fn record_delta(&mut self, delta: &FileDelta) {
+        for op in &delta.ops {
+            match op {
+                DeltaOp::Copy { .. } => self.matched_bytes += delta.block_size as u64,
+                DeltaOp::Data { bytes } => self.literal_bytes += bytes.len() as u64,
+            }
+        }
+    }
+
+    fn record_failure(&mut self, path: &str, error: &str) {
+        eprintln!("[!] {}: {}", path, error);
+        self.failed += 1;
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} file(s) transferred ({} bytes sent, ~{} bytes reused), {} director(ies) created, {} deleted, {} unchanged, {} failed",
+            self.files, self.literal_bytes, self.matched_bytes, self.directories, self.deleted, self.unchanged, self.failed
+        )
+    }
+}
 
 pub struct TxCommandHandler {
-    passphrase: String,
-    ws_sender: Option<Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>>,
-    connection_active: Arc<Mutex<bool>>,
+    command_sender: CommandSender,
     no_envelope: bool,
     shared_state: Arc<Mutex<SharedState>>,
 }
 
 impl TxCommandHandler {
-    pub fn new(
-        passphrase: String,
-        ws_sender: Option<Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>>,
-        no_envelope: bool,
-        shared_state: Arc<Mutex<SharedState>>,
-    ) -> Self {
-        Self { 
-            passphrase, 
-            ws_sender, 
-            connection_active: Arc::new(Mutex::new(true)),
+    /// One per node, commands go over whichever connection carries the session.
+    pub fn new(no_envelope: bool, shared_state: Arc<Mutex<SharedState>>) -> Self {
+        Self {
+            command_sender: CommandSender::new(no_envelope, Arc::clone(&shared_state)),
             no_envelope,
             shared_state,
         }
     }
 
-    pub async fn is_connection_active(&self) -> bool {
-        *self.connection_active.lock().await
-    }
-
     pub async fn handle_command(&mut self, command: &str) {
         let trimmed_command = command.trim();
 
+        // Only a spelled out answer settles a pending confirmation, anything else is a command as usual.
+        // `n` would be taken for NETWORK otherwise.
+        let answer = match trimmed_command.to_lowercase().as_str() {
+            "yes" => Some(true),
+            "no" => Some(false),
+            _ => None,
+        };
+        if let Some(approved) = answer {
+            let confirmation = self.shared_state.lock().await.pending_confirmation.take();
+            if let Some(confirmation) = confirmation {
+                let _ = confirmation.send(approved);
+                return;
+            }
+        }
+
         if trimmed_command.is_empty() {
             println!("Empty command received, nothing to execute.");
             return;
         }
 
+        // Answered locally, also while the handshake is still pending.
+        if trimmed_command.eq_ignore_ascii_case("STATUS") || trimmed_command.eq_ignore_ascii_case("LINK") {
+            return self.print_status().await;
+        }
+
+        // Commands typed while a dropped session can still be resumed are sent once it is.
+        if !self.shared_state.lock().await.session.resumable() {
+            println!("Not connected.\n");
+            return;
+        }
+
         if !self.no_envelope && !self.shared_state_ready().await {
             println!("[!] Session key or public key not available. Initiating handshake...");
             self.send_handshake().await;
@@ -55,12 +115,54 @@ impl TxCommandHandler {
             return;
         }
 
+        let (keyword, args) = trimmed_command.split_once(char::is_whitespace).unwrap_or((trimmed_command, ""));
+        let peer_capabilities = self.shared_state.lock().await.peer_capabilities.clone();
+        if let Some(capability) = peer_capabilities.and_then(|allowed| missing_capability(keyword, args, &allowed)) {
+            eprintln!("[!] The connected node does not allow {}, command not sent.", capability);
+            return;
+        }
+
+        // These drive a whole exchange with the peer, or are answered locally, instead of sending a single command.
+        match keyword.to_uppercase().as_str() {
+            "SYNC" => return self.sync(args.trim()).await,
+            "U" | "PUT" | "UPLOAD" => return self.upload(args.trim()).await,
+            "TRANSFERS" => return self.print_transfers().await,
+            "FORWARD" => return self.forward(args.trim()).await,
+            "FORWARDS" => return self.print_forwards().await,
+            _ => {}
+        }
+
         match self.parse_command(trimmed_command).await {
-            Some(node_command) => self.send_command(node_command).await,
+            Some(node_command) => {
+                debug!("Sending command");
+                self.send_command(node_command).await
+            }
             None => eprintln!("Unknown command: {}", trimmed_command),
         }
     }
 
+    /// Performs the handshake up front instead of on the first command. Returns false if the
+    /// peer's key did not arrive within `timeout`.
+    pub async fn handshake(&self, timeout: Duration) -> bool {
+        if self.no_envelope {
+            return true;
+        }
+        self.send_handshake().await;
+        tokio::time::timeout(timeout, async {
+            while !self.shared_state_ready().await {
+                tokio::time::sleep(HANDSHAKE_POLL_INTERVAL).await;
+            }
+        }).await.is_ok()
+    }
+
+    /// Runs one shell command on the peer and returns its result instead of printing it.
+    pub async fn execute(&self, command: &str) -> Option<Response> {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.shared_state.lock().await.pending_exec_result = Some(result_tx);
+        self.send_command(NodeCommand::Execute { command: command.to_string() }).await;
+        result_rx.await.ok()
+    }
+
     async fn shared_state_ready(&self) -> bool {
         let shared_state = self.shared_state.lock().await;
         shared_state.server_public_key.is_some() && shared_state.session_key.is_some()
@@ -68,12 +170,13 @@ impl TxCommandHandler {
 
     async fn parse_command(&self, command: &str) -> Option<NodeCommand> {
         let parts: Vec<&str> = command.split_whitespace().collect();
-        let cmd = parts.get(0)?.to_uppercase();
+        let cmd = parts.first()?.to_uppercase();
         let args = parts.get(1..).unwrap_or(&[]).join(" ");
 
         match cmd.as_str() {
             "H" | "HELP" => {
-                self.print_help();
+                let peer_capabilities = self.shared_state.lock().await.peer_capabilities.clone();
+                print_help(peer_capabilities.as_ref());
                 None
             }
             "TEXT" | "ECHO" | "PRINT" | "MSG" | "T" => Some(NodeCommand::Echo { message: args }),
@@ -83,105 +186,757 @@ impl TxCommandHandler {
             "NETSTAT" => Some(NodeCommand::Netstat),
             "N" | "NETWORK" | "IFCONFIG" | "IPCONFIG" => Some(NodeCommand::Network),
             "SYSTEM" | "INFO" | "SYSTEMINFO" | "UNAME" => Some(NodeCommand::Info),
-            "D" | "GET" | "DOWNLOAD" => self.parse_get_command(&args),
-            "U" | "PUT" | "UPLOAD" => self.parse_put_command(&args).await,
+            "D" | "GET" | "DOWNLOAD" => self.parse_get_command(&args).await,
             "E" | "X" | "SHELL" | "EXEC" | "RUN" | "CMD" => Some(NodeCommand::Execute { command: args }),
+            "FIND" | "F" => self.parse_find_command(&args),
+            "GREP" | "SEARCH" => self.parse_grep_command(&args),
+            "TAIL" => self.parse_tail_command(&args),
+            "JOBS" => Some(NodeCommand::Jobs),
+            "CANCEL" | "STOP" => self.parse_cancel_command(&args),
             _ => None,
         }
     }
 
-    fn print_help(&self) {
-        let help = indoc!{"
-            [UPLINK HELP]:
+    async fn parse_get_command(&self, args: &str) -> Option<NodeCommand> {
+        match parse_transfer_args(args) {
+            None => {
+                eprintln!("GET/DOWNLOAD command requires both file path and local path.");
+                None
+            }
+            Some((file_path, file_local_path, options)) => {
+                let transfer_id = self.shared_state.lock().await.transfers.start_download(&file_path, &file_local_path, options);
+                Some(NodeCommand::GetFile { file_path, transfer_id })
+            }
+        }
+    }
 
-            H | HELP - Print help
-            ECHO | PRINT | MSG | TEXT | T - Send a message to connected node.
+    async fn upload(&self, args: &str) {
+        let (file_path, file_up_path, options) = match parse_transfer_args(args) {
+            Some(parsed) => parsed,
+            None => {
+                eprintln!("PUT/UPLOAD command requires both file path and upload path.");
+                return;
+            }
+        };
+        let (file_path, file_up_path) = (file_path.as_str(), file_up_path.as_str());
 
-            GET | DOWNLOAD | D <remote> <local> - Download a file or directory.
-            PUT | UPLOAD | U <local> <remote> - Upload a file or directory.
-            LIST | LS | DIR | L - List files in the directory.
+        let mut file = match fs::File::open(file_path).await {
+            Ok(file) => file,
+            Err(e) => {
+                eprintln!("Failed to read file {}: {}", file_path, e);
+                return;
+            }
+        };
+        let metadata = file.metadata().await.ok();
+        let total_size = metadata.as_ref().map_or(0, |metadata| metadata.len());
+        let mode = metadata.as_ref().and_then(safe_write::file_mode);
+        let transfer_id = self.shared_state.lock().await.transfers.start(Direction::Upload, file_path, file_up_path, total_size);
 
-            SHELL | EXEC | RUN | CMD | E | X <command> - Execute a shell command on the connected node.
+        // The peer acknowledges every chunk, those acknowledgements drive the progress display.
+        let mut offset = 0;
+        loop {
+            let data = match transfer::read_chunk(&mut file).await {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Failed to read file {}: {}", file_path, e);
+                    self.shared_state.lock().await.transfers.fail(transfer_id, &e.to_string());
+                    return;
+                }
+            };
+            let length = data.len() as u64;
 
-            ID | WHOAMI | WHO | W - Get current user
-            PWD | WHERE - Get current directory path
-            NETSTAT - Get network connections
-            N | NETWORK | IFCONFIG | IPCONFIG - Get network adapter configuration
-            SYSTEM | INFO | SYSTEMINFO | UNAME - Get system configuration
+            self.send_command(NodeCommand::PutFile {
+                file_path: file_path.to_string(),
+                file_up_path: file_up_path.to_string(),
+                data,
+                transfer_id,
+                offset,
+                total_size,
+                mode,
+                options,
+            }).await;
 
-            PASSPHRASE - Change the encryption passphrase.
-        "};
-        println!("{}", help);
+            offset += length;
+            if length == 0 || offset >= total_size {
+                break;
+            }
+        }
     }
 
-    fn parse_get_command(&self, args: &str) -> Option<NodeCommand> {
-        let arg_parts: Vec<&str> = args.splitn(2, ' ').collect();
-        if arg_parts.len() < 2 {
-            eprintln!("GET/DOWNLOAD command requires both file path and local path.");
-            None
+    async fn print_transfers(&self) {
+        let transfers = self.shared_state.lock().await.transfers.list();
+        if transfers.is_empty() {
+            println!("No transfers.\n");
         } else {
-            Some(NodeCommand::GetFile { 
-                file_path: arg_parts[0].to_string(), 
-                file_local_path: arg_parts[1].to_string() 
-            })
+            println!("{}\n", transfers.join("\n"));
         }
     }
 
-    async fn parse_put_command(&self, args: &str) -> Option<NodeCommand> {
-        let arg_parts: Vec<&str> = args.splitn(2, ' ').collect();
-        if arg_parts.len() < 2 {
-            eprintln!("PUT/UPLOAD command requires both file path and upload path.");
-            None
+    async fn forward(&self, args: &str) {
+        match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+            [kind, bind, target] if kind.eq_ignore_ascii_case("L") => self.forward_local(bind, target).await,
+            [kind, port, target] if kind.eq_ignore_ascii_case("R") => self.forward_remote(port, target).await,
+            [action, id] if action.eq_ignore_ascii_case("STOP") => self.stop_forward(id).await,
+            _ => eprintln!("FORWARD command requires L [address:]<local_port> <remote_host>:<remote_port>, R <remote_port> <local_host>:<local_port>, or STOP <forward>."),
+        }
+    }
+
+    async fn forward_local(&self, bind: &str, target: &str) {
+        if !self.peer_forwards().await {
+            return;
+        }
+        let parsed = tunnel::parse_bind(bind).and_then(|bind| Ok((bind, tunnel::parse_target(target)?)));
+        let (bind, (host, port)) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => return eprintln!("{}", e),
+        };
+
+        match local::listen(bind, host.clone(), port, self.command_sender.clone()).await {
+            Ok(id) => println!("[*] Forwarding {} to {}:{} through the connected node (forward {}). Use FORWARD STOP {} to stop it.\n", bind, host, port, id, id),
+            Err(e) => eprintln!("[!] {}", e),
+        }
+    }
+
+    async fn forward_remote(&self, remote_port: &str, target: &str) {
+        if !self.peer_forwards().await {
+            return;
+        }
+        let remote_port = match remote_port.parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => return eprintln!("Invalid port to listen on: {}", remote_port),
+        };
+        let (host, port) = match tunnel::parse_target(target) {
+            Ok(target) => target,
+            Err(e) => return eprintln!("{}", e),
+        };
+
+        // Recorded before asking, connections may arrive as soon as the peer listens.
+        let forward_id = {
+            let mut shared_state = self.shared_state.lock().await;
+            let forward_id = shared_state.forwards.reserve_id();
+            shared_state.forwards.insert_remote(forward_id, host.clone(), port, remote_port, self.command_sender.clone());
+            forward_id
+        };
+        if self.command_sender.send(NodeCommand::ForwardListen { forward_id, port: remote_port }).await {
+            println!("[*] Asked the connected node to listen on port {} and forward to {}:{} (forward {}). Use FORWARD STOP {} to stop it.\n", remote_port, host, port, forward_id, forward_id);
         } else {
-            let file_path = arg_parts[0].to_string();
-            let file_up_path = arg_parts[1].to_string();
-            let data = fs::read(&file_path).await.unwrap_or_else(|_| vec![]);
-            Some(NodeCommand::PutFile { file_path, file_up_path, data })
+            self.shared_state.lock().await.forwards.stop(forward_id);
         }
     }
 
-    async fn send_command(&self, node_command: NodeCommand) {
-        if let Some((public_key, session_key)) = self.get_keys().await {
-            let envelope = Envelope::create_encrypted_envelope(
-                &public_key, 
-                &serde_json::to_vec(&node_command).unwrap(), 
-                &session_key
-            );
-
-            let serialized_envelope = envelope.to_bytes();
-            let encrypted_envelope = communication::prepare_tx(serialized_envelope, &self.passphrase);
-            self.send_over_ws(encrypted_envelope).await;
-        } else if !self.no_envelope {
-            eprintln!("Session key or public key not available. Command not sent.");
+    async fn stop_forward(&self, id: &str) {
+        let stopped = match id.parse::<u32>() {
+            Ok(id) => self.shared_state.lock().await.forwards.stop(id).map(|forward| (id, forward)),
+            Err(_) => None,
+        };
+        match stopped {
+            Some((id, forward)) => {
+                if forward.is_remote() {
+                    self.send_command(NodeCommand::ForwardUnlisten { forward_id: id }).await;
+                }
+                println!("[+] Forward {} stopped.\n", id);
+            }
+            None => eprintln!("No such forward: {}", id),
+        }
+    }
+
+    async fn peer_forwards(&self) -> bool {
+        let supported = self.shared_state.lock().await.link.as_ref().is_none_or(|link| link.forwarding());
+        if !supported {
+            eprintln!("[!] The connected node does not support forwarding, command not sent.");
+        }
+        supported
+    }
+
+    async fn print_forwards(&self) {
+        let forwards = self.shared_state.lock().await.forwards.list();
+        if forwards.is_empty() {
+            println!("No forwards.\n");
         } else {
-            let serialized_command = serde_json::to_vec(&node_command).expect("Failed to serialize command");
-            let encrypted_command = communication::prepare_tx(serialized_command, &self.passphrase);
-            self.send_over_ws(encrypted_command).await;
+            println!("{}\n", forwards.join("\n"));
         }
     }
 
-    async fn get_keys(&self) -> Option<(RsaPublicKey, Vec<u8>)> {
-        let shared_state = self.shared_state.lock().await;
-        Some((shared_state.server_public_key.clone()?, shared_state.session_key.clone()?))
+    async fn print_status(&self) {
+        match &self.shared_state.lock().await.link {
+            Some(link) => println!("{}\n", link.describe().join("\n")),
+            None => println!("Not connected.\n"),
+        }
     }
 
-    async fn send_over_ws(&self, encrypted_data: Vec<u8>) {
-        if let Some(ws_sender) = &self.ws_sender {
-            let mut sender = ws_sender.lock().await;
-            println!("[*] Sending command");
+    fn parse_find_command(&self, args: &str) -> Option<NodeCommand> {
+        let mut tokens = args.split_whitespace();
+        let (root, pattern) = match (tokens.next(), tokens.next()) {
+            (Some(root), Some(pattern)) => (root.to_string(), pattern.to_string()),
+            _ => {
+                eprintln!("FIND command requires a root directory and a glob pattern.");
+                return None;
+            }
+        };
+
+        let (mut max_depth, mut min_size, mut max_size, mut newer_than_secs, mut limit) = (None, None, None, None, None);
+        while let Some(option) = tokens.next() {
+            let value = match tokens.next() {
+                Some(value) => value,
+                None => {
+                    eprintln!("FIND option {} requires a value.", option);
+                    return None;
+                }
+            };
 
-            if let Err(e) = communication::send_binary_data(&mut sender, encrypted_data).await {
-                eprintln!("Failed to send encrypted envelope: {}", e);
+            match option {
+                "--max-depth" => max_depth = Some(parse_number(option, value)?),
+                "--limit" => limit = Some(parse_number(option, value)?),
+                "--newer" => newer_than_secs = Some(parse_age(value)?),
+                "--size" => {
+                    if let Some(value) = value.strip_prefix('-') {
+                        max_size = Some(parse_size(value)?);
+                    } else {
+                        min_size = Some(parse_size(value.trim_start_matches('+'))?);
+                    }
+                }
+                _ => {
+                    eprintln!("Unknown FIND option: {}", option);
+                    return None;
+                }
+            }
+        }
+
+        Some(NodeCommand::Find { root, pattern, max_depth, min_size, max_size, newer_than_secs, limit })
+    }
+
+    fn parse_grep_command(&self, args: &str) -> Option<NodeCommand> {
+        let mut positional = vec![];
+        let mut recursive = false;
+        let mut limit = None;
+
+        let mut tokens = args.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "-r" | "-R" | "--recursive" => recursive = true,
+                "--limit" => limit = Some(parse_number(token, tokens.next().unwrap_or_default())?),
+                _ => positional.push(token),
+            }
+        }
+
+        match positional.as_slice() {
+            [pattern, path] => Some(NodeCommand::Grep {
+                pattern: pattern.to_string(),
+                path: path.to_string(),
+                recursive,
+                limit,
+            }),
+            _ => {
+                eprintln!("GREP command requires a pattern and a path.");
+                None
             }
+        }
+    }
+
+    fn parse_tail_command(&self, args: &str) -> Option<NodeCommand> {
+        let mut path = None;
+        let mut lines = DEFAULT_TAIL_LINES;
+        let mut follow = false;
+
+        let mut tokens = args.split_whitespace();
+        while let Some(token) = tokens.next() {
+            match token {
+                "-f" | "--follow" => follow = true,
+                "-n" | "--lines" => lines = parse_number(token, tokens.next().unwrap_or_default())?,
+                _ if path.is_none() => path = Some(token.to_string()),
+                _ => {
+                    eprintln!("Unexpected TAIL argument: {}", token);
+                    return None;
+                }
+            }
+        }
+
+        match path {
+            Some(path) => Some(NodeCommand::Tail { path, lines, follow }),
+            None => {
+                eprintln!("TAIL command requires a file path.");
+                None
+            }
+        }
+    }
+
+    fn parse_cancel_command(&self, args: &str) -> Option<NodeCommand> {
+        if args.is_empty() {
+            return Some(NodeCommand::Cancel { job_id: None });
+        }
+        Some(NodeCommand::Cancel { job_id: Some(parse_number("CANCEL", args)?) })
+    }
+
+    async fn sync(&self, args: &str) {
+        let mut pull = false;
+        let mut delete = false;
+        let mut paths = vec![];
+        for token in args.split_whitespace() {
+            match token {
+                "--pull" | "-r" => pull = true,
+                "--delete" => delete = true,
+                _ => paths.push(token),
+            }
+        }
+
+        let (from, to) = match paths.as_slice() {
+            [from, to] => (*from, *to),
+            _ => {
+                eprintln!("SYNC command requires a source and a destination directory.");
+                return;
+            }
+        };
+
+        let result = if pull {
+            self.sync_pull(from, to, delete).await
         } else {
-            eprintln!("No active WebSocket connection. Command not sent.");
+            self.sync_push(from, to, delete).await
+        };
+
+        match result {
+            Ok(stats) => println!("[+] SYNC finished: {}\n", stats.summary()),
+            Err(e) => eprintln!("[!] SYNC aborted: {}\n", e),
         }
     }
 
+    async fn sync_push(&self, local: &str, remote: &str, delete: bool) -> Result<SyncStats, String> {
+        let local_root = PathBuf::from(local);
+        if !local_root.is_dir() {
+            return Err(format!("{} is not a directory", local));
+        }
+
+        let source = tokio::task::block_in_place(|| sync::build_manifest(&local_root))?;
+        let destination = match self.sync_request(|request_id| NodeCommand::SyncManifest { request_id, root: remote.to_string() }).await? {
+            SyncReply::Manifest(entries) => entries,
+            reply => return Err(unexpected_sync_reply(reply)),
+        };
+
+        let plan = sync::plan(&source, &destination, delete);
+        let mut stats = SyncStats { unchanged: plan.unchanged, ..Default::default() };
+        println!("[*] SYNC {} -> {}: {} file(s) to transfer, {} to delete", local, remote, plan.files.len(), plan.deletions.len());
+
+        for directory in &plan.directories {
+            match self.apply_remote(remote, directory, SyncChange::Directory).await? {
+                Ok(()) => stats.directories += 1,
+                Err(e) => stats.record_failure(directory, &e),
+            }
+        }
+
+        for file in &plan.files {
+            let path = sync::resolve(&local_root, &file.entry.path)?;
+            let block_size = sync::block_size_for(file.entry.size);
+            let signatures = if file.has_basis && file.entry.size >= DELTA_THRESHOLD {
+                let request = |request_id| NodeCommand::SyncSignatures {
+                    request_id,
+                    root: remote.to_string(),
+                    path: file.entry.path.clone(),
+                    block_size,
+                };
+                match self.sync_request(request).await? {
+                    SyncReply::Signatures(signatures) => signatures,
+                    SyncReply::Error(e) => {
+                        stats.record_failure(&file.entry.path, &e);
+                        continue;
+                    }
+                    reply => return Err(unexpected_sync_reply(reply)),
+                }
+            } else {
+                vec![]
+            };
+
+            let mut reader = match tokio::task::block_in_place(|| DeltaReader::open(&path, signatures, block_size)) {
+                Ok(reader) => reader,
+                Err(e) => {
+                    stats.record_failure(&file.entry.path, &e);
+                    continue;
+                }
+            };
+
+            println!("[*] {}", file.entry.path);
+            let transfer_id = self.start_sync_transfer(Direction::Upload, &path.display().to_string(), &format!("{}/{}", remote, file.entry.path), file.entry.size).await;
+            let result = self.push_delta(remote, &file.entry.path, transfer_id, &mut reader, &mut stats).await?;
+            self.finish_sync_transfer(transfer_id, file.entry.size, &result).await;
+            match result {
+                Ok(()) => stats.files += 1,
+                Err(e) => stats.record_failure(&file.entry.path, &e),
+            }
+        }
+
+        for path in &plan.deletions {
+            match self.apply_remote(remote, path, SyncChange::Delete).await? {
+                Ok(()) => stats.deleted += 1,
+                Err(e) => stats.record_failure(path, &e),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    async fn sync_pull(&self, remote: &str, local: &str, delete: bool) -> Result<SyncStats, String> {
+        let local_root = PathBuf::from(local);
+        let source = match self.sync_request(|request_id| NodeCommand::SyncManifest { request_id, root: remote.to_string() }).await? {
+            SyncReply::Manifest(entries) => entries,
+            reply => return Err(unexpected_sync_reply(reply)),
+        };
+        let destination = tokio::task::block_in_place(|| sync::build_manifest(&local_root))?;
+
+        let plan = sync::plan(&source, &destination, delete);
+        let mut stats = SyncStats { unchanged: plan.unchanged, ..Default::default() };
+        println!("[*] SYNC {} <- {}: {} file(s) to transfer, {} to delete", local, remote, plan.files.len(), plan.deletions.len());
+
+        std::fs::create_dir_all(&local_root).map_err(|e| format!("Failed to create {}: {}", local, e))?;
+        for directory in &plan.directories {
+            match apply_local(&local_root, directory, SyncChange::Directory) {
+                Ok(()) => stats.directories += 1,
+                Err(e) => stats.record_failure(directory, &e),
+            }
+        }
+
+        for file in &plan.files {
+            let block_size = sync::block_size_for(file.entry.size);
+            let signatures = if file.has_basis && file.entry.size >= DELTA_THRESHOLD {
+                let basis = sync::resolve(&local_root, &file.entry.path)?;
+                tokio::task::block_in_place(|| sync::signatures(&basis, block_size)).unwrap_or_default()
+            } else {
+                vec![]
+            };
+
+            let request = |request_id| NodeCommand::SyncDelta {
+                request_id,
+                root: remote.to_string(),
+                path: file.entry.path.clone(),
+                block_size,
+                signatures,
+            };
+            let local_path = local_root.join(&file.entry.path).display().to_string();
+            let transfer_id = self.start_sync_transfer(Direction::Download, &format!("{}/{}", remote, file.entry.path), &local_path, file.entry.size).await;
+            println!("[*] {}", file.entry.path);
+            let result = self.pull_delta(request, &local_root, &file.entry.path, transfer_id, &mut stats).await?;
+            self.finish_sync_transfer(transfer_id, file.entry.size, &result).await;
+            match result {
+                Ok(()) => stats.files += 1,
+                Err(e) => stats.record_failure(&file.entry.path, &e),
+            }
+        }
+
+        for path in &plan.deletions {
+            match apply_local(&local_root, path, SyncChange::Delete) {
+                Ok(()) => stats.deleted += 1,
+                Err(e) => stats.record_failure(path, &e),
+            }
+        }
+
+        Ok(stats)
+    }
+
+    // SYNC prints its own per-file output, the transfer table only keeps the record for TRANSFERS.
+    async fn start_sync_transfer(&self, direction: Direction, source: &str, destination: &str, size: u64) -> u32 {
+        self.shared_state.lock().await.transfers.start(direction, source, destination, size)
+    }
+
+    async fn finish_sync_transfer(&self, transfer_id: u32, size: u64, result: &Result<(), String>) {
+        let mut shared_state = self.shared_state.lock().await;
+        match result {
+            Ok(()) => shared_state.transfers.update(transfer_id, size),
+            Err(e) => shared_state.transfers.fail(transfer_id, e),
+        };
+    }
+
+    /// Sends a file's delta one part at a time, each applied by the peer before the next is read.
+    async fn push_delta(&self, remote: &str, path: &str, transfer_id: u32, reader: &mut DeltaReader, stats: &mut SyncStats) -> Result<Result<(), String>, String> {
+        loop {
+            let delta = match tokio::task::block_in_place(|| reader.next_part()) {
+                Ok(delta) => delta,
+                Err(e) => return Ok(Err(e)),
+            };
+            stats.record_delta(&delta);
+            let last = delta.end.is_some();
+            if let Err(e) = self.apply_remote(remote, path, SyncChange::File { transfer_id, delta }).await? {
+                return Ok(Err(e));
+            }
+            if last {
+                return Ok(Ok(()));
+            }
+            self.shared_state.lock().await.transfers.update(transfer_id, reader.offset());
+        }
+    }
+
+    /// Applies the parts of a file's delta as the peer sends them, same errors as `apply_remote`.
+    async fn pull_delta<F>(&self, request: F, local_root: &Path, path: &str, transfer_id: u32, stats: &mut SyncStats) -> Result<Result<(), String>, String>
+    where
+        F: FnOnce(u32) -> NodeCommand,
+    {
+        let (request_id, mut replies) = self.send_sync_request(request).await;
+        loop {
+            let delta = match self.next_sync_reply(request_id, &mut replies).await? {
+                SyncReply::Delta(delta) => delta,
+                SyncReply::Error(e) => return Ok(Err(e)),
+                reply => return Err(unexpected_sync_reply(reply)),
+            };
+            stats.record_delta(&delta);
+            let last = delta.end.is_some();
+            let offset = delta.offset;
+            if let Err(e) = apply_local(local_root, path, SyncChange::File { transfer_id, delta }) {
+                // The rest of the parts are dropped as they arrive.
+                self.shared_state.lock().await.pending_sync_replies.remove(&request_id);
+                return Ok(Err(e));
+            }
+            if last {
+                return Ok(Ok(()));
+            }
+            self.shared_state.lock().await.transfers.update(transfer_id, offset);
+        }
+    }
+
+    /// Outer error: the link failed and the sync cannot continue. Inner error: the peer could not apply this change.
+    async fn apply_remote(&self, remote: &str, path: &str, change: SyncChange) -> Result<Result<(), String>, String> {
+        let request = |request_id| NodeCommand::SyncApply {
+            request_id,
+            root: remote.to_string(),
+            path: path.to_string(),
+            change,
+        };
+        match self.sync_request(request).await? {
+            SyncReply::Applied => Ok(Ok(())),
+            SyncReply::Error(e) => Ok(Err(e)),
+            reply => Err(unexpected_sync_reply(reply)),
+        }
+    }
+
+    async fn sync_request<F>(&self, build_command: F) -> Result<SyncReply, String>
+    where
+        F: FnOnce(u32) -> NodeCommand,
+    {
+        let (request_id, mut replies) = self.send_sync_request(build_command).await;
+        self.next_sync_reply(request_id, &mut replies).await
+    }
+
+    // Replies arrive on the returned receiver, a delta takes several.
+    async fn send_sync_request<F>(&self, build_command: F) -> (u32, mpsc::Receiver<SyncReply>)
+    where
+        F: FnOnce(u32) -> NodeCommand,
+    {
+        let (reply_tx, reply_rx) = mpsc::channel(SYNC_REPLY_QUEUE);
+        let request_id = {
+            let mut shared_state = self.shared_state.lock().await;
+            let request_id = shared_state.next_request_id();
+            shared_state.pending_sync_replies.insert(request_id, reply_tx);
+            request_id
+        };
+
+        self.send_command(build_command(request_id)).await;
+        (request_id, reply_rx)
+    }
+
+    async fn next_sync_reply(&self, request_id: u32, replies: &mut mpsc::Receiver<SyncReply>) -> Result<SyncReply, String> {
+        match tokio::time::timeout(SYNC_REPLY_TIMEOUT, replies.recv()).await {
+            Ok(Some(reply)) => Ok(reply),
+            Ok(None) => Err("Sync request was dropped".to_string()),
+            Err(_) => {
+                self.shared_state.lock().await.pending_sync_replies.remove(&request_id);
+                Err("Timed out waiting for the peer".to_string())
+            }
+        }
+    }
+
+    async fn send_command(&self, node_command: NodeCommand) {
+        self.command_sender.send(node_command).await;
+    }
+
     async fn send_handshake(&self) {
-        let node_command = NodeCommand::Handshake;
-        let serialized_command = serde_json::to_vec(&node_command).expect("Failed to serialize handshake command");
-        let encrypted_command = communication::prepare_tx(serialized_command, &self.passphrase);
-        self.send_over_ws(encrypted_command).await;
+        self.command_sender.send_frame(Frame::Command(NodeCommand::Handshake)).await;
+    }
+}
+
+fn print_help(peer_capabilities: Option<&CapabilitySet>) {
+    let help = indoc!{"
+        [UPLINK HELP]:
+
+        H | HELP - Print help
+        STATUS | LINK - Show the connected node, the round trip time and when it was last heard from.
+        ECHO | PRINT | MSG | TEXT | T - Send a message to connected node.
+
+        GET | DOWNLOAD | D <remote> <local> [--overwrite | --skip | --rename] [--follow-symlinks] - Download a file.
+        PUT | UPLOAD | U <local> <remote> [--overwrite | --skip | --rename] [--follow-symlinks] - Upload a file.
+            Existing files are skipped unless --overwrite or --rename is given.
+        TRANSFERS - List active and completed transfers with their progress or outcome.
+        LIST | LS | DIR | L - List files in the directory.
+        FIND | F <root> <glob> [--max-depth N] [--size +N|-N] [--newer AGE] [--limit N] - Find files by name.
+        GREP | SEARCH <pattern> <path> [-r] [--limit N] - Search file contents with a regular expression.
+        TAIL <path> [-n N] [-f] - Print the last lines of a file, -f keeps following appended data.
+
+        SYNC [--delete] <local> <remote> - Push a directory, transferring only changed files and blocks.
+        SYNC --pull [--delete] <remote> <local> - Pull a directory from the connected node.
+
+        JOBS - List background jobs running on the connected node.
+        CANCEL | STOP [job] - Stop a background job, or all of them.
+
+        SHELL | EXEC | RUN | CMD | E | X <command> - Execute a shell command on the connected node.
+
+        FORWARD L [address:]<local_port> <remote_host>:<remote_port> - Tunnel connections to a local port
+            through the connected node to a host it can reach. Listens on 127.0.0.1 unless an address is given.
+        FORWARD R <remote_port> <local_host>:<local_port> - Have the connected node listen on a port and
+            tunnel its connections back to a host reachable from here. Its policy sets the address and limits.
+        FORWARD STOP <forward> - Stop a forward and close its connections.
+        FORWARDS - List forwards with their open and total connections, including those the peer set up here.
+
+        ID | WHOAMI | WHO | W - Get current user
+        PWD | WHERE - Get current directory path
+        NETSTAT - Get network connections
+        N | NETWORK | IFCONFIG | IPCONFIG - Get network adapter configuration
+        SYSTEM | INFO | SYSTEMINFO | UNAME - Get system configuration
+
+        PASSPHRASE - Change the encryption passphrase.
+    "};
+    // Commands the connected node advertised it will refuse are dimmed.
+    for line in help.lines() {
+        let (keyword, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        match peer_capabilities.and_then(|allowed| missing_capability(keyword, args, allowed)) {
+            Some(capability) => println!("\x1b[2m{}  (not allowed: {})\x1b[0m", line, capability),
+            None => println!("{}", line),
+        }
+    }
+}
+
+// The first capability a CLI command needs that the connected node does not grant, so it can be refused before sending.
+fn missing_capability(keyword: &str, args: &str, allowed: &CapabilitySet) -> Option<Capability> {
+    required_capabilities(keyword, args).into_iter().find(|capability| !allowed.contains(capability))
+}
+
+fn required_capabilities(keyword: &str, args: &str) -> CapabilitySet {
+    let capability = match keyword.to_uppercase().as_str() {
+        "L" | "LIST" | "LS" | "DIR" | "D" | "GET" | "DOWNLOAD" | "FIND" | "F" | "GREP" | "SEARCH" | "TAIL" => Capability::ReadFiles,
+        "SYNC" => return sync_capabilities(args.split_whitespace().any(|arg| arg == "--pull" || arg == "-r")),
+        "U" | "PUT" | "UPLOAD" => Capability::WriteFiles,
+        "E" | "X" | "SHELL" | "EXEC" | "RUN" | "CMD" => Capability::Exec,
+        "ID" | "WHOAMI" | "WHO" | "W" | "PWD" | "WHERE" | "SYSTEM" | "INFO" | "SYSTEMINFO" | "UNAME" => Capability::Sysinfo,
+        "NETSTAT" | "N" | "NETWORK" | "IFCONFIG" | "IPCONFIG" => Capability::NetworkInfo,
+        "FORWARD" if !args.trim_start().to_uppercase().starts_with("STOP") => Capability::Forwarding,
+        _ => return CapabilitySet::new(),
+    };
+    CapabilitySet::from([capability])
+}
+
+// A sync sends several kinds of commands, the peer checks each of them.
+fn sync_capabilities(pull: bool) -> CapabilitySet {
+    let (root, path) = (String::new(), String::new());
+    let commands = if pull {
+        vec![
+            NodeCommand::SyncManifest { request_id: 0, root: root.clone() },
+            NodeCommand::SyncDelta { request_id: 0, root, path, block_size: 0, signatures: vec![] },
+        ]
+    } else {
+        vec![
+            NodeCommand::SyncManifest { request_id: 0, root: root.clone() },
+            NodeCommand::SyncSignatures { request_id: 0, root: root.clone(), path: path.clone(), block_size: 0 },
+            NodeCommand::SyncApply { request_id: 0, root, path, change: SyncChange::Directory },
+        ]
+    };
+    commands.iter().filter_map(Capability::required_for).collect()
+}
+
+fn apply_local(root: &Path, path: &str, change: SyncChange) -> Result<(), String> {
+    tokio::task::block_in_place(|| sync::apply_change(root, path, &change))
+}
+
+fn unexpected_sync_reply(reply: SyncReply) -> String {
+    match reply {
+        SyncReply::Error(e) => e,
+        _ => "Unexpected reply from the peer".to_string(),
+    }
+}
+
+// Splits `<source> <destination> [write options]`. The destination may contain spaces.
+fn parse_transfer_args(args: &str) -> Option<(String, String, WriteOptions)> {
+    let mut options = WriteOptions::default();
+    let mut paths = vec![];
+    for token in args.split_whitespace() {
+        match token {
+            "--overwrite" | "-f" => options.existing = ExistingFilePolicy::Overwrite,
+            "--skip" => options.existing = ExistingFilePolicy::Skip,
+            "--rename" => options.existing = ExistingFilePolicy::Rename,
+            "--follow-symlinks" => options.follow_symlinks = true,
+            _ => paths.push(token),
+        }
+    }
+
+    match paths.split_first() {
+        Some((source, destination)) if !destination.is_empty() => Some((source.to_string(), destination.join(" "), options)),
+        _ => None,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> Option<T> {
+    match value.parse() {
+        Ok(number) => Some(number),
+        Err(_) => {
+            eprintln!("Invalid value for {}: {}", option, value);
+            None
+        }
+    }
+}
+
+// Accepts plain byte counts or k/M/G suffixes, e.g. 512, 10k, 4M, 1G.
+fn parse_size(value: &str) -> Option<u64> {
+    let (digits, multiplier) = match value.chars().last()? {
+        'k' | 'K' => (&value[..value.len() - 1], 1024),
+        'm' | 'M' => (&value[..value.len() - 1], 1024 * 1024),
+        'g' | 'G' => (&value[..value.len() - 1], 1024 * 1024 * 1024),
+        _ => (value, 1),
+    };
+    parse_number::<u64>("--size", digits).and_then(|size| scale("--size", value, size, multiplier))
+}
+
+// Accepts seconds or s/m/h/d suffixes, e.g. 90, 30m, 2h, 7d.
+fn parse_age(value: &str) -> Option<u64> {
+    let (digits, multiplier) = match value.chars().last()? {
+        's' => (&value[..value.len() - 1], 1),
+        'm' => (&value[..value.len() - 1], 60),
+        'h' => (&value[..value.len() - 1], 60 * 60),
+        'd' => (&value[..value.len() - 1], 24 * 60 * 60),
+        _ => (value, 1),
+    };
+    parse_number::<u64>("--newer", digits).and_then(|age| scale("--newer", value, age, multiplier))
+}
+
+fn scale(option: &str, value: &str, number: u64, multiplier: u64) -> Option<u64> {
+    let scaled = number.checked_mul(multiplier);
+    if scaled.is_none() {
+        eprintln!("Value for {} is too large: {}", option, value);
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_and_ages_take_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10k"), Some(10 * 1024));
+        assert_eq!(parse_size("4M"), Some(4 * 1024 * 1024));
+        assert_eq!(parse_age("90"), Some(90));
+        assert_eq!(parse_age("2h"), Some(2 * 60 * 60));
+        assert_eq!(parse_age("7d"), Some(7 * 24 * 60 * 60));
+    }
+
+    #[test]
+    fn overflowing_sizes_and_ages_are_rejected() {
+        assert_eq!(parse_size("99999999999G"), None);
+        assert_eq!(parse_size("18446744073709551615k"), None);
+        assert_eq!(parse_age("999999999999999d"), None);
+    }
+
+    #[test]
+    fn sync_needs_every_capability_the_peer_checks() {
+        let write_only = CapabilitySet::from([Capability::WriteFiles]);
+        assert_eq!(missing_capability("SYNC", "local remote", &write_only), Some(Capability::ReadFiles));
+        assert_eq!(missing_capability("sync", "--pull remote local", &write_only), Some(Capability::ReadFiles));
+
+        let read_only = CapabilitySet::from([Capability::ReadFiles]);
+        assert_eq!(missing_capability("SYNC", "local remote", &read_only), Some(Capability::WriteFiles));
+        assert_eq!(missing_capability("SYNC", "--pull remote local", &read_only), None);
+        assert_eq!(missing_capability("FORWARD", "STOP 1", &CapabilitySet::new()), None);
     }
 }
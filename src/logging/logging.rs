@@ -0,0 +1,76 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{LevelFilter, Targets};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+pub const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// Diagnostics go through `tracing` to stderr, filtered by `--log-level`, and optionally as JSON
+/// lines to `--log-file`. Command results and prompts meant for the operator stay on stdout.
+pub fn init(level: LevelFilter, log_file: Option<&Path>) -> Result<(), String> {
+    // Dependencies only get to report problems, their debug output would drown ours.
+    let filter = Targets::new()
+        .with_target("uplink", level)
+        .with_default(level.min(LevelFilter::WARN));
+
+    let console = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        .event_format(ConsoleFormat)
+        .with_filter(filter.clone());
+
+    let file = match log_file {
+        Some(path) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("Failed to open log file {}: {}", path.display(), e))?;
+            let layer = tracing_subscriber::fmt::layer()
+                .json()
+                .with_writer(Mutex::new(file))
+                .with_filter(filter);
+            Some(layer)
+        }
+        None => None,
+    };
+
+    tracing_subscriber::registry()
+        .with(console)
+        .with(file)
+        .try_init()
+        .map_err(|e| format!("Failed to initialize logging: {}", e))
+}
+
+pub fn parse_level(level: &str) -> Result<LevelFilter, String> {
+    level.parse::<LevelFilter>()
+        .map_err(|_| format!("Invalid log level: {} (expected off, error, warn, info, debug or trace)", level))
+}
+
+// Keeps the `[*]` / `[!]` look of the rest of the output instead of timestamps and module paths.
+struct ConsoleFormat;
+
+impl<S, N> FormatEvent<S, N> for ConsoleFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let prefix = match *event.metadata().level() {
+            Level::ERROR | Level::WARN => "[!]",
+            Level::INFO => "[*]",
+            Level::DEBUG => "[debug]",
+            Level::TRACE => "[trace]",
+        };
+        write!(writer, "{} ", prefix)?;
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::module_inception)]
+
 mod uplink_client;
 mod uplink_server; 
 
@@ -6,71 +8,210 @@ mod crypto;
 mod transport;
 mod enums;
 mod handlers;
+mod filesystem;
+mod forwarding;
+mod audit;
+mod logging;
+mod policy;
+mod config;
 
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use clap::Parser;
 use tokio::sync::Mutex;
+use tracing::{info, warn};
 use uplink_server::uplink_server::start_server;
-use uplink_client::uplink_client::start_client;
-use shared_state::shared_state::SharedState;
+use uplink_client::uplink_client::{run_once, start_client};
+use shared_state::shared_state::{SharedState, SharedStateHandle};
+use audit::audit_log::{self, AuditLog};
+use config::cli::{Cli, CliCommand, ConfigAction, LogOptions, NodeOptions};
+use config::config::{Config, Mode};
+use config::config_file::ConfigFile;
+use crypto::kdf::KeyDerivation;
+use transport::communication::ConnectionSettings;
+use crypto::passphrase;
+use enums::response::Response;
+use policy::capabilities;
+use policy::forward_policy;
+use policy::policy::Policy;
 
 #[tokio::main]
 async fn main() {
-    let (
-        mode, 
-        address, 
-        passphrase, 
-        no_exec,
-        no_transfer, 
-        no_envelope
-    ) = get_config();
-
-    let passphrase = Arc::new(passphrase);
-
-    let shared_state = Arc::new(Mutex::new(SharedState::new()));
-
-    match mode.as_deref() {
-        Some("server") => {
-            let address = address.expect("Address is required for server mode");
-            start_server(&address, Arc::clone(&passphrase), no_exec, no_transfer, no_envelope, Arc::clone(&shared_state)).await;
+    let cli = Cli::parse();
+    let load_config = |mode: Option<Mode>, address: Option<String>, node: NodeOptions, log: LogOptions| -> (Config, Option<PathBuf>) {
+        let (file, file_path) = ConfigFile::load(cli.config.as_deref()).unwrap_or_else(|e| exit_with_error(&e));
+        let config = Config::resolve(mode, address, node, log, file).unwrap_or_else(|e| exit_with_error(&e));
+        (config, file_path)
+    };
+
+    match cli.command {
+        None => run_node(load_config(None, None, cli.node, cli.log).0).await,
+        Some(CliCommand::Server { address, node }) => run_node(load_config(Some(Mode::Server), address, node, cli.log).0).await,
+        Some(CliCommand::Client { address, node }) => run_node(load_config(Some(Mode::Client), address, node, cli.log).0).await,
+        Some(CliCommand::Exec { address, node, command }) => {
+            let (config, _) = load_config(Some(Mode::Client), Some(address), node, cli.log);
+            let (settings, policy, shared_state) = prepare_node(&config);
+            let address = config.address.as_deref().unwrap_or_default();
+            let command = command.join(" ");
+            match run_once(address, settings, config.max_results, policy, &command, shared_state).await {
+                Ok(Response::CommandOutput { output, exit_code }) => {
+                    print!("{}", output);
+                    if exit_code != Some(0) {
+                        std::process::exit(exit_code.unwrap_or(1));
+                    }
+                }
+                Ok(Response::PolicyDenied { operation, reason }) => exit_with_error(&format!("[!] Denied by peer policy: {}: {}", operation, reason)),
+                Ok(Response::Message { content } | Response::Error { content }) => exit_with_error(&format!("[!] {}", content.trim())),
+                Ok(_) => exit_with_error("[!] Unexpected response from the peer"),
+                Err(e) => exit_with_error(&format!("[!] {}", e)),
+            }
+        }
+        Some(CliCommand::Config { action: ConfigAction::Check { node } }) => {
+            let (config, file_path) = load_config(None, None, node, cli.log);
+            check_config(&config, file_path.as_deref());
+        }
+        Some(CliCommand::Keygen { out, bytes }) => keygen(out.as_deref(), bytes.into()),
+        Some(CliCommand::VerifyAudit { path }) => verify_audit(&path),
+        Some(CliCommand::Version) => println!("uplink {}", env!("CARGO_PKG_VERSION")),
+    }
+}
+
+async fn run_node(config: Config) {
+    let (mode, address) = match (config.mode, config.address.clone()) {
+        (Some(mode), Some(address)) => (mode, address),
+        (None, _) => exit_with_error("No mode given. Use `uplink server <address>`, `uplink client <address>` or set mode in the config file (see --help)."),
+        (Some(_), None) => exit_with_error("No address given on the command line or in the config file."),
+    };
+
+    let (settings, policy, shared_state) = prepare_node(&config);
+    match mode {
+        Mode::Server => {
+            if let Err(e) = start_server(&address, settings, config.max_results, policy, shared_state).await {
+                exit_with_error(&format!("[!] {}", e));
+            }
+            // The runtime would wait for the CLI, which is still blocked reading stdin.
+            std::process::exit(0);
         }
-        Some("client") => {
-            let address = address.expect("Address is required for client mode");
-            start_client(&address, Arc::clone(&passphrase), no_exec, no_transfer, no_envelope, Arc::clone(&shared_state)).await;
+        Mode::Client => {
+            if let Err(e) = start_client(&address, settings, config.reconnect.clone(), config.max_results, policy, shared_state).await {
+                exit_with_error(&format!("[!] {}", e));
+            }
+            std::process::exit(0);
         }
-        _ => eprintln!("Invalid or missing mode. Use 'server' or 'client'"),
     }
 }
 
-fn get_config() -> (Option<String>, Option<String>, String, bool, bool, bool) {
-    let precompiled_mode: Option<&str> = option_env!("CARGO_PKG_METADATA_PRECOMPILED_MODE");
-    let precompiled_address: Option<&str> = option_env!("CARGO_PKG_METADATA_PRECOMPILED_ADDRESS");
-    let precompiled_passphrase: Option<&str> = option_env!("CARGO_PKG_METADATA_PRECOMPILED_PASSPHRASE");
-    let precompiled_no_envelope: Option<bool> = option_env!("CARGO_PKG_METADATA_PRECOMPILED_NO_ENVELOPE")
-        .and_then(|s| s.parse::<bool>().ok());
+fn prepare_node(config: &Config) -> (Arc<ConnectionSettings>, Arc<Policy>, SharedStateHandle) {
+    if let Err(e) = logging::logging::init(config.log_level, config.log_file.as_deref()) {
+        exit_with_error(&e);
+    }
+
+    let passphrase = obtain_passphrase(config);
+    let policy = config.policy().unwrap_or_else(|e| exit_with_error(&e));
+
+    info!("Capabilities granted to peers: {}", capabilities::format_capabilities(policy.default_capabilities()));
+    for (peer, peer_capabilities) in policy.peer_capabilities() {
+        info!("Capabilities granted to {}: {}", peer, capabilities::format_capabilities(peer_capabilities));
+    }
+    for root in policy.sandbox.describe() {
+        info!("File commands confined to {}", root);
+    }
+    if policy.exec.is_restricted() {
+        info!("Exec policy loaded with {} allowed program rule(s)", policy.exec.rule_count());
+    }
+    if !policy.forwarding.ports().is_empty() {
+        info!("Peer forwards confined to {} port(s) {}", policy.forwarding.bind_address, forward_policy::format_port_ranges(policy.forwarding.ports()).join(", "));
+    }
+
+    let mut shared_state = SharedState::new();
+    if let Some(path) = &config.audit_log {
+        let audit_log = AuditLog::open(path).unwrap_or_else(|e| exit_with_error(&e));
+        info!("Recording commands run for peers in the audit log {}", path.display());
+        shared_state.audit_log = Some(audit_log);
+    }
+
+    let settings = ConnectionSettings {
+        key_derivation: KeyDerivation::new(passphrase, config.kdf),
+        no_envelope: config.no_envelope,
+        compression: config.compression.clone(),
+        limits: config.limits,
+        heartbeat: config.heartbeat,
+    };
+    (Arc::new(settings), Arc::new(policy), Arc::new(Mutex::new(shared_state)))
+}
 
-    let args: Vec<String> = std::env::args().collect();
+fn obtain_passphrase(config: &Config) -> String {
+    let prompt = config.passphrase_prompt || (config.passphrase.is_none() && std::io::stdin().is_terminal());
+    let passphrase = match &config.passphrase {
+        _ if prompt => passphrase::prompt().unwrap_or_else(|e| exit_with_error(&e)),
+        Some(passphrase) => passphrase.clone(),
+        None => String::new(),
+    };
 
-    let mode = args.get(1).cloned().or_else(|| {
-        precompiled_mode.map(|s| s.trim_matches('"').to_string())
-    });
+    match passphrase::weakness(&passphrase) {
+        None => passphrase,
+        Some(reason) if config.insecure => {
+            warn!("The passphrase {}, starting anyway because of --insecure.", reason);
+            if passphrase.is_empty() { passphrase::INSECURE_FALLBACK.to_string() } else { passphrase }
+        }
+        Some(_) if config.passphrase.is_none() && !prompt => exit_with_error(
+            "No passphrase configured. Set PASSPHRASE, --passphrase-file or credentials in the config file (generate one with `uplink keygen`), or pass --insecure."
+        ),
+        Some(reason) => exit_with_error(&format!(
+            "Refusing to start: the passphrase {}. Use a strong one (generate it with `uplink keygen`) or pass --insecure.", reason
+        )),
+    }
+}
 
-    let address = args.get(2).cloned().or_else(|| {
-        precompiled_address.map(|s| s.trim_matches('"').to_string())
-    });
+fn check_config(config: &Config, file_path: Option<&Path>) {
+    let policy = config.policy().unwrap_or_else(|e| exit_with_error(&format!("[!] {}", e)));
+    let passphrase_status = match config.passphrase.as_deref().map(passphrase::weakness) {
+        None => "not configured, asked for at startup".to_string(),
+        Some(None) => "ok".to_string(),
+        Some(Some(reason)) if config.insecure => format!("{} (allowed by --insecure)", reason),
+        Some(Some(reason)) => exit_with_error(&format!("[!] The passphrase {}, nodes refuse to start with it unless --insecure is passed.", reason)),
+    };
+    if let Some(path) = config.audit_log.as_deref().filter(|path| path.exists()) {
+        if let Err(e) = audit_log::verify(path) {
+            exit_with_error(&format!("[!] Audit log {} failed verification: {}", path.display(), e));
+        }
+    }
+    let effective = config.to_toml().unwrap_or_else(|e| exit_with_error(&e));
 
-    let no_envelope = args.contains(&"--no-envelope".to_string()) || precompiled_no_envelope.unwrap_or(false);
+    match file_path {
+        Some(path) => println!("# Config file: {}", path.display()),
+        None => println!("# No config file"),
+    }
+    println!("# Passphrase: {}", passphrase_status);
+    println!("# Capabilities granted to peers: {}", capabilities::format_capabilities(policy.default_capabilities()));
+    for root in policy.sandbox.describe() {
+        println!("# File commands confined to {}", root);
+    }
+    println!("{}", effective);
+    println!("[+] Configuration is valid.");
+}
 
-    let passphrase = std::env::var("PASSPHRASE")
-        .or_else(|_| {
-            precompiled_passphrase
-                .map(|s| s.trim_matches('"').to_string())
-                .ok_or(std::env::VarError::NotPresent)
-        })
-        .unwrap_or_else(|_| "default_passphrase".to_string());
+fn keygen(out: Option<&Path>, bytes: usize) {
+    let passphrase = passphrase::generate(bytes);
+    match out {
+        Some(path) => {
+            passphrase::write_to_file(path, &passphrase).unwrap_or_else(|e: String| exit_with_error(&e));
+            println!("[+] Passphrase written to {}", path.display());
+        }
+        None => println!("{}", passphrase),
+    }
+}
 
-    let no_exec = args.contains(&"--no-exec".to_string());
-    let no_transfer: bool = args.contains(&"--no-transfer".to_string());    
+fn verify_audit(path: &Path) {
+    match audit_log::verify(path) {
+        Ok((entries, _)) => println!("[+] Audit log {} is intact ({} entries).", path.display(), entries),
+        Err(e) => exit_with_error(&format!("[!] Audit log {} has been tampered with: {}", path.display(), e)),
+    }
+}
 
-    (mode, address, passphrase, no_exec, no_transfer, no_envelope)
+fn exit_with_error(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
 }
@@ -0,0 +1,94 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::enums::command::Command;
+
+/// What a peer may ask this node to do. Commands without a capability (ECHO, JOBS, CANCEL,
+/// the handshake) are always allowed, as are the data of connections a permitted FORWARD opened
+/// and stopping a forward.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Capability {
+    ReadFiles,
+    WriteFiles,
+    Exec,
+    Sysinfo,
+    NetworkInfo,
+    Forwarding,
+}
+
+pub type CapabilitySet = BTreeSet<Capability>;
+
+pub const ALL: [Capability; 6] = [
+    Capability::ReadFiles,
+    Capability::WriteFiles,
+    Capability::Exec,
+    Capability::Sysinfo,
+    Capability::NetworkInfo,
+    Capability::Forwarding,
+];
+
+impl Capability {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::ReadFiles => "read-files",
+            Capability::WriteFiles => "write-files",
+            Capability::Exec => "exec",
+            Capability::Sysinfo => "sysinfo",
+            Capability::NetworkInfo => "network-info",
+            Capability::Forwarding => "forwarding",
+        }
+    }
+
+    /// The capability the receiving node checks before running `command`.
+    pub fn required_for(command: &Command) -> Option<Capability> {
+        match command {
+            Command::ListFiles
+            | Command::GetFile { .. }
+            | Command::Find { .. }
+            | Command::Grep { .. }
+            | Command::Tail { .. }
+            | Command::SyncManifest { .. }
+            | Command::SyncSignatures { .. }
+            | Command::SyncDelta { .. } => Some(Capability::ReadFiles),
+            Command::PutFile { .. } | Command::SyncApply { .. } => Some(Capability::WriteFiles),
+            Command::Execute { .. } => Some(Capability::Exec),
+            Command::Whoami | Command::Pwd | Command::Info => Some(Capability::Sysinfo),
+            Command::Netstat | Command::Network => Some(Capability::NetworkInfo),
+            Command::ForwardConnect { .. } | Command::ForwardListen { .. } => Some(Capability::Forwarding),
+            Command::Echo { .. } | Command::Handshake | Command::Jobs | Command::Cancel { .. } => None,
+            Command::ForwardData { .. } | Command::ForwardClose { .. } | Command::ForwardUnlisten { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parses a comma separated list such as `read-files,sysinfo`. `all` and `none` are accepted as well.
+pub fn parse_capabilities(list: &str) -> Result<CapabilitySet, String> {
+    let mut capabilities = CapabilitySet::new();
+    for name in list.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match name {
+            "all" => capabilities.extend(ALL),
+            "none" => {}
+            _ => {
+                let capability = ALL.iter()
+                    .find(|capability| capability.name() == name)
+                    .ok_or_else(|| format!("Unknown capability: {} (expected one of {})", name, format_capabilities(&ALL.into())))?;
+                capabilities.insert(*capability);
+            }
+        }
+    }
+    Ok(capabilities)
+}
+
+pub fn format_capabilities(capabilities: &CapabilitySet) -> String {
+    if capabilities.is_empty() {
+        return "none".to_string();
+    }
+    capabilities.iter().map(Capability::name).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,171 @@
+use std::path::Path;
+use regex::Regex;
+use serde::Deserialize;
+
+/// What happens to a command that no rule allows.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unlisted {
+    #[default]
+    Deny,
+    Confirm,
+}
+
+#[derive(Deserialize)]
+struct PolicyFile {
+    #[serde(default)]
+    unlisted: Unlisted,
+    #[serde(default)]
+    allow: Vec<RuleFile>,
+}
+
+#[derive(Deserialize)]
+struct RuleFile {
+    program: String,
+    args: Option<String>,
+}
+
+struct Rule {
+    program: String,
+    args: Option<Regex>,
+}
+
+impl Rule {
+    // A bare program name only matches when it is looked up in PATH, so `./ls` does not pass as `ls`.
+    fn matches(&self, executable: &str, args: &str) -> bool {
+        let program_matches = if self.program.contains('/') {
+            Path::new(executable) == Path::new(&self.program)
+        } else {
+            executable == self.program
+        };
+        program_matches && self.args.as_ref().is_none_or(|pattern| pattern.is_match(args))
+    }
+}
+
+pub enum Decision {
+    Allow,
+    Confirm,
+    Deny(String),
+}
+
+/// Allowlist for EXEC loaded from `--exec-policy <file>` (TOML, or JSON for `.json` files):
+///
+/// ```toml
+/// unlisted = "confirm"
+///
+/// [[allow]]
+/// program = "ls"
+/// args = "(-[a-z]+ ?)*"
+/// ```
+///
+/// `args` is a regex matched against the whole argument string. Without a policy file every command is allowed.
+pub struct ExecPolicy {
+    rules: Option<Vec<Rule>>,
+    unlisted: Unlisted,
+}
+
+impl ExecPolicy {
+    pub fn unrestricted() -> Self {
+        ExecPolicy { rules: None, unlisted: Unlisted::Deny }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path).map_err(|e| format!("Failed to read exec policy {}: {}", path.display(), e))?;
+        let is_json = path.extension().is_some_and(|extension| extension == "json");
+        let path = path.display();
+        let file: PolicyFile = if is_json {
+            serde_json::from_str(&content).map_err(|e| format!("Invalid exec policy {}: {}", path, e))?
+        } else {
+            toml::from_str(&content).map_err(|e| format!("Invalid exec policy {}: {}", path, e))?
+        };
+
+        let rules = file.allow.into_iter()
+            .map(|rule| {
+                let args = rule.args
+                    .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+                    .transpose()
+                    .map_err(|e| format!("Invalid args pattern for {} in {}: {}", rule.program, path, e))?;
+                Ok(Rule { program: rule.program, args })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(ExecPolicy { rules: Some(rules), unlisted: file.unlisted })
+    }
+
+    pub fn is_restricted(&self) -> bool {
+        self.rules.is_some()
+    }
+
+    pub fn rule_count(&self) -> usize {
+        self.rules.as_ref().map_or(0, Vec::len)
+    }
+
+    pub fn check(&self, executable: &str, args: &[&str]) -> Decision {
+        let rules = match &self.rules {
+            Some(rules) => rules,
+            None => return Decision::Allow,
+        };
+
+        let args = args.join(" ");
+        if rules.iter().any(|rule| rule.matches(executable, &args)) {
+            return Decision::Allow;
+        }
+
+        match self.unlisted {
+            Unlisted::Confirm => Decision::Confirm,
+            Unlisted::Deny => Decision::Deny(format!("{} with these arguments is not in the exec policy allowlist", executable)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, content: &str) -> Result<ExecPolicy, String> {
+        let path = std::env::temp_dir().join(format!("uplink-exec-policy-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        let policy = ExecPolicy::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        policy
+    }
+
+    fn decision(policy: &ExecPolicy, command: &str) -> &'static str {
+        let mut parts = command.split_whitespace();
+        let executable = parts.next().unwrap();
+        match policy.check(executable, &parts.collect::<Vec<_>>()) {
+            Decision::Allow => "allow",
+            Decision::Confirm => "confirm",
+            Decision::Deny(_) => "deny",
+        }
+    }
+
+    #[test]
+    fn only_listed_programs_with_matching_arguments_are_allowed() {
+        let policy = load("rules.toml", "[[allow]]\nprogram = \"ls\"\nargs = \"(-[a-z]+ ?)*\"\n\n[[allow]]\nprogram = \"/usr/bin/uptime\"\n").unwrap();
+        assert_eq!(policy.rule_count(), 2);
+        assert_eq!(decision(&policy, "ls"), "allow");
+        assert_eq!(decision(&policy, "ls -la"), "allow");
+        // The pattern has to match the whole argument string.
+        assert_eq!(decision(&policy, "ls -la /etc"), "deny");
+        assert_eq!(decision(&policy, "./ls"), "deny");
+        assert_eq!(decision(&policy, "/usr/bin/uptime --pretty"), "allow");
+        assert_eq!(decision(&policy, "uptime"), "deny");
+        assert_eq!(decision(&policy, "rm -rf /"), "deny");
+    }
+
+    #[test]
+    fn unlisted_commands_can_ask_for_confirmation() {
+        let policy = load("confirm.json", r#"{"unlisted": "confirm", "allow": [{"program": "id"}]}"#).unwrap();
+        assert_eq!(decision(&policy, "id -u"), "allow");
+        assert_eq!(decision(&policy, "whoami"), "confirm");
+
+        assert_eq!(decision(&ExecPolicy::unrestricted(), "rm -rf /"), "allow");
+    }
+
+    #[test]
+    fn invalid_policies_are_reported() {
+        assert!(load("regex.toml", "[[allow]]\nprogram = \"ls\"\nargs = \"(\"\n").err().unwrap().contains("Invalid args pattern"));
+        assert!(load("unlisted.toml", "unlisted = \"maybe\"\n").is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::net::{IpAddr, Ipv4Addr};
+use std::ops::RangeInclusive;
+
+pub const DEFAULT_BIND_ADDRESS: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+pub const DEFAULT_MAX_CONNECTIONS: usize = 16;
+
+/// Where `FORWARD R` may make this node listen on behalf of the peer: the address its listeners
+/// are bound to, the ports it may pick and how many connections each one takes at a time.
+pub struct ForwardPolicy {
+    pub bind_address: IpAddr,
+    ports: Vec<RangeInclusive<u16>>,
+    pub max_connections: usize,
+}
+
+impl ForwardPolicy {
+    pub fn new(bind_address: IpAddr, ports: Vec<RangeInclusive<u16>>, max_connections: usize) -> Self {
+        ForwardPolicy { bind_address, ports, max_connections }
+    }
+
+    /// Without configured ranges the peer may pick any port.
+    pub fn check_port(&self, port: u16) -> Result<(), String> {
+        if port == 0 {
+            return Err("Port 0 cannot be forwarded".to_string());
+        }
+        if !self.ports.is_empty() && !self.ports.iter().any(|range| range.contains(&port)) {
+            return Err(format!("Port {} is not allowed for forwarding, allowed: {}", port, format_port_ranges(&self.ports).join(", ")));
+        }
+        Ok(())
+    }
+
+    pub fn ports(&self) -> &[RangeInclusive<u16>] {
+        &self.ports
+    }
+}
+
+/// Parses a port or a range of ports such as `8000-8099`.
+pub fn parse_port_range(range: &str) -> Result<RangeInclusive<u16>, String> {
+    let invalid = || format!("expected <port> or <first>-<last>, got {}", range);
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    match (first.trim().parse::<u16>(), last.trim().parse::<u16>()) {
+        (Ok(first), Ok(last)) if first != 0 && first <= last => Ok(first..=last),
+        _ => Err(invalid()),
+    }
+}
+
+pub fn format_port_ranges(ranges: &[RangeInclusive<u16>]) -> Vec<String> {
+    ranges
+        .iter()
+        .map(|range| if range.start() == range.end() {
+            range.start().to_string()
+        } else {
+            format!("{}-{}", range.start(), range.end())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports_and_ranges_are_parsed() {
+        assert_eq!(parse_port_range("8080"), Ok(8080..=8080));
+        assert_eq!(parse_port_range(" 8000 - 8099 "), Ok(8000..=8099));
+        for invalid in ["", "0", "0-10", "10-9", "8000-", "-8000", "65536", "80-90-100", "http"] {
+            assert!(parse_port_range(invalid).is_err(), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn ports_outside_the_configured_ranges_are_refused() {
+        let policy = ForwardPolicy::new(DEFAULT_BIND_ADDRESS, vec![8000..=8099, 9000..=9000], DEFAULT_MAX_CONNECTIONS);
+        assert!(policy.check_port(8000).is_ok());
+        assert!(policy.check_port(9000).is_ok());
+        assert!(policy.check_port(8100).unwrap_err().contains("8000-8099, 9000"));
+        assert!(policy.check_port(0).is_err());
+
+        let any = ForwardPolicy::new(DEFAULT_BIND_ADDRESS, vec![], DEFAULT_MAX_CONNECTIONS);
+        assert!(any.check_port(1).is_ok());
+        assert!(any.check_port(0).is_err());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod capabilities;
+pub mod exec_policy;
+pub mod forward_policy;
+pub mod policy;
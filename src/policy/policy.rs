@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use crate::filesystem::sandbox::Sandbox;
+use crate::policy::capabilities::CapabilitySet;
+use crate::policy::exec_policy::ExecPolicy;
+use crate::policy::forward_policy::ForwardPolicy;
+
+/// Everything that decides what a peer may do on this node: its capabilities, the
+/// directories file commands are confined to, the programs EXEC may start and where it may make
+/// this node listen with `FORWARD R`.
+pub struct Policy {
+    pub sandbox: Sandbox,
+    pub exec: ExecPolicy,
+    pub forwarding: ForwardPolicy,
+    default_capabilities: CapabilitySet,
+    peer_capabilities: HashMap<String, CapabilitySet>,
+}
+
+impl Policy {
+    pub fn new(
+        sandbox: Sandbox,
+        exec: ExecPolicy,
+        forwarding: ForwardPolicy,
+        default_capabilities: CapabilitySet,
+        peer_capabilities: HashMap<String, CapabilitySet>,
+    ) -> Self {
+        Policy { sandbox, exec, forwarding, default_capabilities, peer_capabilities }
+    }
+
+    /// Peers are identified by their IP address, peers without an entry get the default set.
+    pub fn capabilities_for(&self, peer: &str) -> CapabilitySet {
+        self.peer_capabilities.get(peer).unwrap_or(&self.default_capabilities).clone()
+    }
+
+    pub fn default_capabilities(&self) -> &CapabilitySet {
+        &self.default_capabilities
+    }
+
+    pub fn peer_capabilities(&self) -> &HashMap<String, CapabilitySet> {
+        &self.peer_capabilities
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::Sender;
+use tokio::task::AbortHandle;
+use crate::forwarding::tunnel::Outbound;
+use crate::handlers::command_sender::CommandSender;
+
+/// Which node set up the forward a stream belongs to. Commands carry the streams of forwards the
+/// peer set up, responses those of forwards set up here, so both sides can number them freely.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Origin {
+    Here,
+    Peer,
+}
+
+enum Kind {
+    /// `FORWARD L`: the task accepting connections here.
+    Local { listener: AbortHandle },
+    /// `FORWARD R`: the peer listens and its connections are tunnelled back to `host:port`.
+    Remote { host: String, port: u16, command_sender: CommandSender },
+}
+
+pub struct Forward {
+    description: String,
+    kind: Kind,
+    connections: u32,
+}
+
+impl Forward {
+    /// The peer listens for it and has to be told when it stops.
+    pub fn is_remote(&self) -> bool {
+        matches!(self.kind, Kind::Remote { .. })
+    }
+}
+
+// A `FORWARD R` the peer set up, listening on this node.
+struct PeerForward {
+    address: SocketAddr,
+    listener: AbortHandle,
+    connections: u32,
+    max_connections: usize,
+}
+
+// One forwarded connection. Data from the peer is queued for the task writing to the socket, the
+// task reading the socket is started once the peer knows about the stream.
+struct Stream {
+    writer: Option<Sender<Vec<u8>>>,
+    reader: Option<AbortHandle>,
+    outbound: Outbound,
+}
+
+/// What became of data from the peer for one of the streams.
+pub enum Delivery {
+    Queued,
+    /// The stream is closed or was never opened.
+    Gone,
+    /// Its socket fell too far behind, the stream was aborted and the peer has to be told through `Outbound`.
+    Overflowed(Outbound),
+}
+
+/// The port forwards set up by this node as operator and by the peer, and the connections
+/// tunnelled through the link in either direction.
+pub struct ForwardTable {
+    next_id: u32,
+    forwards: BTreeMap<u32, Forward>,
+    peer_forwards: BTreeMap<u32, PeerForward>,
+    streams: HashMap<(Origin, u32, u32), Stream>,
+}
+
+impl ForwardTable {
+    pub fn new() -> Self {
+        ForwardTable {
+            next_id: 1,
+            forwards: BTreeMap::new(),
+            peer_forwards: BTreeMap::new(),
+            streams: HashMap::new(),
+        }
+    }
+
+    pub fn reserve_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn insert_local(&mut self, id: u32, description: String, listener: AbortHandle) {
+        self.forwards.insert(id, Forward { description, kind: Kind::Local { listener }, connections: 0 });
+    }
+
+    pub fn insert_remote(&mut self, id: u32, host: String, port: u16, remote_port: u16, command_sender: CommandSender) {
+        let description = format!("R port {} on the peer -> {}:{} (waiting for the peer)", remote_port, host, port);
+        self.forwards.insert(id, Forward { description, kind: Kind::Remote { host, port, command_sender }, connections: 0 });
+    }
+
+    /// The peer reported the address it listens on for remote forward `id`.
+    pub fn listening(&mut self, id: u32, address: &str) {
+        if let Some(Forward { description, kind: Kind::Remote { host, port, .. }, .. }) = self.forwards.get_mut(&id) {
+            *description = format!("R {} on the peer -> {}:{}", address, host, port);
+        }
+    }
+
+    /// Counts a new connection on local forward `id` and returns its stream id.
+    pub fn next_stream(&mut self, id: u32) -> Option<u32> {
+        let forward = self.forwards.get_mut(&id)?;
+        forward.connections += 1;
+        Some(forward.connections)
+    }
+
+    /// Counts a connection the peer accepted for remote forward `id` and returns where it goes.
+    pub fn accept_remote(&mut self, id: u32) -> Option<(String, u16, CommandSender)> {
+        let forward = self.forwards.get_mut(&id)?;
+        let Kind::Remote { host, port, command_sender } = &forward.kind else { return None };
+        forward.connections += 1;
+        Some((host.clone(), *port, command_sender.clone()))
+    }
+
+    /// Stops forward `id` and closes its connections.
+    pub fn stop(&mut self, id: u32) -> Option<Forward> {
+        let forward = self.forwards.remove(&id)?;
+        if let Kind::Local { listener } = &forward.kind {
+            listener.abort();
+        }
+        self.abort_forward_streams(Origin::Here, id);
+        Some(forward)
+    }
+
+    pub fn insert_peer(&mut self, id: u32, address: SocketAddr, listener: AbortHandle, max_connections: usize) {
+        let forward = PeerForward { address, listener, connections: 0, max_connections };
+        if let Some(replaced) = self.peer_forwards.insert(id, forward) {
+            replaced.listener.abort();
+        }
+    }
+
+    /// Counts a new connection on the peer's forward `id` and returns its stream id, unless the
+    /// forward already has as many connections open as the policy allows.
+    pub fn accept_peer(&mut self, id: u32) -> Result<u32, String> {
+        let open = self.open_streams(Origin::Peer, id);
+        let forward = self.peer_forwards.get_mut(&id).ok_or_else(|| format!("forward {} is closed", id))?;
+        if open >= forward.max_connections {
+            return Err(format!("{} connection(s) already open", open));
+        }
+        forward.connections += 1;
+        Ok(forward.connections)
+    }
+
+    /// Stops listening for the peer's forward `id` and closes its connections.
+    pub fn stop_peer(&mut self, id: u32) -> bool {
+        let Some(forward) = self.peer_forwards.remove(&id) else { return false };
+        forward.listener.abort();
+        self.abort_forward_streams(Origin::Peer, id);
+        true
+    }
+
+    pub fn open_stream(&mut self, forward_id: u32, stream_id: u32, writer: Sender<Vec<u8>>, outbound: Outbound) {
+        let key = (outbound.origin(), forward_id, stream_id);
+        self.streams.insert(key, Stream { writer: Some(writer), reader: None, outbound });
+    }
+
+    /// Records the task reading the stream's socket. False if the stream was aborted in the meantime.
+    pub fn start_reading(&mut self, origin: Origin, forward_id: u32, stream_id: u32, reader: AbortHandle) -> bool {
+        let key = (origin, forward_id, stream_id);
+        match self.streams.get_mut(&key) {
+            // Closed by the peer before it was started, nothing more to track.
+            Some(stream) if stream.writer.is_none() => {
+                self.streams.remove(&key);
+                true
+            }
+            Some(stream) => {
+                stream.reader = Some(reader);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Queues data from the peer for the socket, aborting the stream once its queue is full.
+    pub fn write(&mut self, origin: Origin, forward_id: u32, stream_id: u32, data: Vec<u8>) -> Delivery {
+        let key = (origin, forward_id, stream_id);
+        let Some(writer) = self.streams.get(&key).and_then(|stream| stream.writer.as_ref()) else { return Delivery::Gone };
+        match writer.try_send(data) {
+            Ok(()) => Delivery::Queued,
+            Err(TrySendError::Closed(_)) => Delivery::Gone,
+            Err(TrySendError::Full(_)) => match self.streams.remove(&key) {
+                Some(stream) => {
+                    if let Some(reader) = stream.reader {
+                        reader.abort();
+                    }
+                    Delivery::Overflowed(stream.outbound)
+                }
+                None => Delivery::Gone,
+            },
+        }
+    }
+
+    /// The peer sends no more data: the socket is shut down for writing once the queue is written,
+    /// while its reading side runs until the socket closes.
+    pub fn close_stream(&mut self, origin: Origin, forward_id: u32, stream_id: u32) {
+        let key = (origin, forward_id, stream_id);
+        match self.streams.get_mut(&key) {
+            Some(stream) if stream.reader.is_none() => stream.writer = None,
+            _ => {
+                self.streams.remove(&key);
+            }
+        }
+    }
+
+    pub fn abort_stream(&mut self, origin: Origin, forward_id: u32, stream_id: u32) {
+        if let Some(reader) = self.streams.remove(&(origin, forward_id, stream_id)).and_then(|stream| stream.reader) {
+            reader.abort();
+        }
+    }
+
+    /// The peer is gone: closes every forwarded connection, stops listening for the peer's forwards
+    /// and forgets the remote ones it listened for.
+    pub fn end_session(&mut self) {
+        for (_, stream) in std::mem::take(&mut self.streams) {
+            if let Some(reader) = stream.reader {
+                reader.abort();
+            }
+        }
+        for (_, forward) in std::mem::take(&mut self.peer_forwards) {
+            forward.listener.abort();
+        }
+        self.forwards.retain(|_, forward| !forward.is_remote());
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        let own = self.forwards.iter().map(|(id, forward)| {
+            format!("[{}] {} ({} open, {} total)", id, forward.description, self.open_streams(Origin::Here, *id), forward.connections)
+        });
+        let peer = self.peer_forwards.iter().map(|(id, forward)| {
+            format!(
+                "[peer {}] R {} -> the peer ({} open, {} total, at most {} at a time)",
+                id, forward.address, self.open_streams(Origin::Peer, *id), forward.connections, forward.max_connections
+            )
+        });
+        own.chain(peer).collect()
+    }
+
+    fn open_streams(&self, origin: Origin, id: u32) -> usize {
+        self.streams.keys().filter(|key| key.0 == origin && key.1 == id).count()
+    }
+
+    fn abort_forward_streams(&mut self, origin: Origin, id: u32) {
+        let streams: Vec<_> = self.streams.keys().filter(|key| key.0 == origin && key.1 == id).copied().collect();
+        for (origin, forward_id, stream_id) in streams {
+            self.abort_stream(origin, forward_id, stream_id);
+        }
+    }
+}
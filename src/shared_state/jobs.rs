@@ -0,0 +1,61 @@
+use std::collections::BTreeMap;
+use tokio::task::AbortHandle;
+
+pub struct Job {
+    pub description: String,
+    handle: AbortHandle,
+}
+
+/// Long running background jobs (e.g. `TAIL -f`) started on behalf of the peer.
+pub struct JobTable {
+    next_id: u32,
+    jobs: BTreeMap<u32, Job>,
+}
+
+impl JobTable {
+    pub fn new() -> Self {
+        JobTable {
+            next_id: 1,
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    pub fn reserve_id(&mut self) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    pub fn insert(&mut self, id: u32, description: String, handle: AbortHandle) {
+        self.jobs.insert(id, Job { description, handle });
+    }
+
+    pub fn remove(&mut self, id: u32) -> Option<Job> {
+        self.jobs.remove(&id)
+    }
+
+    pub fn cancel(&mut self, id: u32) -> bool {
+        match self.jobs.remove(&id) {
+            Some(job) => {
+                job.handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn cancel_all(&mut self) -> usize {
+        let count = self.jobs.len();
+        for (_, job) in std::mem::take(&mut self.jobs) {
+            job.handle.abort();
+        }
+        count
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.jobs
+            .iter()
+            .map(|(id, job)| format!("[{}] {}", id, job.description))
+            .collect()
+    }
+}
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+use crate::shared_state::transfers::format_duration;
+use crate::transport::hello::{self, Hello};
+
+/// Liveness of the current connection, fed by every frame received and by heartbeats.
+pub struct LinkHealth {
+    peer: String,
+    build_version: String,
+    heartbeats: bool,
+    forwarding: bool,
+    connected_at: Instant,
+    last_seen: Instant,
+    last_activity: Instant,
+    round_trip: Option<Duration>,
+    // The ping still waiting for its pong, a later ping replaces it.
+    outstanding_ping: Option<(u64, Instant)>,
+    next_nonce: u64,
+}
+
+impl LinkHealth {
+    /// `peer_hello` tells whether the peer answers pings and can tunnel forwarded connections.
+    pub fn new(peer: String, peer_hello: &Hello) -> Self {
+        let now = Instant::now();
+        LinkHealth {
+            peer,
+            build_version: peer_hello.build_version.clone(),
+            heartbeats: peer_hello.has_feature(hello::FEATURE_HEARTBEAT),
+            forwarding: peer_hello.has_feature(hello::FEATURE_FORWARDING),
+            connected_at: now,
+            last_seen: now,
+            last_activity: now,
+            round_trip: None,
+            outstanding_ping: None,
+            next_nonce: 1,
+        }
+    }
+
+    pub fn heartbeats(&self) -> bool {
+        self.heartbeats
+    }
+
+    pub fn forwarding(&self) -> bool {
+        self.forwarding
+    }
+
+    /// Any frame from the peer. Heartbeats prove the peer is alive but do not keep the session from idling.
+    pub fn record_received(&mut self, heartbeat: bool) {
+        self.last_seen = Instant::now();
+        if !heartbeat {
+            self.last_activity = self.last_seen;
+        }
+    }
+
+    pub fn record_activity(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    pub fn start_ping(&mut self) -> u64 {
+        let nonce = self.next_nonce;
+        self.next_nonce = self.next_nonce.wrapping_add(1);
+        self.outstanding_ping = Some((nonce, Instant::now()));
+        nonce
+    }
+
+    pub fn record_pong(&mut self, nonce: u64) {
+        if let Some((expected, sent_at)) = self.outstanding_ping {
+            if expected == nonce {
+                self.round_trip = Some(sent_at.elapsed());
+                self.outstanding_ping = None;
+            }
+        }
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
+
+    pub fn idle_for(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Lines printed by the STATUS command.
+    pub fn describe(&self) -> Vec<String> {
+        let round_trip = match (self.round_trip, self.heartbeats) {
+            (Some(round_trip), _) => format!("{} ms", round_trip.as_millis()),
+            (None, true) => "not measured yet".to_string(),
+            (None, false) => "unknown, the peer does not answer heartbeats".to_string(),
+        };
+        vec![
+            format!("Peer: {} (uplink {})", self.peer, self.build_version),
+            format!("Connected for: {}", format_duration(self.connected_at.elapsed())),
+            format!("Last seen: {} ago", format_duration(self.silent_for())),
+            format!("Round trip: {}", round_trip),
+            format!("Idle for: {}", format_duration(self.idle_for())),
+        ]
+    }
+}
@@ -1 +1,6 @@
-pub mod shared_state;
\ No newline at end of file
+pub mod shared_state;
+pub mod forwards;
+pub mod jobs;
+pub mod link;
+pub mod session;
+pub mod transfers;
\ No newline at end of file
@@ -0,0 +1,210 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use rand::RngCore;
+use crate::transport::codec::{self, Frame};
+use crate::transport::communication::{Channel, WsSender};
+
+/// How long a dropped session is kept for the peer to resume it.
+pub const RESUME_WINDOW: Duration = Duration::from_secs(300);
+// Frames the peer has not confirmed are kept for replay up to this size, the oldest are dropped beyond it.
+const MAX_REPLAY_BYTES: usize = 32 * 1024 * 1024;
+const TICKET_LENGTH: usize = 32;
+
+/// The connection a session is carried over.
+#[derive(Clone)]
+pub struct Connection {
+    pub channel: Channel,
+    pub ws_sender: WsSender,
+}
+
+/// The part of the conversation with the peer that outlives a connection: the ticket to resume it
+/// with, the numbering of commands and responses, and the ones the peer has not confirmed yet.
+pub struct Session {
+    ticket: Option<Vec<u8>>,
+    connection: Option<Connection>,
+    suspended_since: Option<Instant>,
+    sent: u64,
+    received: u64,
+    unacknowledged: VecDeque<(u64, Vec<u8>)>,
+    unacknowledged_bytes: usize,
+    // The last frame dropped from the replay buffer before the peer confirmed it.
+    dropped_through: u64,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Session {
+            ticket: None,
+            connection: None,
+            suspended_since: None,
+            sent: 0,
+            received: 0,
+            unacknowledged: VecDeque::new(),
+            unacknowledged_bytes: 0,
+            dropped_through: 0,
+        }
+    }
+
+    /// Starts a new session on the accepting node and returns the ticket to resume it with.
+    pub fn issue_ticket(&mut self) -> Vec<u8> {
+        let mut ticket = vec![0u8; TICKET_LENGTH];
+        rand::thread_rng().fill_bytes(&mut ticket);
+        self.restart(ticket.clone());
+        ticket
+    }
+
+    /// Starts the new session the accepting node issued `ticket` for.
+    pub fn adopt_ticket(&mut self, ticket: Vec<u8>) {
+        self.restart(ticket);
+    }
+
+    fn restart(&mut self, ticket: Vec<u8>) {
+        *self = Session { ticket: Some(ticket), ..Session::new() };
+    }
+
+    pub fn ticket(&self) -> Option<&[u8]> {
+        self.ticket.as_deref()
+    }
+
+    /// Whether `ticket` names this session and it is still kept.
+    pub fn can_resume(&self, ticket: &[u8]) -> bool {
+        self.ticket.as_deref() == Some(ticket) && !self.expired()
+    }
+
+    /// Whether frames sent now can still reach the peer, over this connection or a resumed one.
+    pub fn resumable(&self) -> bool {
+        self.ticket.is_some() && !self.expired()
+    }
+
+    fn expired(&self) -> bool {
+        self.suspended_since.is_some_and(|since| since.elapsed() > RESUME_WINDOW)
+    }
+
+    pub fn attach(&mut self, connection: Connection) {
+        self.connection = Some(connection);
+        self.suspended_since = None;
+    }
+
+    /// The connection over `ws_sender` ended. A connection the session already moved away from changes nothing.
+    pub fn detach(&mut self, ws_sender: &WsSender) {
+        if self.connection.as_ref().is_some_and(|connection| Arc::ptr_eq(&connection.ws_sender, ws_sender)) {
+            self.connection = None;
+            self.suspended_since = Some(Instant::now());
+        }
+    }
+
+    pub fn connection(&self) -> Option<&Connection> {
+        self.connection.as_ref()
+    }
+
+    /// Whether the session is carried over `ws_sender`, or over no connection at all yet.
+    pub fn is_current(&self, ws_sender: &WsSender) -> bool {
+        self.connection.as_ref().is_none_or(|connection| Arc::ptr_eq(&connection.ws_sender, ws_sender))
+    }
+
+    /// Numbers a command or response and keeps it until the peer confirms it. Returns the encoded frame.
+    pub fn number(&mut self, frame: Frame) -> Vec<u8> {
+        self.sent += 1;
+        let encoded = codec::encode(&Frame::Numbered { seq: self.sent, frame: Box::new(frame) });
+        self.unacknowledged_bytes += encoded.len();
+        self.unacknowledged.push_back((self.sent, encoded.clone()));
+
+        while self.unacknowledged_bytes > MAX_REPLAY_BYTES {
+            let Some((seq, dropped)) = self.unacknowledged.pop_front() else { break };
+            self.unacknowledged_bytes -= dropped.len();
+            self.dropped_through = seq;
+        }
+        encoded
+    }
+
+    /// Records a numbered frame from the peer. False for one that already arrived before a replay.
+    pub fn receive(&mut self, seq: u64) -> bool {
+        if seq <= self.received {
+            return false;
+        }
+        self.received = seq;
+        true
+    }
+
+    /// The last numbered frame received from the peer.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Forgets the frames up to the last one the peer received.
+    pub fn acknowledge(&mut self, received: u64) {
+        while let Some((seq, frame)) = self.unacknowledged.front() {
+            if *seq > received {
+                break;
+            }
+            self.unacknowledged_bytes -= frame.len();
+            self.unacknowledged.pop_front();
+        }
+    }
+
+    /// The frames the peer missed after the last one it received, and how many more it missed
+    /// that no longer fit the replay buffer.
+    pub fn replay(&mut self, received: u64) -> (Vec<Vec<u8>>, u64) {
+        self.acknowledge(received);
+        let lost = self.dropped_through.saturating_sub(received);
+        (self.unacknowledged.iter().map(|(_, frame)| frame.clone()).collect(), lost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn numbered(session: &mut Session, count: u64) {
+        for nonce in 0..count {
+            session.number(Frame::Ping { nonce, received: 0 });
+        }
+    }
+
+    fn sequence_numbers(frames: &[Vec<u8>]) -> Vec<u64> {
+        frames.iter().map(|frame| match codec::decode::<Frame>(frame) {
+            Ok(Frame::Numbered { seq, .. }) => seq,
+            other => panic!("not a numbered frame: {:?}", other),
+        }).collect()
+    }
+
+    #[test]
+    fn replay_sends_what_the_peer_has_not_acknowledged() {
+        let mut session = Session::new();
+        numbered(&mut session, 5);
+
+        session.acknowledge(2);
+        let (frames, lost) = session.replay(3);
+        assert_eq!((sequence_numbers(&frames), lost), (vec![4, 5], 0));
+
+        // An acknowledgement older than the last one changes nothing.
+        session.acknowledge(1);
+        assert_eq!(sequence_numbers(&session.replay(0).0), vec![4, 5]);
+        session.acknowledge(5);
+        assert!(session.replay(5).0.is_empty());
+    }
+
+    #[test]
+    fn frames_dropped_from_a_full_replay_buffer_are_reported_lost() {
+        let mut session = Session::new();
+        let content = "x".repeat(MAX_REPLAY_BYTES / 4);
+        for _ in 0..6 {
+            session.number(Frame::Response(crate::enums::response::Response::Message { content: content.clone() }));
+        }
+        let (frames, lost) = session.replay(0);
+        assert_eq!(lost, 6 - frames.len() as u64);
+        assert!(lost > 0);
+        assert_eq!(sequence_numbers(&frames).last(), Some(&6));
+    }
+
+    #[test]
+    fn frames_arriving_twice_are_recognised() {
+        let mut session = Session::new();
+        assert!(session.receive(1));
+        assert!(session.receive(2));
+        assert!(!session.receive(2));
+        assert!(!session.receive(1));
+        assert_eq!(session.received(), 2);
+    }
+}
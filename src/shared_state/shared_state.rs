@@ -1,11 +1,40 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, oneshot, Mutex};
 use rsa::{RsaPrivateKey, RsaPublicKey};
+use crate::audit::audit_log::AuditLog;
+use crate::enums::response::Response;
+use crate::enums::sync::SyncReply;
+use crate::policy::capabilities::CapabilitySet;
+use crate::shared_state::forwards::ForwardTable;
+use crate::shared_state::jobs::JobTable;
+use crate::shared_state::link::LinkHealth;
+use crate::shared_state::session::Session;
+use crate::shared_state::transfers::TransferTable;
+use crate::transport::communication::WsSender;
 
 pub struct SharedState {
     pub server_public_key: Option<RsaPublicKey>,
     pub local_private_key: Option<RsaPrivateKey>,
     pub session_key: Option<Vec<u8>>,
+    pub jobs: JobTable,
+    pub transfers: TransferTable,
+    pub forwards: ForwardTable,
+    pub pending_sync_replies: HashMap<u32, mpsc::Sender<SyncReply>>,
+    // Answered by the next line typed into the CLI while a peer waits for approval.
+    pub pending_confirmation: Option<oneshot::Sender<bool>>,
+    // What the connected node advertised it allows us to do, unknown until it told us.
+    pub peer_capabilities: Option<CapabilitySet>,
+    pub audit_log: Option<AuditLog>,
+    // Set by `uplink exec`, which takes the command's result instead of having it printed.
+    pub pending_exec_result: Option<oneshot::Sender<Response>>,
+    // Health of the current connection, shown by STATUS and watched by the heartbeat.
+    pub link: Option<LinkHealth>,
+    // Survives reconnects so the peer can resume where the connection dropped.
+    pub session: Session,
+    // Set while the server shuts down, the peer may only finish the transfers it started.
+    pub draining: bool,
+    next_request_id: u32,
 }
 
 impl SharedState {
@@ -14,8 +43,70 @@ impl SharedState {
             local_private_key: None,
             server_public_key: None,
             session_key: None,
+            jobs: JobTable::new(),
+            transfers: TransferTable::new(),
+            forwards: ForwardTable::new(),
+            pending_sync_replies: HashMap::new(),
+            pending_confirmation: None,
+            peer_capabilities: None,
+            audit_log: None,
+            pending_exec_result: None,
+            link: None,
+            session: Session::new(),
+            draining: false,
+            next_request_id: 1,
         }
     }
+
+    /// Keys and advertisements belong to one connection, a new peer has to do its own handshake.
+    pub fn reset_session(&mut self) {
+        self.server_public_key = None;
+        self.local_private_key = None;
+        self.session_key = None;
+        self.peer_capabilities = None;
+        self.link = None;
+    }
+
+    /// Starts tracking a freshly connected peer. Jobs of the previous session have nobody to report to.
+    pub fn start_session(&mut self, link: LinkHealth) {
+        self.drop_session();
+        self.link = Some(link);
+    }
+
+    /// The peer ended the session carried over `ws_sender` for good, it is not kept for resuming.
+    pub fn close_session(&mut self, ws_sender: &WsSender) {
+        if self.session.connection().is_some_and(|connection| Arc::ptr_eq(&connection.ws_sender, ws_sender)) {
+            self.drop_session();
+            self.session = Session::new();
+        }
+    }
+
+    fn drop_session(&mut self) {
+        self.reset_session();
+        self.jobs.cancel_all();
+        self.transfers.forget_incoming();
+        self.forwards.end_session();
+    }
+
+    /// Continues the previous session over a new connection, keeping its keys and jobs.
+    pub fn resume_session(&mut self, link: LinkHealth) {
+        self.link = Some(link);
+    }
+
+    /// The connection over `ws_sender` ended. The session is kept for the peer to resume it, the
+    /// link is only dropped if no newer connection replaced it.
+    pub fn end_connection(&mut self, ws_sender: &WsSender) {
+        if self.session.connection().is_some_and(|connection| Arc::ptr_eq(&connection.ws_sender, ws_sender)) {
+            self.link = None;
+        }
+        self.session.detach(ws_sender);
+    }
+
+    pub fn next_request_id(&mut self) -> u32 {
+        let id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        id
+    }
 }
 
 pub type SharedStateHandle = Arc<Mutex<SharedState>>;
\ No newline at end of file
@@ -0,0 +1,231 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+use crate::enums::command::WriteOptions;
+
+const RENDER_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+pub enum TransferStatus {
+    Active,
+    Completed,
+    Failed(String),
+}
+
+pub struct Transfer {
+    pub direction: Direction,
+    pub source: String,
+    pub destination: String,
+    pub total: u64,
+    pub transferred: u64,
+    pub status: TransferStatus,
+    pub write_options: WriteOptions,
+    started: Instant,
+    finished: Option<Instant>,
+    last_render: Option<Instant>,
+}
+
+impl Transfer {
+    fn elapsed(&self) -> Duration {
+        self.finished.unwrap_or_else(Instant::now).duration_since(self.started)
+    }
+
+    fn rate(&self) -> f64 {
+        let secs = self.elapsed().as_secs_f64();
+        if secs > 0.0 { self.transferred as f64 / secs } else { 0.0 }
+    }
+
+    fn label(&self) -> String {
+        let verb = match self.direction {
+            Direction::Upload => "PUT",
+            Direction::Download => "GET",
+        };
+        format!("{} {} -> {}", verb, self.source, self.destination)
+    }
+
+    pub fn progress_line(&self) -> String {
+        if self.total == 0 && self.transferred == 0 {
+            return format!("[*] {}  waiting for the peer", self.label());
+        }
+
+        let percent = (self.transferred * 100).checked_div(self.total).unwrap_or(100);
+        let rate = self.rate();
+        let eta = if rate > 0.0 {
+            format_duration(Duration::from_secs_f64(self.total.saturating_sub(self.transferred) as f64 / rate))
+        } else {
+            "--:--".to_string()
+        };
+
+        format!(
+            "[*] {}  {} / {} ({}%)  {}/s  ETA {}",
+            self.label(), format_bytes(self.transferred), format_bytes(self.total), percent, format_bytes(rate as u64), eta
+        )
+    }
+
+    pub fn summary_line(&self) -> String {
+        match &self.status {
+            TransferStatus::Active => self.progress_line(),
+            TransferStatus::Completed => format!(
+                "[+] {}  {} in {} ({}/s)",
+                self.label(), format_bytes(self.transferred), format_duration(self.elapsed()), format_bytes(self.rate() as u64)
+            ),
+            TransferStatus::Failed(e) => format!(
+                "[!] {}  failed after {} / {}: {}",
+                self.label(), format_bytes(self.transferred), format_bytes(self.total), e
+            ),
+        }
+    }
+}
+
+/// Every GET/PUT/SYNC file transfer started by this node as operator, and the uploads the peer
+/// is still sending to it.
+pub struct TransferTable {
+    next_id: u32,
+    transfers: BTreeMap<u32, Transfer>,
+    incoming: BTreeSet<u32>,
+}
+
+impl TransferTable {
+    pub fn new() -> Self {
+        TransferTable {
+            next_id: 1,
+            transfers: BTreeMap::new(),
+            incoming: BTreeSet::new(),
+        }
+    }
+
+    pub fn start(&mut self, direction: Direction, source: &str, destination: &str, total: u64) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.transfers.insert(id, Transfer {
+            direction,
+            source: source.to_string(),
+            destination: destination.to_string(),
+            total,
+            transferred: 0,
+            status: TransferStatus::Active,
+            write_options: WriteOptions::default(),
+            started: Instant::now(),
+            finished: None,
+            last_render: None,
+        });
+        id
+    }
+
+    pub fn start_download(&mut self, source: &str, destination: &str, write_options: WriteOptions) -> u32 {
+        let id = self.start(Direction::Download, source, destination, 0);
+        if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.write_options = write_options;
+        }
+        id
+    }
+
+    /// Returns the download waiting for the chunk at `offset`. The peer only sends the
+    /// transfer id, where the data ends up on disk is decided when the download is started.
+    pub fn expected_download(&self, id: u32, offset: u64, total: u64) -> Result<&Transfer, String> {
+        let transfer = match self.transfers.get(&id) {
+            Some(transfer) if transfer.direction == Direction::Download => transfer,
+            _ => return Err("no such download was requested".to_string()),
+        };
+        if !matches!(transfer.status, TransferStatus::Active) {
+            return Err("the download is no longer active".to_string());
+        }
+        if offset != transfer.transferred || (offset > 0 && total != transfer.total) {
+            return Err(format!("unexpected chunk at offset {} of {}", offset, total));
+        }
+        Ok(transfer)
+    }
+
+    pub fn set_total(&mut self, id: u32, total: u64) {
+        if let Some(transfer) = self.transfers.get_mut(&id) {
+            transfer.total = total;
+        }
+    }
+
+    /// Records progress and returns a line to display, throttled so fast transfers do not flood the terminal.
+    pub fn update(&mut self, id: u32, transferred: u64) -> Option<String> {
+        let transfer = self.transfers.get_mut(&id)?;
+        if !matches!(transfer.status, TransferStatus::Active) {
+            return None;
+        }
+
+        transfer.transferred = transferred.min(transfer.total);
+        if transferred >= transfer.total {
+            transfer.status = TransferStatus::Completed;
+            transfer.finished = Some(Instant::now());
+            return Some(transfer.summary_line());
+        }
+
+        let now = Instant::now();
+        if transfer.last_render.is_some_and(|last| now.duration_since(last) < RENDER_INTERVAL) {
+            return None;
+        }
+        transfer.last_render = Some(now);
+        Some(transfer.progress_line())
+    }
+
+    pub fn fail(&mut self, id: u32, error: &str) -> Option<String> {
+        let transfer = self.transfers.get_mut(&id)?;
+        transfer.status = TransferStatus::Failed(error.to_string());
+        transfer.finished = Some(Instant::now());
+        Some(transfer.summary_line())
+    }
+
+    /// Tracks an upload from the peer, `finished` once its last chunk is written or it failed.
+    pub fn receive(&mut self, transfer_id: u32, finished: bool) {
+        if finished {
+            self.incoming.remove(&transfer_id);
+        } else {
+            self.incoming.insert(transfer_id);
+        }
+    }
+
+    /// Uploads from a peer that is gone will not be finished.
+    pub fn forget_incoming(&mut self) {
+        self.incoming.clear();
+    }
+
+    /// Transfers in either direction that have not finished yet.
+    pub fn in_flight(&self) -> usize {
+        self.incoming.len() + self.count(|status| matches!(status, TransferStatus::Active))
+    }
+
+    pub fn count(&self, filter: impl Fn(&TransferStatus) -> bool) -> usize {
+        self.transfers.values().filter(|transfer| filter(&transfer.status)).count()
+    }
+
+    pub fn list(&self) -> Vec<String> {
+        self.transfers
+            .iter()
+            .map(|(id, transfer)| format!("[{}] {}", id, transfer.summary_line()))
+            .collect()
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
@@ -1,21 +1,131 @@
-use futures_util::stream::SplitSink;
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+use futures_util::stream::{SplitSink, SplitStream};
 use tokio::net::TcpStream;
+use tokio::sync::Mutex;
 use tokio_tungstenite::WebSocketStream;
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, WebSocketConfig};
 use futures_util::sink::SinkExt;
-use crate::crypto::aes;
-use crate::transport::compression;
+use crate::crypto::aes::{self, ChannelKey};
+use crate::crypto::kdf::KeyDerivation;
+use crate::transport::compression::{self, CompressionSettings, Compressor};
 
-pub fn prepare_tx(data: Vec<u8>, passphrase: &str) -> Vec<u8> {
-    let compressed_data = compression::compress(&data);
-    let key = aes::derive_key(passphrase.as_bytes());
-    aes::encrypt(&compressed_data, &key)
+pub type WsSender = Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>;
+pub type WsReceiver = Arc<Mutex<SplitStream<WebSocketStream<TcpStream>>>>;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+// File chunks have to fit, smaller limits would break every transfer.
+const MIN_FRAME_LIMIT: usize = 1024 * 1024;
+// Longer close reasons do not fit in a WebSocket control frame.
+const MAX_CLOSE_REASON: usize = 123;
+
+/// How much a peer can make this node buffer for one message, before and after decompression.
+#[derive(Clone, Copy)]
+pub struct FrameLimits {
+    pub max_frame_size: usize,
+    pub max_decompressed_size: usize,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits { max_frame_size: DEFAULT_MAX_FRAME_SIZE, max_decompressed_size: DEFAULT_MAX_DECOMPRESSED_SIZE }
+    }
+}
+
+impl FrameLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_frame_size < MIN_FRAME_LIMIT || self.max_decompressed_size < MIN_FRAME_LIMIT {
+            return Err(format!("Frame size limits must be at least {} bytes", MIN_FRAME_LIMIT));
+        }
+        Ok(())
+    }
+
+    /// tungstenite refuses larger messages while reading them, before they are buffered whole.
+    pub fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig {
+            max_message_size: Some(self.max_frame_size),
+            max_frame_size: Some(self.max_frame_size),
+            ..Default::default()
+        }
+    }
+}
+
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How often to ping the peer, how long it may stay silent before the connection is given up,
+/// and how long a session may go without commands or responses. Zero disables either.
+#[derive(Clone, Copy)]
+pub struct HeartbeatSettings {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for HeartbeatSettings {
+    fn default() -> Self {
+        HeartbeatSettings { interval: DEFAULT_HEARTBEAT_INTERVAL, timeout: DEFAULT_HEARTBEAT_TIMEOUT, idle_timeout: None }
+    }
 }
 
-pub fn prepare_rx(data: Vec<u8>, passphrase: &str) -> Vec<u8> {
-    let key = aes::derive_key(passphrase.as_bytes());
-    let decrypted_data = aes::decrypt(&data, &key).unwrap();
-    compression::decompress(&decrypted_data)
+impl HeartbeatSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.interval.is_zero() && self.timeout <= self.interval {
+            return Err("The heartbeat timeout has to be longer than the heartbeat interval".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// What a node brings to every connection it accepts or opens.
+pub struct ConnectionSettings {
+    pub key_derivation: KeyDerivation,
+    pub no_envelope: bool,
+    pub compression: CompressionSettings,
+    pub limits: FrameLimits,
+    pub heartbeat: HeartbeatSettings,
+}
+
+impl ConnectionSettings {
+    /// The channel of a connection whose hello produced `key`, `peer_compression` is what the peer accepts.
+    pub fn channel(&self, key: ChannelKey, peer_compression: &[String]) -> Channel {
+        Channel {
+            key,
+            compressor: self.compression.negotiate(peer_compression),
+            max_decompressed_size: self.limits.max_decompressed_size,
+        }
+    }
+}
+
+/// The outer layer of one connection: the key derived in the hello and the compression negotiated there.
+#[derive(Clone, Copy)]
+pub struct Channel {
+    pub key: ChannelKey,
+    pub compressor: Compressor,
+    pub max_decompressed_size: usize,
+}
+
+pub fn prepare_tx(data: Vec<u8>, channel: &Channel) -> Vec<u8> {
+    let compressed_data = channel.compressor.compress(&data);
+    aes::encrypt(&compressed_data, &channel.key)
+}
+
+pub fn prepare_rx(data: Vec<u8>, channel: &Channel) -> Result<Vec<u8>, String> {
+    let decrypted_data = aes::decrypt(&data, &channel.key)
+        .map_err(|_| "Failed to decrypt a frame, the peer uses another passphrase or the data was altered".to_string())?;
+    compression::decompress(&decrypted_data, channel.max_decompressed_size)
+}
+
+/// A close frame telling the peer why, cut to what fits in a control frame.
+pub fn close_frame(code: CloseCode, reason: &str) -> CloseFrame<'static> {
+    let mut end = reason.len().min(MAX_CLOSE_REASON);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    CloseFrame { code, reason: Cow::Owned(reason[..end].to_string()) }
 }
 
 pub async fn send_binary_data(
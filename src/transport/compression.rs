@@ -1,17 +1,228 @@
-use flate2::write::GzEncoder;
-use flate2::Compression;
-use flate2::read::GzDecoder;
+use std::fmt;
 use std::io::prelude::*;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+
+/// Frames smaller than this are sent uncompressed by default, the headers would eat the gain.
+pub const DEFAULT_THRESHOLD: usize = 256;
+
+// Shannon entropy, in bits per byte, above which a payload is taken to be compressed or
+// encrypted already. Measured on a sample, so large payloads cost little to check.
+const MAX_ENTROPY: f64 = 7.5;
+const ENTROPY_SAMPLE: usize = 4096;
+
+/// A compression method, tagged on every frame so the receiver knows how to unpack it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+pub const ALL: [Compression; 4] = [Compression::None, Compression::Gzip, Compression::Zstd, Compression::Lz4];
+
+/// Used when nothing is configured, in order of preference.
+pub const DEFAULT_METHODS: [Compression; 3] = [Compression::Zstd, Compression::Lz4, Compression::Gzip];
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Compression> {
+        ALL.iter().copied().find(|method| method.name() == name)
+    }
+
+    fn tag(&self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip => 1,
+            Compression::Zstd => 2,
+            Compression::Lz4 => 3,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Compression> {
+        ALL.iter().copied().find(|method| method.tag() == tag)
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// What a node offers in its hello: the methods it accepts, most preferred first, and the size
+/// below which it does not bother compressing.
+#[derive(Clone)]
+pub struct CompressionSettings {
+    pub methods: Vec<Compression>,
+    pub threshold: usize,
+}
+
+impl Default for CompressionSettings {
+    fn default() -> Self {
+        CompressionSettings { methods: DEFAULT_METHODS.to_vec(), threshold: DEFAULT_THRESHOLD }
+    }
+}
 
-pub fn compress(data: &[u8]) -> Vec<u8> {
-    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
-    encoder.write_all(data).expect("Compression failed");
-    encoder.finish().expect("Compression failed")
+impl CompressionSettings {
+    /// The method used for what this node sends: its first preference the peer also accepts.
+    /// Each side picks for itself, frames carry their method, so the two need not agree.
+    pub fn negotiate(&self, peer_methods: &[String]) -> Compressor {
+        let method = self.methods.iter().copied()
+            .find(|method| peer_methods.iter().any(|name| name == method.name()))
+            .unwrap_or(Compression::None);
+        Compressor { method, threshold: self.threshold }
+    }
+
+    /// Names advertised in the hello. Uncompressed frames are always understood.
+    pub fn advertised(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.methods.iter().map(|method| method.name().to_string()).collect();
+        if !self.methods.contains(&Compression::None) {
+            names.push(Compression::None.name().to_string());
+        }
+        names
+    }
+}
+
+/// The negotiated compression of one session.
+#[derive(Clone, Copy)]
+pub struct Compressor {
+    method: Compression,
+    threshold: usize,
 }
 
-pub fn decompress(data: &[u8]) -> Vec<u8> {
-    let mut decoder = GzDecoder::new(data);
+impl Compressor {
+    pub fn method(&self) -> Compression {
+        self.method
+    }
+
+    /// Prefixes the frame with the method it was packed with. Small and incompressible payloads,
+    /// and any that would not shrink, are sent as they are.
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let method = if data.len() < self.threshold || looks_incompressible(data) { Compression::None } else { self.method };
+
+        let compressed = match method {
+            Compression::None => None,
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(vec![method.tag()], flate2::Compression::default());
+                encoder.write_all(data).expect("Compression failed");
+                Some(encoder.finish().expect("Compression failed"))
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(vec![method.tag()], zstd::DEFAULT_COMPRESSION_LEVEL).expect("Compression failed");
+                encoder.write_all(data).expect("Compression failed");
+                Some(encoder.finish().expect("Compression failed"))
+            }
+            Compression::Lz4 => {
+                let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![method.tag()]);
+                encoder.write_all(data).expect("Compression failed");
+                Some(encoder.finish().expect("Compression failed"))
+            }
+        };
+
+        match compressed {
+            Some(compressed) if compressed.len() <= data.len() => compressed,
+            _ => {
+                let mut raw = Vec::with_capacity(data.len() + 1);
+                raw.push(Compression::None.tag());
+                raw.extend_from_slice(data);
+                raw
+            }
+        }
+    }
+}
+
+/// Unpacks a frame from `Compressor::compress`, whatever method the peer chose for it. Stops
+/// reading once the output exceeds `limit`, so a small frame cannot inflate without bound.
+pub fn decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, String> {
+    let (&tag, payload) = data.split_first().ok_or_else(|| "Empty frame".to_string())?;
+    let method = Compression::from_tag(tag).ok_or_else(|| format!("Unknown compression method {}", tag))?;
+
+    let decoder: Box<dyn Read + '_> = match method {
+        Compression::None => Box::new(payload),
+        Compression::Gzip => Box::new(GzDecoder::new(payload)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(payload).map_err(|e| format!("Failed to decompress {} frame: {}", method, e))?),
+        Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(payload)),
+    };
+
     let mut decompressed_data = Vec::new();
-    decoder.read_to_end(&mut decompressed_data).expect("Decompression failed");
-    decompressed_data
+    decoder.take(limit as u64 + 1)
+        .read_to_end(&mut decompressed_data)
+        .map_err(|e| format!("Failed to decompress {} frame: {}", method, e))?;
+    if decompressed_data.len() > limit {
+        return Err(format!("{} frame decompresses to more than {} bytes", method, limit));
+    }
+    Ok(decompressed_data)
+}
+
+pub fn parse_method(name: &str) -> Result<Compression, String> {
+    Compression::from_name(name.trim()).ok_or_else(|| format!(
+        "Unknown compression: {} (expected one of {})",
+        name,
+        ALL.iter().map(Compression::name).collect::<Vec<_>>().join(", ")
+    ))
+}
+
+fn looks_incompressible(data: &[u8]) -> bool {
+    let sample = &data[..data.len().min(ENTROPY_SAMPLE)];
+    let mut counts = [0usize; 256];
+    for &byte in sample {
+        counts[byte as usize] += 1;
+    }
+
+    let length = sample.len() as f64;
+    let entropy: f64 = counts.iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let probability = count as f64 / length;
+            -probability * probability.log2()
+        })
+        .sum();
+    entropy > MAX_ENTROPY
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressor(method: Compression) -> Compressor {
+        Compressor { method, threshold: DEFAULT_THRESHOLD }
+    }
+
+    #[test]
+    fn every_method_round_trips() {
+        let data = b"uplink ".repeat(1000);
+        for method in ALL {
+            let compressed = compressor(method).compress(&data);
+            assert_eq!(decompress(&compressed, data.len()).unwrap(), data, "{}", method);
+        }
+    }
+
+    #[test]
+    fn a_frame_inflating_beyond_the_limit_is_refused() {
+        let bomb = vec![0u8; 8 * 1024 * 1024];
+        for method in [Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let compressed = compressor(method).compress(&bomb);
+            assert!(compressed.len() < 64 * 1024, "{} barely compressed zeros", method);
+            assert!(decompress(&compressed, 1024 * 1024).unwrap_err().contains("more than"), "{}", method);
+        }
+        assert!(decompress(&compressor(Compression::None).compress(&bomb), bomb.len() - 1).is_err());
+    }
+
+    #[test]
+    fn unknown_methods_and_empty_frames_are_refused() {
+        assert!(decompress(&[9, 1, 2, 3], 1024).unwrap_err().contains("Unknown compression"));
+        assert!(decompress(&[], 1024).is_err());
+    }
 }
@@ -0,0 +1,160 @@
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::WebSocketStream;
+use crate::crypto::kdf::{self, KdfAnnouncement, KeyDerivation};
+use crate::transport::codec;
+use crate::transport::communication::{self, Channel, ConnectionSettings};
+use crate::transport::compression::CompressionSettings;
+
+/// Bumped on changes a peer cannot cope with by ignoring unknown fields or negotiating.
+/// 2: frames carry their compression method.
+/// 3: commands and responses are numbered and sessions can be resumed.
+pub const PROTOCOL_VERSION: u32 = 3;
+pub const CIPHER: &str = "aes-256-gcm";
+pub const FEATURE_ENVELOPE: &str = "envelope";
+pub const FEATURE_HEARTBEAT: &str = "heartbeat";
+pub const FEATURE_FORWARDING: &str = "forwarding";
+
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Exchanged in the clear right after the WebSocket upgrade, before the channel key exists, so
+/// a peer with another version or another passphrase setup is told why instead of failing to
+/// decrypt. Capability grants are only sent once the channel is encrypted.
+#[derive(Serialize, Deserialize)]
+pub struct Hello {
+    pub protocol_version: u32,
+    pub build_version: String,
+    pub ciphers: Vec<String>,
+    /// Compression methods this node accepts, most preferred first.
+    pub compression: Vec<String>,
+    pub codecs: Vec<u8>,
+    /// Optional protocol features this node has enabled, e.g. "envelope" or "heartbeat".
+    pub features: Vec<String>,
+    /// Salt and costs of the channel key, sent by the accepting node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kdf: Option<KdfAnnouncement>,
+}
+
+impl Hello {
+    pub fn new(no_envelope: bool, compression: &CompressionSettings) -> Self {
+        let mut features = vec![FEATURE_HEARTBEAT.to_string(), FEATURE_FORWARDING.to_string()];
+        if !no_envelope {
+            features.push(FEATURE_ENVELOPE.to_string());
+        }
+
+        Hello {
+            protocol_version: PROTOCOL_VERSION,
+            build_version: env!("CARGO_PKG_VERSION").to_string(),
+            ciphers: vec![CIPHER.to_string()],
+            compression: compression.advertised(),
+            codecs: vec![codec::WIRE_VERSION],
+            features,
+            kdf: None,
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|enabled| enabled == feature)
+    }
+
+    /// Whether the two nodes can talk. The reason names both sides, it is shown on each of them.
+    fn check(&self, peer: &Hello) -> Result<(), String> {
+        if peer.protocol_version != self.protocol_version {
+            return Err(format!(
+                "Incompatible versions: uplink {} speaks protocol {}, uplink {} speaks protocol {}",
+                peer.build_version, peer.protocol_version, self.build_version, self.protocol_version
+            ));
+        }
+        if !self.ciphers.iter().any(|cipher| peer.ciphers.contains(cipher)) {
+            return Err(format!("No common cipher: {} and {}", peer.ciphers.join(", "), self.ciphers.join(", ")));
+        }
+        if !self.codecs.iter().any(|version| peer.codecs.contains(version)) {
+            return Err(format!("No common wire format: versions {:?} and {:?}", peer.codecs, self.codecs));
+        }
+        if peer.has_feature(FEATURE_ENVELOPE) != self.has_feature(FEATURE_ENVELOPE) {
+            return Err("One node runs with --no-envelope and the other does not".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Accepting side: sends its hello with a fresh salt for this connection, checks the peer's
+/// answer and derives the channel key. Returns the channel and the peer's hello.
+pub async fn accept(ws_stream: &mut WebSocketStream<TcpStream>, settings: &ConnectionSettings) -> Result<(Channel, Hello), String> {
+    let announcement = settings.key_derivation.announce();
+    let (salt, params) = (announcement.salt.clone(), announcement.params);
+    let local = Hello { kdf: Some(announcement), ..Hello::new(settings.no_envelope, &settings.compression) };
+    send_hello(ws_stream, &local).await?;
+
+    let peer = receive_hello(ws_stream).await?;
+    if let Err(reason) = local.check(&peer) {
+        return Err(refuse(ws_stream, reason).await);
+    }
+
+    let key = settings.key_derivation.derive(&salt, params).await?;
+    Ok((settings.channel(key, &peer.compression), peer))
+}
+
+/// Connecting side: checks the peer's hello and its key derivation parameters, refusing costs
+/// weaker than its own, answers with its own hello and derives the same channel key.
+pub async fn connect(ws_stream: &mut WebSocketStream<TcpStream>, settings: &ConnectionSettings) -> Result<(Channel, Hello), String> {
+    let local = Hello::new(settings.no_envelope, &settings.compression);
+    let peer = receive_hello(ws_stream).await?;
+
+    let announcement = match local.check(&peer).and_then(|_| check_announcement(&peer, &settings.key_derivation)) {
+        Ok(announcement) => announcement,
+        Err(reason) => return Err(refuse(ws_stream, reason).await),
+    };
+    send_hello(ws_stream, &local).await?;
+
+    let key = settings.key_derivation.derive(&announcement.salt, announcement.params).await?;
+    Ok((settings.channel(key, &peer.compression), peer))
+}
+
+fn check_announcement<'a>(peer: &'a Hello, key_derivation: &KeyDerivation) -> Result<&'a KdfAnnouncement, String> {
+    let announcement = peer.kdf.as_ref()
+        .ok_or_else(|| "The accepting node sent no key derivation parameters".to_string())?;
+
+    if announcement.algorithm != kdf::ALGORITHM {
+        return Err(format!("Unsupported key derivation {}", announcement.algorithm));
+    }
+    if announcement.salt.len() != kdf::SALT_LENGTH {
+        return Err(format!("Key derivation salt of {} bytes, expected {}", announcement.salt.len(), kdf::SALT_LENGTH));
+    }
+    announcement.params.check_at_least(&key_derivation.params())?;
+    Ok(announcement)
+}
+
+async fn send_hello(ws_stream: &mut WebSocketStream<TcpStream>, hello: &Hello) -> Result<(), String> {
+    let serialized = serde_json::to_vec(hello).map_err(|e| e.to_string())?;
+    ws_stream.send(Message::Binary(serialized))
+        .await
+        .map_err(|e| format!("Failed to send hello: {}", e))
+}
+
+async fn receive_hello(ws_stream: &mut WebSocketStream<TcpStream>) -> Result<Hello, String> {
+    let message = timeout(HELLO_TIMEOUT, ws_stream.next())
+        .await
+        .map_err(|_| "Timed out waiting for the peer's hello".to_string())?
+        .ok_or_else(|| "Connection closed before the peer's hello arrived".to_string())?
+        .map_err(|e| format!("Failed to receive the peer's hello: {}", e))?;
+
+    match message {
+        Message::Binary(data) => serde_json::from_slice(&data)
+            .map_err(|e| format!("Invalid hello from the peer, is it an uplink node? {}", e)),
+        Message::Close(Some(frame)) => Err(format!("Peer refused the connection: {}", frame.reason)),
+        Message::Close(None) => Err("Peer closed the connection during the hello".to_string()),
+        _ => Err("Expected a hello from the peer".to_string()),
+    }
+}
+
+/// Tells the peer why the connection is refused before closing it, returns the reason.
+async fn refuse(ws_stream: &mut WebSocketStream<TcpStream>, reason: String) -> String {
+    let _ = ws_stream.close(Some(communication::close_frame(CloseCode::Policy, &reason))).await;
+    reason
+}
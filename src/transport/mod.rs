@@ -1,2 +1,5 @@
+pub mod codec;
 pub mod communication;
-pub mod compression;
\ No newline at end of file
+pub mod compression;
+pub mod hello;
+pub mod resume;
\ No newline at end of file
@@ -0,0 +1,301 @@
+use std::sync::Arc;
+use futures_util::sink::SinkExt;
+use futures_util::stream::{SplitSink, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio_tungstenite::WebSocketStream;
+use tracing::{info, warn};
+use crate::shared_state::link::LinkHealth;
+use crate::shared_state::session::Connection;
+use crate::shared_state::shared_state::SharedStateHandle;
+use crate::transport::codec;
+use crate::transport::communication::{self, Channel, WsReceiver, WsSender};
+
+const RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+const SESSION_IN_USE: &str = "Another peer's session is in progress, try again once it ended";
+
+/// First frame on the encrypted channel, sent by the connecting node: the ticket of the session
+/// it wants to continue, empty for a new one, and the last numbered frame of it that arrived.
+#[derive(Serialize, Deserialize)]
+struct ResumeRequest {
+    #[serde(with = "serde_bytes")]
+    ticket: Vec<u8>,
+    received: u64,
+}
+
+/// The accepting node's answer: the ticket of the session carried from now on, whether it is
+/// the one asked for, and the last numbered frame of it that arrived.
+#[derive(Serialize, Deserialize)]
+struct ResumeReply {
+    #[serde(with = "serde_bytes")]
+    ticket: Vec<u8>,
+    resumed: bool,
+    received: u64,
+}
+
+/// Accepting side: continues the session the peer holds a ticket for if it is still kept, or
+/// starts a new one, and replays what the peer missed. Returns whether the session was resumed.
+/// There is one session at a time, a peer without its ticket is refused while it is carried over
+/// another connection or can still be resumed.
+pub async fn accept(
+    ws_sender: &WsSender,
+    ws_receiver: &WsReceiver,
+    channel: Channel,
+    link: LinkHealth,
+    shared_state: &SharedStateHandle,
+) -> Result<bool, String> {
+    let request: ResumeRequest = match receive(ws_receiver, &channel).await {
+        Ok(request) => request,
+        Err(e) => {
+            let frame = communication::close_frame(CloseCode::Protocol, &e);
+            let _ = ws_sender.lock().await.send(Message::Close(Some(frame))).await;
+            return Err(e);
+        }
+    };
+
+    // Held until the replay is out, so nothing sent meanwhile overtakes it.
+    let mut sender = ws_sender.lock().await;
+    let setup = {
+        let mut shared_state = shared_state.lock().await;
+        let resumed = !request.ticket.is_empty() && shared_state.session.can_resume(&request.ticket);
+        if !resumed && shared_state.session.resumable() {
+            None
+        } else {
+            let (ticket, replay) = if resumed {
+                shared_state.resume_session(link);
+                (request.ticket, shared_state.session.replay(request.received))
+            } else {
+                shared_state.start_session(link);
+                (shared_state.session.issue_ticket(), (Vec::new(), 0))
+            };
+            shared_state.session.attach(Connection { channel, ws_sender: Arc::clone(ws_sender) });
+            Some((ResumeReply { ticket, resumed, received: shared_state.session.received() }, replay))
+        }
+    };
+    let Some((reply, replay)) = setup else {
+        let frame = communication::close_frame(CloseCode::Again, SESSION_IN_USE);
+        let _ = sender.send(Message::Close(Some(frame))).await;
+        return Err(SESSION_IN_USE.to_string());
+    };
+
+    send(&mut sender, codec::encode(&reply), &channel).await?;
+    if reply.resumed {
+        send_replay(&mut sender, replay, &channel).await?;
+    }
+    Ok(reply.resumed)
+}
+
+/// Connecting side: asks to continue the session this node holds a ticket for, replays what the
+/// peer missed if it agrees, and otherwise starts over with the new ticket. Returns whether the
+/// session was resumed.
+pub async fn connect(
+    ws_sender: &WsSender,
+    ws_receiver: &WsReceiver,
+    channel: Channel,
+    link: LinkHealth,
+    shared_state: &SharedStateHandle,
+) -> Result<bool, String> {
+    let request = {
+        let shared_state = shared_state.lock().await;
+        ResumeRequest {
+            ticket: shared_state.session.ticket().map_or_else(Vec::new, <[u8]>::to_vec),
+            received: shared_state.session.received(),
+        }
+    };
+    send(&mut *ws_sender.lock().await, codec::encode(&request), &channel).await?;
+    let reply: ResumeReply = receive(ws_receiver, &channel).await?;
+
+    let mut sender = ws_sender.lock().await;
+    let replay = {
+        let mut shared_state = shared_state.lock().await;
+        let replay = if reply.resumed && !request.ticket.is_empty() {
+            shared_state.resume_session(link);
+            Some(shared_state.session.replay(reply.received))
+        } else {
+            if !request.ticket.is_empty() {
+                warn!("The peer no longer keeps the previous session, starting a new one");
+            }
+            shared_state.start_session(link);
+            shared_state.session.adopt_ticket(reply.ticket);
+            None
+        };
+        shared_state.session.attach(Connection { channel, ws_sender: Arc::clone(ws_sender) });
+        replay
+    };
+
+    match replay {
+        Some(replay) => {
+            send_replay(&mut sender, replay, &channel).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+async fn send_replay(
+    sender: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
+    (frames, lost): (Vec<Vec<u8>>, u64),
+    channel: &Channel,
+) -> Result<(), String> {
+    info!("Replaying {} frame(s) the peer missed", frames.len());
+    if lost > 0 {
+        warn!("{} frame(s) the peer missed no longer fit the replay buffer and are lost", lost);
+    }
+    for frame in frames {
+        send(sender, frame, channel).await?;
+    }
+    Ok(())
+}
+
+async fn send(
+    sender: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
+    data: Vec<u8>,
+    channel: &Channel,
+) -> Result<(), String> {
+    communication::send_binary_data(sender, communication::prepare_tx(data, channel))
+        .await
+        .map_err(|e| format!("Failed to resume the session: {}", e))
+}
+
+async fn receive<T: DeserializeOwned>(ws_receiver: &WsReceiver, channel: &Channel) -> Result<T, String> {
+    let message = timeout(RESUME_TIMEOUT, ws_receiver.lock().await.next())
+        .await
+        .map_err(|_| "Timed out waiting for the peer to set up the session".to_string())?
+        .ok_or_else(|| "Connection closed while setting up the session".to_string())?
+        .map_err(|e| format!("Failed to set up the session: {}", e))?;
+
+    match message {
+        Message::Binary(data) => codec::decode(&communication::prepare_rx(data, channel)?),
+        Message::Close(Some(frame)) => Err(format!("Peer refused the connection: {}", frame.reason)),
+        Message::Close(None) => Err("Peer closed the connection while setting up the session".to_string()),
+        _ => Err("Expected the session setup from the peer".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::Mutex;
+    use tokio_tungstenite::{accept_async, client_async};
+    use crate::enums::response::Response;
+    use crate::handlers::heartbeat;
+    use crate::handlers::response_sender::ResponseSender;
+    use crate::shared_state::shared_state::SharedState;
+    use crate::transport::codec::Frame;
+    use crate::transport::communication::HeartbeatSettings;
+    use crate::transport::compression::CompressionSettings;
+    use crate::transport::hello::Hello;
+
+    struct Peer {
+        ws_sender: WsSender,
+        ws_receiver: WsReceiver,
+        channel: Channel,
+        shared_state: SharedStateHandle,
+    }
+
+    fn split(ws_stream: WebSocketStream<TcpStream>, channel: Channel, shared_state: SharedStateHandle) -> Peer {
+        let (ws_sender, ws_receiver) = ws_stream.split();
+        Peer { ws_sender: Arc::new(Mutex::new(ws_sender)), ws_receiver: Arc::new(Mutex::new(ws_receiver)), channel, shared_state }
+    }
+
+    // Connects a new client to the server's shared state and sets up its session on both ends.
+    async fn connect_peer(listener: &TcpListener, server_state: &SharedStateHandle, key: u8) -> (Peer, Peer, Result<bool, String>, Result<bool, String>) {
+        connect_client(listener, server_state, &Arc::new(Mutex::new(SharedState::new())), key).await
+    }
+
+    // Connects a client with the given state, offering the ticket of the session it holds if any.
+    async fn connect_client(
+        listener: &TcpListener,
+        server_state: &SharedStateHandle,
+        client_state: &SharedStateHandle,
+        key: u8,
+    ) -> (Peer, Peer, Result<bool, String>, Result<bool, String>) {
+        let address = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(
+            async { client_async(format!("ws://{}", address), TcpStream::connect(address).await.unwrap()).await.unwrap().0 },
+            async { accept_async(listener.accept().await.unwrap().0).await.unwrap() },
+        );
+        let channel = Channel { key: [key; 32], compressor: CompressionSettings::default().negotiate(&[]), max_decompressed_size: 1024 * 1024 };
+        let server = split(server, channel, Arc::clone(server_state));
+        let client = split(client, channel, Arc::clone(client_state));
+
+        let hello = Hello::new(false, &CompressionSettings::default());
+        let (accepted, connected) = tokio::join!(
+            accept(&server.ws_sender, &server.ws_receiver, channel, LinkHealth::new("client".to_string(), &hello), &server.shared_state),
+            connect(&client.ws_sender, &client.ws_receiver, channel, LinkHealth::new("server".to_string(), &hello), &client.shared_state),
+        );
+        (server, client, accepted, connected)
+    }
+
+    // Everything the client receives until the connection closes or stays quiet.
+    async fn received_responses(client: &Peer) -> Vec<String> {
+        let mut responses = Vec::new();
+        while let Ok(Some(Ok(message))) = timeout(Duration::from_millis(300), client.ws_receiver.lock().await.next()).await {
+            let Message::Binary(data) = message else { continue };
+            let frame = codec::decode(&communication::prepare_rx(data, &client.channel).unwrap()).unwrap();
+            if let Frame::Numbered { frame, .. } = frame {
+                if let Frame::Response(Response::Message { content }) = *frame {
+                    responses.push(content);
+                }
+            }
+        }
+        responses
+    }
+
+    #[tokio::test]
+    async fn a_second_peer_is_refused_and_never_sees_the_first_ones_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_state: SharedStateHandle = Arc::new(Mutex::new(SharedState::new()));
+
+        let (first_server, first, accepted, connected) = connect_peer(&listener, &server_state, 1).await;
+        assert_eq!((accepted, connected), (Ok(false), Ok(false)));
+
+        let (second_server, second, accepted, connected) = connect_peer(&listener, &server_state, 2).await;
+        assert_eq!(accepted, Err(SESSION_IN_USE.to_string()));
+        assert!(connected.unwrap_err().contains(SESSION_IN_USE));
+        assert!(server_state.lock().await.session.is_current(&first_server.ws_sender));
+
+        assert!(ResponseSender::for_session(Arc::clone(&server_state)).send(Response::Message { content: "for the first".to_string() }).await);
+        assert_eq!(received_responses(&first).await, vec!["for the first".to_string()]);
+        assert!(received_responses(&second).await.is_empty());
+        drop(second_server);
+
+        // Once the first peer ended its session the next one is admitted.
+        server_state.lock().await.close_session(&first_server.ws_sender);
+        let (_, _, accepted, connected) = connect_peer(&listener, &server_state, 3).await;
+        assert_eq!((accepted, connected), (Ok(false), Ok(false)));
+    }
+
+    #[tokio::test]
+    async fn an_idle_session_is_closed_normally_and_cannot_be_resumed() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_state: SharedStateHandle = Arc::new(Mutex::new(SharedState::new()));
+        let client_state: SharedStateHandle = Arc::new(Mutex::new(SharedState::new()));
+
+        let (server, client, accepted, connected) = connect_client(&listener, &server_state, &client_state, 1).await;
+        assert_eq!((accepted, connected), (Ok(false), Ok(false)));
+
+        let response_sender = ResponseSender::new(server.channel, Some(Arc::clone(&server.ws_sender)), Arc::clone(&server_state));
+        let settings = HeartbeatSettings { idle_timeout: Some(Duration::ZERO), ..HeartbeatSettings::default() };
+        let (code, reason) = heartbeat::monitor(response_sender.clone(), settings).await;
+        assert_eq!(code, CloseCode::Normal);
+        assert!(!server_state.lock().await.session.resumable());
+
+        // The client sees a normal close, which ends the session on its side as well.
+        response_sender.close(code, &reason).await;
+        let message = timeout(Duration::from_secs(5), client.ws_receiver.lock().await.next()).await.unwrap();
+        assert!(matches!(message, Some(Ok(Message::Close(Some(frame)))) if frame.code == CloseCode::Normal));
+        server_state.lock().await.end_connection(&server.ws_sender);
+        client_state.lock().await.end_connection(&client.ws_sender);
+
+        // Even a client still holding the ticket gets a new session.
+        assert!(client_state.lock().await.session.resumable());
+        let (_, _, accepted, connected) = connect_client(&listener, &server_state, &client_state, 2).await;
+        assert_eq!((accepted, connected), (Ok(false), Ok(false)));
+    }
+}
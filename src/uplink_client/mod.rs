@@ -1 +1,2 @@
+pub mod reconnect;
 pub mod uplink_client;
\ No newline at end of file
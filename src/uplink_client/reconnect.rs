@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+use rand::Rng;
+
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+const MULTIPLIER: u32 = 2;
+// Each delay is randomly shortened or stretched by up to this fraction, so clients that lost the
+// same server do not all come back at the same moment.
+const JITTER: f64 = 0.2;
+
+/// How a client gets back to its server: the addresses to try in order, how long to wait between
+/// rounds and when to give up.
+#[derive(Clone)]
+pub struct ReconnectSettings {
+    pub fallback_addresses: Vec<String>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Failed rounds over all addresses before giving up, None retries forever.
+    pub max_retries: Option<u32>,
+    /// Time without a connection before giving up, None waits forever.
+    pub deadline: Option<Duration>,
+    /// Exit when an established connection ends instead of reconnecting.
+    pub exit_on_disconnect: bool,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        ReconnectSettings {
+            fallback_addresses: Vec::new(),
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            max_retries: None,
+            deadline: None,
+            exit_on_disconnect: false,
+        }
+    }
+}
+
+impl ReconnectSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.initial_delay.is_zero() || self.max_delay < self.initial_delay {
+            return Err("The reconnect delay has to be positive and at most the maximum reconnect delay".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Exponential backoff with jitter, reset whenever a connection is established.
+pub struct Backoff {
+    settings: ReconnectSettings,
+    failures: u32,
+    disconnected_since: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn new(settings: ReconnectSettings) -> Self {
+        Backoff { settings, failures: 0, disconnected_since: None }
+    }
+
+    pub fn connected(&mut self) {
+        self.failures = 0;
+        self.disconnected_since = None;
+    }
+
+    /// The wait before the next round, or why to give up.
+    pub fn next_delay(&mut self) -> Result<Duration, String> {
+        let disconnected_since = *self.disconnected_since.get_or_insert_with(Instant::now);
+        if self.settings.max_retries.is_some_and(|max_retries| self.failures >= max_retries) {
+            return Err(format!("Giving up after {} failed reconnect attempt(s)", self.failures));
+        }
+        if let Some(deadline) = self.settings.deadline {
+            if disconnected_since.elapsed() >= deadline {
+                return Err(format!("Giving up, no connection for {} seconds", deadline.as_secs()));
+            }
+        }
+
+        let base = self.settings.initial_delay
+            .saturating_mul(MULTIPLIER.saturating_pow(self.failures))
+            .min(self.settings.max_delay);
+        self.failures += 1;
+        let factor = rand::thread_rng().gen_range(1.0 - JITTER..=1.0 + JITTER);
+        Ok(base.mul_f64(factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> ReconnectSettings {
+        ReconnectSettings { initial_delay: Duration::from_secs(1), max_delay: Duration::from_secs(10), ..ReconnectSettings::default() }
+    }
+
+    fn within_jitter(delay: Duration, base: Duration) -> bool {
+        delay >= base.mul_f64(1.0 - JITTER) && delay <= base.mul_f64(1.0 + JITTER)
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum_and_start_over_once_connected() {
+        let mut backoff = Backoff::new(settings());
+        for base in [1, 2, 4, 8, 10, 10] {
+            assert!(within_jitter(backoff.next_delay().unwrap(), Duration::from_secs(base)));
+        }
+        // Far beyond the point where the doubling would overflow.
+        backoff.failures = 200;
+        assert!(within_jitter(backoff.next_delay().unwrap(), Duration::from_secs(10)));
+
+        backoff.connected();
+        assert!(within_jitter(backoff.next_delay().unwrap(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn gives_up_after_the_retries_or_the_deadline() {
+        let mut backoff = Backoff::new(ReconnectSettings { max_retries: Some(2), ..settings() });
+        assert!(backoff.next_delay().is_ok());
+        assert!(backoff.next_delay().is_ok());
+        assert!(backoff.next_delay().unwrap_err().contains("after 2 failed"));
+
+        let mut backoff = Backoff::new(ReconnectSettings { deadline: Some(Duration::ZERO), ..settings() });
+        assert!(backoff.next_delay().unwrap_err().contains("no connection"));
+    }
+}
@@ -1,101 +1,217 @@
 use tokio::net::TcpStream;
-use tokio_tungstenite::client_async;
+use tracing::{info, warn};
+use tokio_tungstenite::client_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use futures_util::stream::StreamExt;
 use std::sync::Arc;
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::Mutex;
 use tokio::time::{sleep, Duration};
+use crate::uplink_client::reconnect::{Backoff, ReconnectSettings};
+use crate::handlers::heartbeat;
+use crate::handlers::response_sender::ResponseSender;
 use crate::handlers::rx_command_handler::RxCommandHandler;
 use crate::handlers::tx_command_handler::TxCommandHandler;
 use crate::handlers::cli_handler::handle_cli;
+use crate::enums::response::Response;
+use crate::policy::policy::Policy;
+use crate::shared_state::link::LinkHealth;
 use crate::shared_state::shared_state::SharedStateHandle;
+use crate::transport::communication::{Channel, ConnectionSettings, WsReceiver, WsSender};
+use crate::transport::{hello, resume};
+use crate::uplink_server::shutdown;
 
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// A normal close tells the server the session is over, so it admits the next peer right away.
+const SESSION_ENDED: &str = "Session ended";
+
+/// A connection past the hello, carrying a new or resumed session.
+struct Connected {
+    ws_sender: WsSender,
+    ws_receiver: WsReceiver,
+    peer: String,
+    channel: Channel,
+}
+
+/// Keeps the client connected: tries `address` and then each fallback address in order, waiting
+/// with exponential backoff between rounds. Returns when the reconnect settings give up, or ends
+/// the session on SIGINT or SIGTERM.
 pub async fn start_client(
     address: &str,
-    passphrase: Arc<String>,
-    no_exec: bool,
-    no_transfer: bool,
-    no_envelope: bool,
+    settings: Arc<ConnectionSettings>,
+    reconnect: ReconnectSettings,
+    max_results: usize,
+    policy: Arc<Policy>,
+    shared_state: SharedStateHandle,
+) -> Result<(), String> {
+    tokio::select! {
+        result = stay_connected(address, settings, reconnect, max_results, policy, Arc::clone(&shared_state)) => result,
+        signal = shutdown::signal() => {
+            info!("{} received, ending the session", signal);
+            let connection = shared_state.lock().await.session.connection().cloned();
+            if let Some(connection) = connection {
+                let response_sender = ResponseSender::new(connection.channel, Some(connection.ws_sender), Arc::clone(&shared_state));
+                response_sender.close(CloseCode::Normal, SESSION_ENDED).await;
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn stay_connected(
+    address: &str,
+    settings: Arc<ConnectionSettings>,
+    reconnect: ReconnectSettings,
+    max_results: usize,
+    policy: Arc<Policy>,
     shared_state: SharedStateHandle,
-) {
-    let shutdown_notify = Arc::new(Notify::new());
+) -> Result<(), String> {
+    let addresses: Vec<String> = std::iter::once(address.to_string())
+        .chain(reconnect.fallback_addresses.iter().cloned())
+        .collect();
+    let exit_on_disconnect = reconnect.exit_on_disconnect;
+    let mut backoff = Backoff::new(reconnect);
+
+    let tx_command_handler = Arc::new(Mutex::new(TxCommandHandler::new(settings.no_envelope, Arc::clone(&shared_state))));
+    tokio::spawn(handle_cli(tx_command_handler));
 
     loop {
-        let shutdown_notify_clone = shutdown_notify.clone();
-        let shared_state = Arc::clone(&shared_state);
+        for address in &addresses {
+            let connected = match connect(address, &settings, &shared_state).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Could not connect to {}: {}", address, e);
+                    continue;
+                }
+            };
+            backoff.connected();
 
-        match connect_and_run(address, passphrase.clone(), no_exec, no_transfer, no_envelope, shutdown_notify_clone, shared_state).await {
-            Ok(_) => eprintln!("Connection closed. Reconnecting in 5 seconds..."),
-            Err(e) => eprintln!("Connection error: {}. Reconnecting in 5 seconds...", e),
+            let reason = run_session(connected, Arc::clone(&settings), max_results, Arc::clone(&policy), Arc::clone(&shared_state)).await;
+            // A normal close from either side, an idle one included, ended the session: there is nothing to resume.
+            if !shared_state.lock().await.session.resumable() {
+                info!("Session with {} ended: {}", address, reason);
+                return Ok(());
+            }
+            if exit_on_disconnect {
+                return Err(format!("Connection to {} lost: {}", address, reason));
+            }
+            warn!("Connection to {} lost: {}", address, reason);
+            break;
         }
-        sleep(Duration::from_secs(5)).await;
+
+        let delay = backoff.next_delay()?;
+        warn!("Reconnecting in {:.1} seconds...", delay.as_secs_f64());
+        sleep(delay).await;
     }
 }
 
-async fn connect_and_run(
+/// Runs one established connection until it ends, returning why.
+async fn run_session(
+    connected: Connected,
+    settings: Arc<ConnectionSettings>,
+    max_results: usize,
+    policy: Arc<Policy>,
+    shared_state: SharedStateHandle,
+) -> String {
+    let Connected { ws_sender, ws_receiver, peer, channel } = connected;
+    let capabilities = policy.capabilities_for(&peer);
+
+    let mut rx_command_handler = RxCommandHandler::new(
+        channel,
+        Some(ws_sender.clone()),
+        Some(ws_receiver.clone()),
+        settings.no_envelope,
+        max_results,
+        policy,
+        peer,
+        capabilities,
+        Arc::clone(&shared_state),
+    );
+    let mut rx_task = tokio::spawn(async move { rx_command_handler.handle_rx().await });
+
+    let response_sender = ResponseSender::new(channel, Some(Arc::clone(&ws_sender)), Arc::clone(&shared_state));
+    let reason = tokio::select! {
+        _ = &mut rx_task => "Connection closed.".to_string(),
+        (code, reason) = heartbeat::monitor(response_sender.clone(), settings.heartbeat) => {
+            response_sender.close(code, &reason).await;
+            // A half-open connection never ends the read by itself.
+            rx_task.abort();
+            let _ = rx_task.await;
+            reason
+        }
+    };
+    shared_state.lock().await.end_connection(&ws_sender);
+    reason
+}
+
+/// `uplink exec`: connects once, runs `command` on the peer and returns its result. Nothing is
+/// read from stdin, the peer can still run commands on this node while the command runs.
+pub async fn run_once(
     address: &str,
-    passphrase: Arc<String>,
-    no_exec: bool,
-    no_transfer: bool,
-    no_envelope: bool,
-    shutdown_notify: Arc<Notify>,
+    settings: Arc<ConnectionSettings>,
+    max_results: usize,
+    policy: Arc<Policy>,
+    command: &str,
     shared_state: SharedStateHandle,
-) -> Result<(), String> {
+) -> Result<Response, String> {
+    let Connected { ws_sender, ws_receiver, peer, channel } = connect(address, &settings, &shared_state).await?;
+    let capabilities = policy.capabilities_for(&peer);
+
+    let tx_command_handler = TxCommandHandler::new(settings.no_envelope, Arc::clone(&shared_state));
+
+    let response_sender = ResponseSender::new(channel, Some(ws_sender.clone()), Arc::clone(&shared_state));
+    let mut rx_command_handler = RxCommandHandler::new(
+        channel,
+        Some(ws_sender),
+        Some(ws_receiver),
+        settings.no_envelope,
+        max_results,
+        policy,
+        peer,
+        capabilities,
+        shared_state,
+    );
+    let rx_task = tokio::spawn(async move { rx_command_handler.handle_rx().await });
+
+    let exchange = async {
+        if !tx_command_handler.handshake(HANDSHAKE_TIMEOUT).await {
+            return Err("Handshake with the peer timed out".to_string());
+        }
+        tx_command_handler.execute(command).await.ok_or_else(|| "No result received".to_string())
+    };
+
+    tokio::select! {
+        result = exchange => {
+            response_sender.close(CloseCode::Normal, SESSION_ENDED).await;
+            result
+        }
+        _ = rx_task => Err("Connection closed before the command finished".to_string()),
+        (code, reason) = heartbeat::monitor(response_sender.clone(), settings.heartbeat) => {
+            response_sender.close(code, &reason).await;
+            Err(reason)
+        }
+    }
+}
+
+async fn connect(address: &str, settings: &ConnectionSettings, shared_state: &SharedStateHandle) -> Result<Connected, String> {
     let tcp_stream = TcpStream::connect(address)
         .await
         .map_err(|e| format!("Failed to connect: {}", e))?;
+    let peer = tcp_stream.peer_addr().map_or_else(|_| address.to_string(), |peer| peer.ip().to_string());
 
     let url = format!("ws://{}", address);
-    let (ws_stream, _) = client_async(&url, tcp_stream)
+    let (mut ws_stream, _) = client_async_with_config(&url, tcp_stream, Some(settings.limits.websocket_config()))
         .await
         .map_err(|e| format!("WebSocket upgrade failed: {}", e))?;
 
+    let (channel, peer_hello) = hello::connect(&mut ws_stream, settings).await?;
+    info!("Peer {} runs uplink {} (compression: {})", peer, peer_hello.build_version, channel.compressor.method());
+    let link = LinkHealth::new(peer.clone(), &peer_hello);
+
     let (ws_sender, ws_receiver) = ws_stream.split();
     let ws_sender = Arc::new(Mutex::new(ws_sender));
     let ws_receiver = Arc::new(Mutex::new(ws_receiver));
-
-    let tx_command_handler = Arc::new(Mutex::new(TxCommandHandler::new(
-        passphrase.to_string(),
-        Some(ws_sender.clone()),
-        no_envelope,
-        Arc::clone(&shared_state), 
-    )));
-
-    let rx_command_handler = Arc::new(Mutex::new(RxCommandHandler::new(
-        passphrase.to_string(),
-        Some(ws_sender.clone()),
-        Some(ws_receiver.clone()),
-        no_exec,
-        no_transfer,
-        no_envelope,
-        Arc::clone(&shared_state),
-    )));
-
-    let rx_task = tokio::spawn({
-        let rx_command_handler = rx_command_handler.clone();
-        let shutdown_notify = shutdown_notify.clone();
-        async move {
-            let mut handler = rx_command_handler.lock().await;
-            handler.handle_rx().await;
-            shutdown_notify.notify_one();
-        }
-    });
-
-    let cli_task = tokio::spawn({
-        let tx_command_handler = tx_command_handler.clone();
-        let shutdown_notify = shutdown_notify.clone();
-        async move {
-            handle_cli(tx_command_handler).await;
-            shutdown_notify.notify_one();
-        }
-    });
-
-    tokio::select! {
-        _ = rx_task => Err("Message handling task ended.".to_string()),
-        _ = cli_task => Err("CLI task ended.".to_string()),
-        _ = shutdown_notify.notified() => {
-            drop(rx_command_handler);
-            drop(tx_command_handler);
-            Err("Connection lost or tasks terminated.".to_string())
-        }
+    if resume::connect(&ws_sender, &ws_receiver, channel, link, shared_state).await? {
+        info!("Resumed the session with {}", peer);
     }
+    Ok(Connected { ws_sender, ws_receiver, peer, channel })
 }
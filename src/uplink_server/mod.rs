@@ -1 +1,3 @@
+pub mod rate_limit;
+pub mod shutdown;
 pub mod uplink_server;
\ No newline at end of file
@@ -0,0 +1,52 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+/// Connections one address may open per window. Each accepted connection costs a key derivation,
+/// a reconnecting client stays far below this.
+pub const ACCEPTS_PER_WINDOW: u32 = 10;
+pub const ACCEPT_WINDOW: Duration = Duration::from_secs(10);
+
+/// Counts the connections each address opened in the current window.
+pub struct AcceptLimiter {
+    max: u32,
+    window: Duration,
+    recent: HashMap<IpAddr, (Instant, u32)>,
+}
+
+impl AcceptLimiter {
+    pub fn new(max: u32, window: Duration) -> Self {
+        AcceptLimiter { max, window, recent: HashMap::new() }
+    }
+
+    /// Counts a connection from `address`, false once it opened more than allowed in this window.
+    pub fn allow(&mut self, address: IpAddr) -> bool {
+        let now = Instant::now();
+        let window = self.window;
+        self.recent.retain(|_, (started, _)| now.duration_since(*started) < window);
+        let (_, count) = self.recent.entry(address).or_insert((now, 0));
+        *count = count.saturating_add(1);
+        *count <= self.max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn addresses_are_limited_separately_until_the_window_ends() {
+        let mut limiter = AcceptLimiter::new(2, Duration::from_millis(100));
+        let first = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let second = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        assert!(limiter.allow(first));
+        assert!(limiter.allow(first));
+        assert!(!limiter.allow(first));
+        assert!(limiter.allow(second));
+
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(limiter.allow(first));
+    }
+}
@@ -0,0 +1,32 @@
+use tracing::warn;
+
+/// Waits for SIGINT, or SIGTERM where there is one, and returns the name of the signal.
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = match signal(SignalKind::terminate()) {
+            Ok(terminate) => terminate,
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                return interrupt().await;
+            }
+        };
+        tokio::select! {
+            name = interrupt() => name,
+            _ = terminate.recv() => "SIGTERM",
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        interrupt().await
+    }
+}
+
+async fn interrupt() -> &'static str {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Failed to listen for SIGINT: {}", e);
+        std::future::pending::<()>().await;
+    }
+    "SIGINT"
+}
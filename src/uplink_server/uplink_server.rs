@@ -1,91 +1,222 @@
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use tokio::sync::{watch, Mutex};
+use tokio::task::JoinSet;
+use tokio::time::{sleep, timeout, Duration, Instant};
 use std::sync::Arc;
 use crate::handlers::cli_handler::handle_cli;
+use crate::handlers::heartbeat;
+use crate::handlers::response_sender::ResponseSender;
 use crate::handlers::rx_command_handler::RxCommandHandler;
 use crate::handlers::tx_command_handler::TxCommandHandler;
+use crate::policy::policy::Policy;
+use crate::shared_state::link::LinkHealth;
 use crate::shared_state::shared_state::SharedStateHandle;
-use tokio_tungstenite::accept_async;
+use crate::shared_state::transfers::{format_duration, TransferStatus};
+use crate::uplink_server::rate_limit::{AcceptLimiter, ACCEPTS_PER_WINDOW, ACCEPT_WINDOW};
+use crate::uplink_server::shutdown;
+use tokio_tungstenite::accept_async_with_config;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use futures_util::stream::StreamExt;
-use crate::transport::communication;
+use crate::transport::communication::{self, ConnectionSettings};
+use crate::transport::{hello, resume};
 use tokio::net::TcpStream;
 
+// How long a shutdown waits for transfers under way to finish.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(60);
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+// How long connections get to close once the peers were told the server is shutting down.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves connections until SIGINT or SIGTERM, then lets transfers under way finish, closes every
+/// connection and prints what the server did. Fails if it cannot listen on `bind_addr`.
 pub async fn start_server(
     bind_addr: &str,
-    passphrase: Arc<String>,
-    no_exec: bool,
-    no_transfer: bool,
-    no_envelope: bool,
+    settings: Arc<ConnectionSettings>,
+    max_results: usize,
+    policy: Arc<Policy>,
     shared_state: SharedStateHandle,
-) {
-    let listener = TcpListener::bind(bind_addr).await.unwrap();
-    println!("Server listening on {}", bind_addr);
+) -> Result<(), String> {
+    let listener = TcpListener::bind(bind_addr).await.map_err(|e| format!("Failed to listen on {}: {}", bind_addr, e))?;
+    info!("Server listening on {}", bind_addr);
+    let started = Instant::now();
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                let shared_state = Arc::clone(&shared_state);
-
-                tokio::spawn(handle_connection(
-                    stream,
-                    Arc::clone(&passphrase),
-                    no_exec,
-                    no_transfer,
-                    no_envelope,
-                    shared_state,
-                ));
+    let tx_command_handler = Arc::new(Mutex::new(TxCommandHandler::new(settings.no_envelope, Arc::clone(&shared_state))));
+    tokio::spawn(handle_cli(tx_command_handler));
+
+    let (stop, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let mut served = 0;
+    let mut limiter = AcceptLimiter::new(ACCEPTS_PER_WINDOW, ACCEPT_WINDOW);
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+
+    let signal = loop {
+        tokio::select! {
+            signal = &mut signal => break signal,
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            accepted = listener.accept() => match accepted {
+                Ok((_, address)) if !limiter.allow(address.ip()) => {
+                    debug!("Dropped a connection from {}, it connects too often", address.ip());
+                }
+                Ok((stream, _)) => {
+                    served += 1;
+                    connections.spawn(handle_connection(
+                        stream,
+                        Arc::clone(&settings),
+                        max_results,
+                        Arc::clone(&policy),
+                        Arc::clone(&shared_state),
+                        stopping.clone(),
+                    ));
+                }
+                Err(e) => {
+                    warn!("Failed to accept connection: {:?}", e);
+                }
             }
-            Err(e) => {
-                eprintln!("Failed to accept connection: {:?}", e);
+        }
+    };
+    drop(listener);
+    println!("[*] {} received, shutting down.", signal);
+
+    drain(&shared_state).await;
+    stop.send_replace(true);
+    let closed = async { while connections.join_next().await.is_some() {} };
+    if timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+        warn!("{} connection(s) did not close in time", connections.len());
+    }
+    connections.shutdown().await;
+
+    let shared_state = shared_state.lock().await;
+    let transfers = &shared_state.transfers;
+    println!(
+        "[*] Server stopped after {}: {} connection(s) served, transfers: {} completed, {} failed, {} unfinished",
+        format_duration(started.elapsed()),
+        served,
+        transfers.count(|status| matches!(status, TransferStatus::Completed)),
+        transfers.count(|status| matches!(status, TransferStatus::Failed(_))),
+        transfers.count(|status| matches!(status, TransferStatus::Active)),
+    );
+    Ok(())
+}
+
+/// Refuses new commands and waits for the transfers under way. Gives up after `DRAIN_TIMEOUT`, on
+/// a second signal, or once no connection is left to finish them over.
+async fn drain(shared_state: &SharedStateHandle) {
+    shared_state.lock().await.draining = true;
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    let signal = shutdown::signal();
+    tokio::pin!(signal);
+    let mut announced = false;
+
+    loop {
+        let (in_flight, connected) = {
+            let shared_state = shared_state.lock().await;
+            (shared_state.transfers.in_flight(), shared_state.session.connection().is_some())
+        };
+        if in_flight == 0 {
+            return;
+        }
+        if !connected {
+            warn!("Abandoning {} transfer(s), the peer is not connected", in_flight);
+            return;
+        }
+        if Instant::now() >= deadline {
+            warn!("Abandoning {} transfer(s) still under way after {} seconds", in_flight, DRAIN_TIMEOUT.as_secs());
+            return;
+        }
+        if !announced {
+            println!("[*] Waiting for {} transfer(s) to finish, signal again to stop now.", in_flight);
+            announced = true;
+        }
+
+        tokio::select! {
+            signal = &mut signal => {
+                warn!("{} received, abandoning {} transfer(s)", signal, in_flight);
+                return;
             }
+            _ = sleep(DRAIN_CHECK_INTERVAL) => {}
         }
     }
 }
 
 async fn handle_connection(
     mut stream: TcpStream,
-    passphrase: Arc<String>,
-    no_exec: bool,
-    no_transfer: bool,
-    no_envelope: bool,
+    settings: Arc<ConnectionSettings>,
+    max_results: usize,
+    policy: Arc<Policy>,
     shared_state: SharedStateHandle,
+    mut stopping: watch::Receiver<bool>,
 ) {
+    let peer = match stream.peer_addr() {
+        Ok(peer) => peer.ip().to_string(),
+        Err(e) => {
+            warn!("Failed to read peer address: {}", e);
+            return;
+        }
+    };
+
+    let capabilities = policy.capabilities_for(&peer);
+
     if communication::is_websocket_upgrade_request(&mut stream).await {
-        match accept_async(stream).await {
-            Ok(ws_stream) => {
+        match accept_async_with_config(stream, Some(settings.limits.websocket_config())).await {
+            Ok(mut ws_stream) => {
+                let (channel, peer_hello) = match hello::accept(&mut ws_stream, &settings).await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Connection from {} refused: {}", peer, e);
+                        return;
+                    }
+                };
+                info!("Peer {} runs uplink {} (compression: {})", peer, peer_hello.build_version, channel.compressor.method());
+                let link = LinkHealth::new(peer.clone(), &peer_hello);
                 let (ws_sender, ws_receiver) = ws_stream.split();
                 let ws_sender = Arc::new(Mutex::new(ws_sender));
                 let ws_receiver = Arc::new(Mutex::new(ws_receiver));
 
-                let tx_command_handler = Arc::new(Mutex::new(TxCommandHandler::new(
-                    passphrase.clone().to_string(),
-                    Some(Arc::clone(&ws_sender)),
-                    no_envelope,
-                    Arc::clone(&shared_state), 
-                )));
-                let rx_command_handler = Arc::new(Mutex::new(RxCommandHandler::new(
-                    passphrase.clone().to_string(),
+                match resume::accept(&ws_sender, &ws_receiver, channel, link, &shared_state).await {
+                    Ok(true) => info!("Peer {} resumed its session", peer),
+                    Ok(false) => {}
+                    Err(e) => {
+                        warn!("Connection from {} refused: {}", peer, e);
+                        return;
+                    }
+                }
+
+                let mut rx_command_handler = RxCommandHandler::new(
+                    channel,
                     Some(Arc::clone(&ws_sender)),
                     Some(Arc::clone(&ws_receiver)),
-                    no_exec,
-                    no_transfer,
-                    no_envelope,
+                    settings.no_envelope,
+                    max_results,
+                    Arc::clone(&policy),
+                    peer.clone(),
+                    capabilities,
                     Arc::clone(&shared_state),
-                )));
-
-                tokio::spawn(handle_cli(Arc::clone(&tx_command_handler)));
-
-                tokio::spawn(async move {
-                    let mut command_handler_for_ws = rx_command_handler.lock().await;
-                    command_handler_for_ws.handle_rx().await;
-                });
+                );
+                let mut rx_task = tokio::spawn(async move { rx_command_handler.handle_rx().await });
 
-                loop {
-                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                let response_sender = ResponseSender::new(channel, Some(Arc::clone(&ws_sender)), Arc::clone(&shared_state));
+                tokio::select! {
+                    _ = &mut rx_task => info!("Connection from {} closed", peer),
+                    (code, reason) = heartbeat::monitor(response_sender.clone(), settings.heartbeat) => {
+                        warn!("Closing the connection from {}: {}", peer, reason);
+                        response_sender.close(code, &reason).await;
+                        // A half-open connection never ends the read by itself.
+                        rx_task.abort();
+                        let _ = rx_task.await;
+                    }
+                    _ = stopping.changed() => {
+                        info!("Closing the connection from {}, the server is shutting down", peer);
+                        response_sender.close(CloseCode::Away, "Server shutting down").await;
+                        rx_task.abort();
+                        let _ = rx_task.await;
+                    }
                 }
+                shared_state.lock().await.end_connection(&ws_sender);
             }
             Err(e) => {
-                eprintln!("WebSocket handshake failed: {:?}", e);
+                warn!("WebSocket handshake failed from {}: {:?}", peer, e);
             }
         }
     }